@@ -112,6 +112,7 @@ LOCAL_STATIC_LIBRARIES := \
 LOCAL_SRC_FILES := \
     boot/main.cpp \
     boot/bootimg.cpp \
+    boot/bootconfig.cpp \
     boot/hexpatch.cpp \
     boot/compress.cpp \
     boot/format.cpp \
@@ -0,0 +1,776 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+
+use anyhow::{anyhow, Context};
+use byteorder::{BigEndian, ByteOrder};
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+use base::ResultExt;
+
+use crate::sign::SigningKey;
+
+// Android Verified Boot footers and vbmeta images
+// https://android.googlesource.com/platform/external/avb/+/refs/heads/main/libavb/
+
+pub const AVB_FOOTER_MAGIC: &[u8] = b"AVBf";
+pub const AVB_MAGIC: &[u8] = b"AVB0";
+pub const AVB_FOOTER_SIZE: u64 = 64;
+pub const VBMETA_HEADER_SIZE: usize = 256;
+// Same as AVB_VBMETA_IMAGE_MAX_SIZE in libavb
+const VBMETA_MAX_SIZE: u64 = 64 * 1024;
+
+const DESCRIPTOR_TAG_PROPERTY: u64 = 0;
+const DESCRIPTOR_TAG_HASHTREE: u64 = 1;
+const DESCRIPTOR_TAG_HASH: u64 = 2;
+const DESCRIPTOR_TAG_KERNEL_CMDLINE: u64 = 3;
+const DESCRIPTOR_TAG_CHAIN_PARTITION: u64 = 4;
+
+pub const ALGORITHM_NONE: u32 = 0;
+const ALGORITHM_SHA256_RSA2048: u32 = 1;
+const ALGORITHM_SHA256_RSA4096: u32 = 2;
+
+pub const ALGORITHM_NAMES: [&str; 7] = [
+    "NONE",
+    "SHA256_RSA2048",
+    "SHA256_RSA4096",
+    "SHA256_RSA8192",
+    "SHA512_RSA2048",
+    "SHA512_RSA4096",
+    "SHA512_RSA8192",
+];
+
+pub const FLAG_HASHTREE_DISABLED: u32 = 1;
+pub const FLAG_VERIFICATION_DISABLED: u32 = 2;
+
+macro_rules! bad_avb {
+    ($msg:literal) => {
+        anyhow!(concat!("invalid AVB metadata: ", $msg))
+    };
+    ($($args:tt)*) => {
+        anyhow!("invalid AVB metadata: {}", format_args!($($args)*))
+    };
+}
+
+#[derive(Clone, Copy)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub fn from_name(name: &str) -> anyhow::Result<HashAlgorithm> {
+        match name {
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            _ => Err(anyhow!("unsupported hash algorithm '{name}'")),
+        }
+    }
+
+    fn digest_size(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+
+    fn salted<D: Digest>(salt: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut hasher = D::new();
+        hasher.update(salt);
+        hasher.update(data);
+        hasher.finalize().to_vec()
+    }
+
+    pub fn hash(&self, salt: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha1 => Self::salted::<Sha1>(salt, data),
+            HashAlgorithm::Sha256 => Self::salted::<Sha256>(salt, data),
+            HashAlgorithm::Sha512 => Self::salted::<Sha512>(salt, data),
+        }
+    }
+
+    fn hash_file<D: Digest>(file: &File, salt: &[u8], size: u64) -> anyhow::Result<Vec<u8>> {
+        let mut hasher = D::new();
+        hasher.update(salt);
+        let mut buf = vec![0u8; 1024 * 1024];
+        let mut offset = 0;
+        while offset < size {
+            let len = std::cmp::min(buf.len() as u64, size - offset) as usize;
+            file.read_exact_at(&mut buf[..len], offset)?;
+            hasher.update(&buf[..len]);
+            offset += len as u64;
+        }
+        Ok(hasher.finalize().to_vec())
+    }
+
+    // Hash the salt followed by the first size bytes of the file
+    pub fn hash_image(&self, file: &File, salt: &[u8], size: u64) -> anyhow::Result<Vec<u8>> {
+        match self {
+            HashAlgorithm::Sha1 => Self::hash_file::<Sha1>(file, salt, size),
+            HashAlgorithm::Sha256 => Self::hash_file::<Sha256>(file, salt, size),
+            HashAlgorithm::Sha512 => Self::hash_file::<Sha512>(file, salt, size),
+        }
+    }
+}
+
+// Compute the dm-verity hash tree of size bytes at offset in the file.
+// Returns the root digest and the tree, stored with the top level first like avbtool.
+pub fn hash_tree(
+    file: &File,
+    offset: u64,
+    size: u64,
+    block_size: u32,
+    algorithm: HashAlgorithm,
+    salt: &[u8],
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let bs = block_size as usize;
+    if bs == 0 {
+        return Err(bad_avb!("block size is zero"));
+    }
+    // Each digest is padded to a power of two
+    let digest_size = algorithm.digest_size();
+    let digest_padding = digest_size.next_power_of_two() - digest_size;
+    let pad_level = |level: &mut Vec<u8>| {
+        let len = level.len().div_ceil(bs) * bs;
+        level.resize(len, 0);
+    };
+
+    // The bottom level hashes the data blocks
+    let mut level = Vec::new();
+    let mut block = vec![0u8; bs];
+    let mut pos = 0;
+    while pos < size {
+        let len = std::cmp::min(bs as u64, size - pos) as usize;
+        block[len..].fill(0);
+        file.read_exact_at(&mut block[..len], offset + pos)
+            .context("failed to read image")?;
+        level.extend(algorithm.hash(salt, &block));
+        level.resize(level.len() + digest_padding, 0);
+        pos += bs as u64;
+    }
+    pad_level(&mut level);
+
+    // Every upper level hashes the blocks of the level below, until it fits in a single block
+    let mut levels = Vec::new();
+    while level.len() > bs {
+        let mut upper = Vec::new();
+        for block in level.chunks(bs) {
+            upper.extend(algorithm.hash(salt, block));
+            upper.resize(upper.len() + digest_padding, 0);
+        }
+        pad_level(&mut upper);
+        levels.push(std::mem::replace(&mut level, upper));
+    }
+    let root = algorithm.hash(salt, &level);
+
+    // Data that fits in a single block does not need a tree
+    let mut tree = Vec::new();
+    if size > bs as u64 {
+        tree.extend_from_slice(&level);
+        for level in levels.iter().rev() {
+            tree.extend_from_slice(level);
+        }
+    }
+    Ok((root, tree))
+}
+
+pub struct AvbFooter {
+    pub original_image_size: u64,
+    pub vbmeta_offset: u64,
+    pub vbmeta_size: u64,
+}
+
+// A vbmeta image split into its blocks, serialized back with the same layout as avbtool
+pub struct Vbmeta {
+    pub required_libavb_version: (u32, u32),
+    pub algorithm_type: u32,
+    pub rollback_index: u64,
+    pub flags: u32,
+    pub rollback_index_location: u32,
+    pub release_string: [u8; 48],
+    pub hash: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub public_key_metadata: Vec<u8>,
+    pub descriptors: Vec<u8>,
+}
+
+pub struct HashDescriptor {
+    pub image_size: u64,
+    pub hash_algorithm: String,
+    pub partition_name: String,
+    pub salt: Vec<u8>,
+    pub digest: Vec<u8>,
+}
+
+pub struct HashtreeDescriptor {
+    pub image_size: u64,
+    pub tree_offset: u64,
+    pub tree_size: u64,
+    pub data_block_size: u32,
+    pub hash_block_size: u32,
+    pub hash_algorithm: String,
+    pub partition_name: String,
+    pub salt: Vec<u8>,
+    pub root_digest: Vec<u8>,
+}
+
+pub struct PropertyDescriptor {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+pub struct KernelCmdlineDescriptor {
+    pub flags: u32,
+    pub cmdline: String,
+}
+
+pub struct ChainPartitionDescriptor {
+    pub rollback_index_location: u32,
+    pub partition_name: String,
+    pub public_key: Vec<u8>,
+    pub flags: u32,
+}
+
+pub enum Descriptor {
+    Property(PropertyDescriptor),
+    Hashtree(HashtreeDescriptor),
+    Hash(HashDescriptor),
+    KernelCmdline(KernelCmdlineDescriptor),
+    ChainPartition(ChainPartitionDescriptor),
+    Unknown(u64),
+}
+
+fn sub_slice(data: &[u8], offset: u64, len: u64) -> anyhow::Result<&[u8]> {
+    let end = offset
+        .checked_add(len)
+        .filter(|end| *end <= data.len() as u64)
+        .ok_or(bad_avb!("data is truncated"))?;
+    Ok(&data[offset as usize..end as usize])
+}
+
+pub fn c_str(data: &[u8]) -> String {
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+}
+
+// The partition name, salt and digest follow the fixed size part of the descriptor
+fn read_trailing<'a>(body: &'a [u8], offset: u64, lens: &[u32]) -> anyhow::Result<Vec<&'a [u8]>> {
+    let mut offset = offset;
+    let mut fields = Vec::new();
+    for len in lens {
+        fields.push(sub_slice(body, offset, *len as u64)?);
+        offset += *len as u64;
+    }
+    Ok(fields)
+}
+
+fn parse_hash_descriptor(body: &[u8]) -> anyhow::Result<HashDescriptor> {
+    let fixed = sub_slice(body, 0, 116)?;
+    let lens = [
+        BigEndian::read_u32(&fixed[40..44]),
+        BigEndian::read_u32(&fixed[44..48]),
+        BigEndian::read_u32(&fixed[48..52]),
+    ];
+    let fields = read_trailing(body, 116, &lens)?;
+    Ok(HashDescriptor {
+        image_size: BigEndian::read_u64(&fixed[0..8]),
+        hash_algorithm: c_str(&fixed[8..40]),
+        partition_name: String::from_utf8_lossy(fields[0]).into_owned(),
+        salt: fields[1].to_vec(),
+        digest: fields[2].to_vec(),
+    })
+}
+
+fn parse_hashtree_descriptor(body: &[u8]) -> anyhow::Result<HashtreeDescriptor> {
+    let fixed = sub_slice(body, 0, 164)?;
+    let lens = [
+        BigEndian::read_u32(&fixed[88..92]),
+        BigEndian::read_u32(&fixed[92..96]),
+        BigEndian::read_u32(&fixed[96..100]),
+    ];
+    let fields = read_trailing(body, 164, &lens)?;
+    Ok(HashtreeDescriptor {
+        image_size: BigEndian::read_u64(&fixed[4..12]),
+        tree_offset: BigEndian::read_u64(&fixed[12..20]),
+        tree_size: BigEndian::read_u64(&fixed[20..28]),
+        data_block_size: BigEndian::read_u32(&fixed[28..32]),
+        hash_block_size: BigEndian::read_u32(&fixed[32..36]),
+        hash_algorithm: c_str(&fixed[56..88]),
+        partition_name: String::from_utf8_lossy(fields[0]).into_owned(),
+        salt: fields[1].to_vec(),
+        root_digest: fields[2].to_vec(),
+    })
+}
+
+fn parse_property_descriptor(body: &[u8]) -> anyhow::Result<PropertyDescriptor> {
+    let fixed = sub_slice(body, 0, 16)?;
+    let key_len = BigEndian::read_u64(&fixed[0..8]);
+    let value_len = BigEndian::read_u64(&fixed[8..16]);
+    // Both the key and the value are followed by a NUL byte
+    let key = sub_slice(body, 16, key_len)?;
+    let value_offset = key_len
+        .checked_add(16 + 1)
+        .ok_or(bad_avb!("invalid property key length"))?;
+    let value = sub_slice(body, value_offset, value_len)?;
+    Ok(PropertyDescriptor {
+        key: String::from_utf8_lossy(key).into_owned(),
+        value: value.to_vec(),
+    })
+}
+
+fn parse_kernel_cmdline_descriptor(body: &[u8]) -> anyhow::Result<KernelCmdlineDescriptor> {
+    let fixed = sub_slice(body, 0, 8)?;
+    let fields = read_trailing(body, 8, &[BigEndian::read_u32(&fixed[4..8])])?;
+    Ok(KernelCmdlineDescriptor {
+        flags: BigEndian::read_u32(&fixed[0..4]),
+        cmdline: String::from_utf8_lossy(fields[0]).into_owned(),
+    })
+}
+
+fn parse_chain_partition_descriptor(body: &[u8]) -> anyhow::Result<ChainPartitionDescriptor> {
+    let fixed = sub_slice(body, 0, 76)?;
+    let lens = [
+        BigEndian::read_u32(&fixed[4..8]),
+        BigEndian::read_u32(&fixed[8..12]),
+    ];
+    let fields = read_trailing(body, 76, &lens)?;
+    Ok(ChainPartitionDescriptor {
+        rollback_index_location: BigEndian::read_u32(&fixed[0..4]),
+        partition_name: String::from_utf8_lossy(fields[0]).into_owned(),
+        public_key: fields[1].to_vec(),
+        flags: BigEndian::read_u32(&fixed[12..16]),
+    })
+}
+
+pub fn read_footer(file: &File) -> anyhow::Result<Option<AvbFooter>> {
+    let len = file.metadata()?.len();
+    if len < AVB_FOOTER_SIZE {
+        return Ok(None);
+    }
+    let mut buf = [0u8; AVB_FOOTER_SIZE as usize];
+    file.read_exact_at(&mut buf, len - AVB_FOOTER_SIZE)?;
+    if !buf.starts_with(AVB_FOOTER_MAGIC) {
+        return Ok(None);
+    }
+    Ok(Some(AvbFooter {
+        original_image_size: BigEndian::read_u64(&buf[12..20]),
+        vbmeta_offset: BigEndian::read_u64(&buf[20..28]),
+        vbmeta_size: BigEndian::read_u64(&buf[28..36]),
+    }))
+}
+
+pub fn read_vbmeta(file: &File, footer: &AvbFooter) -> anyhow::Result<Vec<u8>> {
+    if footer.vbmeta_size > VBMETA_MAX_SIZE {
+        return Err(bad_avb!("vbmeta size {} is too large", footer.vbmeta_size));
+    }
+    let mut vbmeta = vec![0u8; footer.vbmeta_size as usize];
+    file.read_exact_at(&mut vbmeta, footer.vbmeta_offset)
+        .context("failed to read vbmeta")?;
+    Ok(vbmeta)
+}
+
+// Read the vbmeta image of a partition with an AVB footer, or of a standalone vbmeta image
+pub fn read_image_vbmeta(file: &File) -> anyhow::Result<(Option<AvbFooter>, Vec<u8>)> {
+    if let Some(footer) = read_footer(file)? {
+        let vbmeta = read_vbmeta(file, &footer)?;
+        return Ok((Some(footer), vbmeta));
+    }
+    let mut hdr = vec![0u8; VBMETA_HEADER_SIZE];
+    file.read_exact_at(&mut hdr, 0)
+        .context("failed to read vbmeta")?;
+    if !hdr.starts_with(AVB_MAGIC) {
+        return Err(bad_avb!("invalid vbmeta magic"));
+    }
+    let size = BigEndian::read_u64(&hdr[12..20])
+        .checked_add(BigEndian::read_u64(&hdr[20..28]))
+        .and_then(|size| size.checked_add(VBMETA_HEADER_SIZE as u64))
+        .filter(|size| *size <= VBMETA_MAX_SIZE)
+        .ok_or(bad_avb!("invalid vbmeta size"))?;
+    let footer = AvbFooter {
+        original_image_size: 0,
+        vbmeta_offset: 0,
+        vbmeta_size: size,
+    };
+    Ok((None, read_vbmeta(file, &footer)?))
+}
+
+// Replace the vbmeta image read with read_image_vbmeta, clearing leftover bytes of the old one
+pub fn write_image_vbmeta(
+    file: &File,
+    footer: Option<&AvbFooter>,
+    old_size: u64,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let offset = footer.map_or(0, |f| f.vbmeta_offset);
+    let end = offset + data.len() as u64;
+    let footer_offset = file.metadata()?.len() - AVB_FOOTER_SIZE;
+    if footer.is_some() && end > footer_offset {
+        return Err(anyhow!("no space left for the new vbmeta"));
+    }
+    file.write_all_at(data, offset)?;
+    if old_size > data.len() as u64 {
+        let zeros = vec![0u8; (old_size - data.len() as u64) as usize];
+        file.write_all_at(&zeros, end)?;
+    }
+    if footer.is_some() {
+        let mut size = [0u8; 8];
+        BigEndian::write_u64(&mut size, data.len() as u64);
+        file.write_all_at(&size, footer_offset + 28)?;
+    }
+    Ok(())
+}
+
+impl Vbmeta {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Vbmeta> {
+        if data.len() < VBMETA_HEADER_SIZE || !data.starts_with(AVB_MAGIC) {
+            return Err(bad_avb!("invalid vbmeta magic"));
+        }
+        let hdr = &data[..VBMETA_HEADER_SIZE];
+        let field = |offset: usize| BigEndian::read_u64(&hdr[offset..offset + 8]);
+        let auth = sub_slice(data, VBMETA_HEADER_SIZE as u64, field(12))?;
+        let aux = sub_slice(data, VBMETA_HEADER_SIZE as u64 + field(12), field(20))?;
+        let mut release_string = [0u8; 48];
+        release_string.copy_from_slice(&hdr[128..176]);
+        Ok(Vbmeta {
+            required_libavb_version: (
+                BigEndian::read_u32(&hdr[4..8]),
+                BigEndian::read_u32(&hdr[8..12]),
+            ),
+            algorithm_type: BigEndian::read_u32(&hdr[28..32]),
+            rollback_index: field(112),
+            flags: BigEndian::read_u32(&hdr[120..124]),
+            rollback_index_location: BigEndian::read_u32(&hdr[124..128]),
+            release_string,
+            hash: sub_slice(auth, field(32), field(40))?.to_vec(),
+            signature: sub_slice(auth, field(48), field(56))?.to_vec(),
+            public_key: sub_slice(aux, field(64), field(72))?.to_vec(),
+            public_key_metadata: sub_slice(aux, field(80), field(88))?.to_vec(),
+            descriptors: sub_slice(aux, field(96), field(104))?.to_vec(),
+        })
+    }
+
+    // The header and the auxiliary data block, which are covered by the signature
+    fn signed_data(&self) -> (Vec<u8>, Vec<u8>) {
+        let mut aux = Vec::new();
+        aux.extend_from_slice(&self.descriptors);
+        aux.extend_from_slice(&self.public_key);
+        aux.extend_from_slice(&self.public_key_metadata);
+        aux.resize(aux.len().next_multiple_of(64), 0);
+        let auth_size = (self.hash.len() + self.signature.len()).next_multiple_of(64);
+
+        let desc_size = self.descriptors.len() as u64;
+        let key_size = self.public_key.len() as u64;
+        let mut hdr = vec![0u8; VBMETA_HEADER_SIZE];
+        hdr[..4].copy_from_slice(AVB_MAGIC);
+        BigEndian::write_u32(&mut hdr[4..8], self.required_libavb_version.0);
+        BigEndian::write_u32(&mut hdr[8..12], self.required_libavb_version.1);
+        BigEndian::write_u64(&mut hdr[12..20], auth_size as u64);
+        BigEndian::write_u64(&mut hdr[20..28], aux.len() as u64);
+        BigEndian::write_u32(&mut hdr[28..32], self.algorithm_type);
+        BigEndian::write_u64(&mut hdr[32..40], 0);
+        BigEndian::write_u64(&mut hdr[40..48], self.hash.len() as u64);
+        BigEndian::write_u64(&mut hdr[48..56], self.hash.len() as u64);
+        BigEndian::write_u64(&mut hdr[56..64], self.signature.len() as u64);
+        BigEndian::write_u64(&mut hdr[64..72], desc_size);
+        BigEndian::write_u64(&mut hdr[72..80], key_size);
+        BigEndian::write_u64(&mut hdr[80..88], desc_size + key_size);
+        BigEndian::write_u64(&mut hdr[88..96], self.public_key_metadata.len() as u64);
+        BigEndian::write_u64(&mut hdr[96..104], 0);
+        BigEndian::write_u64(&mut hdr[104..112], desc_size);
+        BigEndian::write_u64(&mut hdr[112..120], self.rollback_index);
+        BigEndian::write_u32(&mut hdr[120..124], self.flags);
+        BigEndian::write_u32(&mut hdr[124..128], self.rollback_index_location);
+        hdr[128..176].copy_from_slice(&self.release_string);
+        (hdr, aux)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (mut data, aux) = self.signed_data();
+        let auth_size = BigEndian::read_u64(&data[12..20]) as usize;
+        data.extend_from_slice(&self.hash);
+        data.extend_from_slice(&self.signature);
+        data.resize(VBMETA_HEADER_SIZE + auth_size, 0);
+        data.extend_from_slice(&aux);
+        data
+    }
+
+    // Sign with SHA256_RSA2048 or SHA256_RSA4096, depending on the key size
+    pub fn sign(&mut self, key: &SigningKey) -> anyhow::Result<()> {
+        self.algorithm_type = match key.size() * 8 {
+            2048 => ALGORITHM_SHA256_RSA2048,
+            4096 => ALGORITHM_SHA256_RSA4096,
+            bits => return Err(anyhow!("unsupported RSA key size {bits}")),
+        };
+        self.public_key = encode_public_key(&key.public_key());
+        self.hash = vec![0; 32];
+        self.signature = vec![0; key.size()];
+
+        let (hdr, aux) = self.signed_data();
+        let mut hasher = Sha256::new();
+        hasher.update(&hdr);
+        hasher.update(&aux);
+        self.hash = hasher.finalize().to_vec();
+        self.signature = key.sign_digest(&self.hash)?;
+        Ok(())
+    }
+
+    // Recompute the digest of the hash descriptor of a partition for the first image_size bytes
+    // of the file
+    pub fn update_hash_descriptor(
+        &mut self,
+        file: &File,
+        partition_name: &str,
+        image_size: u64,
+    ) -> anyhow::Result<()> {
+        let mut offset = 0;
+        while offset < self.descriptors.len() {
+            let header = sub_slice(&self.descriptors, offset as u64, 16)?;
+            let tag = BigEndian::read_u64(&header[0..8]);
+            let len = BigEndian::read_u64(&header[8..16]);
+            let body_offset = offset + 16;
+            let next = sub_slice(&self.descriptors, body_offset as u64, len)?.len() + body_offset;
+            let desc = match tag {
+                DESCRIPTOR_TAG_HASH => {
+                    Some(parse_hash_descriptor(&self.descriptors[body_offset..next])?)
+                }
+                _ => None,
+            };
+            if let Some(desc) = desc.filter(|d| d.partition_name == partition_name) {
+                let algorithm = HashAlgorithm::from_name(&desc.hash_algorithm)?;
+                let digest = algorithm.hash_image(file, &desc.salt, image_size)?;
+                if digest.len() != desc.digest.len() {
+                    return Err(bad_avb!("invalid digest size"));
+                }
+                let body = &mut self.descriptors[body_offset..next];
+                BigEndian::write_u64(&mut body[0..8], image_size);
+                let name_len = BigEndian::read_u32(&body[40..44]) as usize;
+                let digest_offset = body_offset + 116 + name_len + desc.salt.len();
+                self.descriptors[digest_offset..digest_offset + digest.len()]
+                    .copy_from_slice(&digest);
+                return Ok(());
+            }
+            offset = next;
+        }
+        Err(anyhow!(
+            "no hash descriptor for partition '{partition_name}'"
+        ))
+    }
+}
+
+// Encode an RSA public key in the AvbRSAPublicKeyHeader format, followed by n and r^2 mod n
+pub fn encode_public_key(key: &RsaPublicKey) -> Vec<u8> {
+    let size = key.size();
+    let n = key.n();
+
+    // n0inv = -1 / n[0] mod 2^32, computed with Newton's method
+    let mut n0 = [0u8; 4];
+    let le = n.to_bytes_le();
+    n0[..le.len().min(4)].copy_from_slice(&le[..le.len().min(4)]);
+    let n0 = u32::from_le_bytes(n0);
+    let mut inv = n0;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inv)));
+    }
+    let rr = (BigUint::from(1u32) << (size * 16)) % n;
+
+    let pad = |v: Vec<u8>| {
+        let mut out = vec![0u8; size - v.len()];
+        out.extend(v);
+        out
+    };
+    let mut out = Vec::with_capacity(8 + 2 * size);
+    out.extend_from_slice(&((size * 8) as u32).to_be_bytes());
+    out.extend_from_slice(&inv.wrapping_neg().to_be_bytes());
+    out.extend(pad(n.to_bytes_be()));
+    out.extend(pad(rr.to_bytes_be()));
+    out
+}
+
+// Decode an RSA public key in the AvbRSAPublicKeyHeader format, the exponent is always 65537
+pub fn decode_public_key(data: &[u8]) -> anyhow::Result<RsaPublicKey> {
+    let bits = BigEndian::read_u32(sub_slice(data, 0, 8)?);
+    let n = sub_slice(data, 8, bits as u64 / 8)?;
+    RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from(65537u32))
+        .map_err(|_| bad_avb!("invalid public key"))
+}
+
+// Check the hash and signature of a vbmeta image with its embedded public key
+pub fn verify_vbmeta(data: &[u8]) -> anyhow::Result<Vbmeta> {
+    let vbmeta = Vbmeta::parse(data)?;
+    let (algorithm, scheme, bits) = match vbmeta.algorithm_type {
+        ALGORITHM_NONE => return Err(anyhow!("vbmeta is not signed")),
+        1..=3 => (
+            HashAlgorithm::Sha256,
+            Pkcs1v15Sign::new::<Sha256>(),
+            1024 << vbmeta.algorithm_type,
+        ),
+        4..=6 => (
+            HashAlgorithm::Sha512,
+            Pkcs1v15Sign::new::<Sha512>(),
+            1024 << (vbmeta.algorithm_type - 3),
+        ),
+        ty => return Err(bad_avb!("unknown algorithm type {}", ty)),
+    };
+
+    // The signature covers the header and the auxiliary data block as they are stored
+    let hdr = &data[..VBMETA_HEADER_SIZE];
+    let auth_size = BigEndian::read_u64(&hdr[12..20]);
+    let aux_size = BigEndian::read_u64(&hdr[20..28]);
+    let aux = sub_slice(data, VBMETA_HEADER_SIZE as u64 + auth_size, aux_size)?;
+    let digest = algorithm.hash(hdr, aux);
+    if digest != vbmeta.hash {
+        return Err(anyhow!("vbmeta hash mismatch"));
+    }
+
+    let key = decode_public_key(&vbmeta.public_key)?;
+    if key.size() * 8 != bits {
+        return Err(bad_avb!("public key size does not match the algorithm"));
+    }
+    key.verify(scheme, &digest, &vbmeta.signature)
+        .map_err(|_| anyhow!("vbmeta signature verification failed"))?;
+    Ok(vbmeta)
+}
+
+// The partition name of the hash descriptor that matches the image in front of the AVB footer
+fn image_partition_name(file: &File, footer: &AvbFooter) -> anyhow::Result<String> {
+    let vbmeta = read_vbmeta(file, footer)?;
+    for desc in parse_descriptors(&vbmeta)? {
+        if let Descriptor::Hash(desc) = desc {
+            if desc.image_size == footer.original_image_size && verify_hash(file, &desc).is_ok() {
+                return Ok(desc.partition_name);
+            }
+        }
+    }
+    Err(anyhow!("no hash descriptor matches the image"))
+}
+
+// Update the hash descriptor of the partition that orig_img was built for, and the footer of
+// img, a modified copy of orig_img, then re-sign the vbmeta image with key
+fn update_footer(orig_img: &str, img: &str, key: &str) -> anyhow::Result<()> {
+    let key = SigningKey::load(key)?;
+    let orig = File::open(orig_img).with_context(|| format!("cannot open '{orig_img}'"))?;
+    let Some(orig_footer) = read_footer(&orig)? else {
+        return Ok(());
+    };
+    let partition_name = image_partition_name(&orig, &orig_footer)?;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(img)
+        .with_context(|| format!("cannot open '{img}'"))?;
+    let Some(footer) = read_footer(&file)? else {
+        return Ok(());
+    };
+    let mut vbmeta = Vbmeta::parse(&read_vbmeta(&file, &footer)?)?;
+    vbmeta.update_hash_descriptor(&file, &partition_name, footer.original_image_size)?;
+    vbmeta.sign(&key)?;
+
+    write_image_vbmeta(&file, Some(&footer), footer.vbmeta_size, &vbmeta.to_bytes())
+}
+
+pub fn update_avb_footer(orig_img: &str, img: &str, key: &str) -> bool {
+    update_footer(orig_img, img, key)
+        .context("Failed to update AVB footer")
+        .log()
+        .is_ok()
+}
+
+pub fn parse_descriptors(vbmeta: &[u8]) -> anyhow::Result<Vec<Descriptor>> {
+    let vbmeta = Vbmeta::parse(vbmeta)?;
+    let mut data = vbmeta.descriptors.as_slice();
+
+    let mut descriptors = Vec::new();
+    while !data.is_empty() {
+        let header = sub_slice(data, 0, 16)?;
+        let tag = BigEndian::read_u64(&header[0..8]);
+        let len = BigEndian::read_u64(&header[8..16]);
+        let body = sub_slice(data, 16, len)?;
+        descriptors.push(match tag {
+            DESCRIPTOR_TAG_PROPERTY => Descriptor::Property(parse_property_descriptor(body)?),
+            DESCRIPTOR_TAG_HASHTREE => Descriptor::Hashtree(parse_hashtree_descriptor(body)?),
+            DESCRIPTOR_TAG_HASH => Descriptor::Hash(parse_hash_descriptor(body)?),
+            DESCRIPTOR_TAG_KERNEL_CMDLINE => {
+                Descriptor::KernelCmdline(parse_kernel_cmdline_descriptor(body)?)
+            }
+            DESCRIPTOR_TAG_CHAIN_PARTITION => {
+                Descriptor::ChainPartition(parse_chain_partition_descriptor(body)?)
+            }
+            _ => Descriptor::Unknown(tag),
+        });
+        data = &data[16 + len as usize..];
+    }
+    Ok(descriptors)
+}
+
+fn verify_hash(file: &File, desc: &HashDescriptor) -> anyhow::Result<()> {
+    let algorithm = HashAlgorithm::from_name(&desc.hash_algorithm)?;
+    let digest = algorithm.hash_image(file, &desc.salt, desc.image_size)?;
+    if digest != desc.digest {
+        return Err(anyhow!(
+            "AVB hash mismatch in partition '{}'",
+            desc.partition_name
+        ));
+    }
+    Ok(())
+}
+
+fn verify_hashtree(file: &File, desc: &HashtreeDescriptor) -> anyhow::Result<()> {
+    if desc.data_block_size != desc.hash_block_size {
+        return Err(bad_avb!("different data and hash block sizes"));
+    }
+    let algorithm = HashAlgorithm::from_name(&desc.hash_algorithm)?;
+    let (root, tree) = hash_tree(
+        file,
+        0,
+        desc.image_size,
+        desc.data_block_size,
+        algorithm,
+        &desc.salt,
+    )?;
+    if root != desc.root_digest {
+        return Err(anyhow!(
+            "AVB hash tree root digest mismatch in partition '{}'",
+            desc.partition_name
+        ));
+    }
+    if tree.len() as u64 != desc.tree_size {
+        return Err(bad_avb!("invalid hash tree size"));
+    }
+    let mut stored = vec![0u8; tree.len()];
+    file.read_exact_at(&mut stored, desc.tree_offset)
+        .context("failed to read hash tree")?;
+    if stored != tree {
+        return Err(anyhow!(
+            "AVB hash tree mismatch in partition '{}'",
+            desc.partition_name
+        ));
+    }
+    Ok(())
+}
+
+// Verify the hash and hashtree descriptors of a partition in its AVB footer, if any
+pub fn verify_footer(file: &File, partition_name: &str) -> anyhow::Result<()> {
+    let Some(footer) = read_footer(file)? else {
+        return Ok(());
+    };
+    let vbmeta = read_vbmeta(file, &footer)?;
+    for desc in parse_descriptors(&vbmeta)? {
+        match desc {
+            Descriptor::Hash(desc) if desc.partition_name == partition_name => {
+                verify_hash(file, &desc)?;
+            }
+            Descriptor::Hashtree(desc) if desc.partition_name == partition_name => {
+                verify_hashtree(file, &desc)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
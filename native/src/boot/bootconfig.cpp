@@ -0,0 +1,344 @@
+#include <algorithm>
+#include <vector>
+
+#include <base.hpp>
+
+#include "bootimg.hpp"
+#include "magiskboot.hpp"
+
+using namespace std;
+
+// https://www.kernel.org/doc/html/latest/admin-guide/bootconfig.html
+//
+// When the bootconfig trailer is present, the section is structured as follows:
+//
+// +---------------------+
+// | data                | size bytes, padded with '\0' to 4 bytes
+// +---------------------+
+// | size                | u32 little endian
+// +---------------------+
+// | checksum            | u32 little endian, sum of all bytes in data
+// +---------------------+
+// | "#BOOTCONFIG\n"     | 12 bytes
+// +---------------------+
+
+#define BOOTCONFIG_MAGIC "#BOOTCONFIG\n"
+#define BOOTCONFIG_MAGIC_LEN 12
+#define BOOTCONFIG_ALIGN 4
+#define BOOTCONFIG_TRAILER_SZ (8 + BOOTCONFIG_MAGIC_LEN)
+
+namespace {
+
+struct bootconfig_entry {
+    // Empty for lines that only have a comment
+    string key;
+    // Keys without a value are boolean flags, keys with multiple values are arrays
+    bool has_value = false;
+    vector<string> values;
+    // Comments are kept as is, including the leading '#'
+    string comment;
+};
+
+struct bootconfig {
+    vector<bootconfig_entry> entries;
+    bool trailer = false;
+
+    bool parse(const uint8_t *buf, size_t size);
+    string dump() const;
+    bootconfig_entry *find(string_view key);
+    void set(string_view key, vector<string> values);
+    bool rm(string_view key);
+};
+
+}
+
+static uint32_t bootconfig_checksum(const void *buf, size_t size) {
+    uint32_t sum = 0;
+    auto p = static_cast<const uint8_t *>(buf);
+    for (size_t i = 0; i < size; ++i)
+        sum += p[i];
+    return sum;
+}
+
+static string_view trim(string_view s) {
+    auto begin = s.find_first_not_of(" \t\r");
+    if (begin == string_view::npos)
+        return {};
+    auto end = s.find_last_not_of(" \t\r");
+    return s.substr(begin, end - begin + 1);
+}
+
+static void skip_space(string_view &s, bool newline) {
+    auto end = s.find_first_not_of(newline ? " \t\r\n" : " \t\r");
+    s.remove_prefix(end == string_view::npos ? s.size() : end);
+}
+
+static bool is_key_char(char c) {
+    return isalnum(c) || c == '-' || c == '_' || c == '.';
+}
+
+static bool valid_key(string_view key) {
+    return !key.empty() && std::all_of(key.begin(), key.end(), is_key_char);
+}
+
+static string_view take_comment(string_view &s) {
+    auto end = s.find('\n');
+    auto comment = trim(s.substr(0, end));
+    s.remove_prefix(end == string_view::npos ? s.size() : end);
+    return comment;
+}
+
+// A value is either quoted with ' or ", or ends at the next delimiter
+static bool parse_value(string_view &s, string &value) {
+    if (!s.empty() && (s[0] == '"' || s[0] == '\'')) {
+        auto end = s.find(s[0], 1);
+        if (end == string_view::npos) {
+            fprintf(stderr, "! Unterminated quoted bootconfig value\n");
+            return false;
+        }
+        value = s.substr(1, end - 1);
+        s.remove_prefix(end + 1);
+        return true;
+    }
+    auto end = s.find_first_of(",;\n#{}");
+    auto v = trim(s.substr(0, end));
+    if (v.find_first_of("\"'") != string_view::npos) {
+        fprintf(stderr, "! Invalid bootconfig value [%.*s]\n", (int) v.size(), v.data());
+        return false;
+    }
+    value = v;
+    s.remove_prefix(end == string_view::npos ? s.size() : end);
+    return true;
+}
+
+// Comma separated values, an array can continue on the next lines after a comma
+static bool parse_values(string_view &s, vector<string> &values) {
+    for (;;) {
+        skip_space(s, false);
+        string value;
+        if (!parse_value(s, value))
+            return false;
+        values.push_back(std::move(value));
+        skip_space(s, false);
+        if (s.empty() || s[0] != ',')
+            return true;
+        s.remove_prefix(1);
+        skip_space(s, true);
+    }
+}
+
+// Values are quoted only if they have to be
+static string quote_value(const string &value) {
+    if (!value.empty() && value.find_first_of(",;\n#{}\"'") == string::npos &&
+        trim(value).size() == value.size())
+        return value;
+    char q = value.find('"') == string::npos ? '"' : '\'';
+    return q + value + q;
+}
+
+static string join_values(const vector<string> &values) {
+    string s;
+    for (auto &v : values) {
+        if (!s.empty())
+            s += ", ";
+        s += quote_value(v);
+    }
+    return s;
+}
+
+bool bootconfig::parse(const uint8_t *buf, size_t size) {
+    string_view data(reinterpret_cast<const char *>(buf), size);
+
+    if (size >= BOOTCONFIG_TRAILER_SZ &&
+        memcmp(buf + size - BOOTCONFIG_MAGIC_LEN, BOOTCONFIG_MAGIC, BOOTCONFIG_MAGIC_LEN) == 0) {
+        uint32_t data_sz, csum;
+        memcpy(&data_sz, buf + size - BOOTCONFIG_TRAILER_SZ, sizeof(data_sz));
+        memcpy(&csum, buf + size - BOOTCONFIG_TRAILER_SZ + 4, sizeof(csum));
+        if (data_sz > size - BOOTCONFIG_TRAILER_SZ) {
+            fprintf(stderr, "! Invalid bootconfig size [%u]\n", data_sz);
+            return false;
+        }
+        auto data_buf = buf + size - BOOTCONFIG_TRAILER_SZ - data_sz;
+        if (bootconfig_checksum(data_buf, data_sz) != csum)
+            fprintf(stderr, "! bootconfig checksum mismatch\n");
+        data = string_view(reinterpret_cast<const char *>(data_buf), data_sz);
+        trailer = true;
+    }
+
+    // Strip padding
+    if (auto end = data.find('\0'); end != string_view::npos)
+        data = data.substr(0, end);
+
+    // Only flat key=value lists can be modified and written back
+    for (;;) {
+        skip_space(data, true);
+        if (data.empty())
+            return true;
+        if (data[0] == ';') {
+            data.remove_prefix(1);
+            continue;
+        }
+        bootconfig_entry entry;
+        if (data[0] == '#') {
+            entry.comment = take_comment(data);
+            entries.push_back(std::move(entry));
+            continue;
+        }
+
+        auto len = std::find_if_not(data.begin(), data.end(), is_key_char) - data.begin();
+        entry.key = data.substr(0, len);
+        data.remove_prefix(len);
+        skip_space(data, false);
+        if (!entry.key.empty() && !data.empty() && data[0] == '=') {
+            data.remove_prefix(1);
+            entry.has_value = true;
+            if (!parse_values(data, entry.values))
+                return false;
+        }
+
+        if (!entry.key.empty() && (data.empty() || data[0] == ';' || data[0] == '\n')) {
+            data.remove_prefix(data.empty() ? 0 : 1);
+        } else if (!entry.key.empty() && data[0] == '#') {
+            entry.comment = take_comment(data);
+        } else {
+            if (!data.empty() && (data[0] == '{' || data[0] == '}')) {
+                fprintf(stderr, "! Nested bootconfig is not supported\n");
+            } else if (data.starts_with("+=") || data.starts_with(":=")) {
+                fprintf(stderr, "! bootconfig operator [%.2s] is not supported\n", data.data());
+            } else {
+                auto line = take_comment(data);
+                fprintf(stderr, "! Invalid bootconfig [%.*s]\n", (int) line.size(), line.data());
+            }
+            return false;
+        }
+        entries.push_back(std::move(entry));
+    }
+}
+
+string bootconfig::dump() const {
+    string data;
+    for (auto &e : entries) {
+        data += e.key;
+        if (e.has_value) {
+            data += '=';
+            data += join_values(e.values);
+        }
+        if (!e.comment.empty()) {
+            if (!e.key.empty())
+                data += ' ';
+            data += e.comment;
+        }
+        data += '\n';
+    }
+    if (trailer) {
+        data.resize(align_to(data.size(), BOOTCONFIG_ALIGN), '\0');
+        uint32_t data_sz = data.size();
+        uint32_t csum = bootconfig_checksum(data.data(), data.size());
+        data.append(reinterpret_cast<const char *>(&data_sz), sizeof(data_sz));
+        data.append(reinterpret_cast<const char *>(&csum), sizeof(csum));
+        data.append(BOOTCONFIG_MAGIC, BOOTCONFIG_MAGIC_LEN);
+    }
+    return data;
+}
+
+bootconfig_entry *bootconfig::find(string_view key) {
+    for (auto &e : entries) {
+        if (!e.key.empty() && e.key == key)
+            return &e;
+    }
+    return nullptr;
+}
+
+void bootconfig::set(string_view key, vector<string> values) {
+    auto e = find(key);
+    if (e == nullptr) {
+        entries.push_back({string(key)});
+        e = &entries.back();
+    }
+    e->has_value = true;
+    e->values = std::move(values);
+}
+
+bool bootconfig::rm(string_view key) {
+    auto size = entries.size();
+    std::erase_if(entries, [&](auto &e) { return !e.key.empty() && e.key == key; });
+    return size != entries.size();
+}
+
+int bootconfig_commands(int argc, char *argv[]) {
+    char *image = argv[0];
+    string_view action(argv[1]);
+
+    bootconfig config;
+    off_t hdr_off, off;
+    size_t space;
+    {
+        boot_img boot(image);
+        if (!boot.hdr->is_vendor || boot.hdr->header_version() < 4) {
+            fprintf(stderr, "! Only v4 vendor boot images have a bootconfig section\n");
+            exit(1);
+        }
+        if (!config.parse(boot.bootconfig, boot.hdr->bootconfig_size()))
+            exit(1);
+        hdr_off = boot.hdr_addr - boot.map.buf;
+        off = boot.bootconfig - boot.map.buf;
+        space = align_to(boot.hdr->bootconfig_size(), boot.hdr->page_size());
+    }
+
+    if (action == "print") {
+        for (auto &e : config.entries) {
+            if (e.key.empty())
+                continue;
+            if (e.has_value) {
+                printf("%s=%s\n", e.key.data(), join_values(e.values).data());
+            } else {
+                printf("%s\n", e.key.data());
+            }
+        }
+        return 0;
+    } else if (argc > 2 && action == "get") {
+        auto e = config.find(argv[2]);
+        if (e == nullptr)
+            exit(1);
+        // One value per line for arrays
+        for (auto &v : e->values)
+            printf("%s\n", v.data());
+        return 0;
+    } else if (argc > 2 && action == "set") {
+        string_view kv(argv[2]);
+        auto eq = kv.find('=');
+        auto key = trim(kv.substr(0, eq));
+        string_view value = eq == string_view::npos ? string_view() : kv.substr(eq + 1);
+        vector<string> values;
+        if (eq == string_view::npos || !valid_key(key) || !parse_values(value, values) ||
+            !trim(value).empty()) {
+            fprintf(stderr, "! Invalid bootconfig parameter [%s], expected KEY=VALUE\n", argv[2]);
+            exit(1);
+        }
+        config.set(key, std::move(values));
+    } else if (argc > 2 && action == "rm") {
+        if (!config.rm(argv[2])) {
+            fprintf(stderr, "! Bootconfig parameter [%s] does not exist\n", argv[2]);
+            exit(1);
+        }
+    } else {
+        return 1;
+    }
+
+    auto data = config.dump();
+    if (data.size() > space) {
+        fprintf(stderr, "! New bootconfig [%zu] does not fit in the original section [%zu]\n",
+                data.size(), space);
+        exit(1);
+    }
+
+    // The section keeps its original position and aligned size, patch in-place
+    auto m = mmap_data(image, true);
+    memcpy(m.buf + off, data.data(), data.size());
+    memset(m.buf + off + data.size(), 0, space - data.size());
+    boot_img_hdr_vnd_v4 hdr;
+    memcpy(&hdr, m.buf + hdr_off, sizeof(hdr));
+    hdr.bootconfig_size = data.size();
+    memcpy(m.buf + hdr_off, &hdr, sizeof(hdr));
+    return 0;
+}
@@ -1,5 +1,6 @@
 #include <functional>
 #include <memory>
+#include <set>
 
 #include <libfdt.h>
 #include <mincrypt/sha.h>
@@ -50,6 +51,52 @@ static size_t restore(int fd, const char *filename) {
     return size;
 }
 
+static string vnd_ramdisk_name(const vendor_ramdisk_table_entry_v4 &entry) {
+    auto name = reinterpret_cast<const char *>(entry.ramdisk_name);
+    return string(name, strnlen(name, VENDOR_RAMDISK_NAME_SIZE));
+}
+
+// The files of the vendor ramdisks in table order. Names that map to the file of
+// an earlier ramdisk get the index of their entry as a suffix, so that each ramdisk
+// is unpacked to and repacked from its own file.
+static vector<string> vnd_ramdisk_files(const vector<string> &names) {
+    vector<string> files;
+    set<string> used;
+    for (size_t i = 0; i < names.size(); ++i) {
+        string base(VND_RAMDISK_DIR "/");
+        if (names[i].empty()) {
+            base += "ramdisk";
+        } else {
+            // Never let names in the image escape the directory
+            for (char c : names[i])
+                base += c == '/' ? '_' : c;
+        }
+        string file = base + ".cpio";
+        for (size_t n = i; used.count(file); ++n)
+            file = base + "." + to_string(n) + ".cpio";
+        used.insert(file);
+        files.push_back(std::move(file));
+    }
+    return files;
+}
+
+static const char *vnd_ramdisk_types[] = { "none", "platform", "recovery", "dlkm" };
+
+static string vnd_ramdisk_type_name(uint32_t type) {
+    if (type < std::size(vnd_ramdisk_types))
+        return vnd_ramdisk_types[type];
+    return std::to_string(type);
+}
+
+static uint32_t parse_vnd_ramdisk_type(string_view type) {
+    for (uint32_t i = 0; i < std::size(vnd_ramdisk_types); ++i) {
+        if (type == vnd_ramdisk_types[i])
+            return i;
+    }
+    int t = parse_int(type);
+    return t < 0 ? VENDOR_RAMDISK_TYPE_NONE : t;
+}
+
 void dyn_img_hdr::print() {
     uint32_t ver = header_version();
     fprintf(stderr, "%-*s [%u]\n", PADDING, "HEADER_VER", ver);
@@ -118,21 +165,31 @@ void dyn_img_hdr::dump_hdr_file() {
     fclose(fp);
 }
 
+void dyn_img_hdr::set_name(string_view value) {
+    if (name()) {
+        memset(name(), 0, 16);
+        memcpy(name(), value.data(), value.length() > 15 ? 15 : value.length());
+    }
+}
+
+void dyn_img_hdr::set_cmdline(string_view value) {
+    memset(cmdline(), 0, BOOT_ARGS_SIZE);
+    memset(extra_cmdline(), 0, BOOT_EXTRA_ARGS_SIZE);
+    if (value.length() > BOOT_ARGS_SIZE) {
+        memcpy(cmdline(), value.data(), BOOT_ARGS_SIZE);
+        auto len = std::min(value.length() - BOOT_ARGS_SIZE, (size_t) BOOT_EXTRA_ARGS_SIZE);
+        memcpy(extra_cmdline(), &value[BOOT_ARGS_SIZE], len);
+    } else {
+        memcpy(cmdline(), value.data(), value.length());
+    }
+}
+
 void dyn_img_hdr::load_hdr_file() {
     parse_prop_file(HEADER_FILE, [=](string_view key, string_view value) -> bool {
-        if (key == "name" && name()) {
-            memset(name(), 0, 16);
-            memcpy(name(), value.data(), value.length() > 15 ? 15 : value.length());
+        if (key == "name") {
+            set_name(value);
         } else if (key == "cmdline") {
-            memset(cmdline(), 0, BOOT_ARGS_SIZE);
-            memset(extra_cmdline(), 0, BOOT_EXTRA_ARGS_SIZE);
-            if (value.length() > BOOT_ARGS_SIZE) {
-                memcpy(cmdline(), value.data(), BOOT_ARGS_SIZE);
-                auto len = std::min(value.length() - BOOT_ARGS_SIZE, (size_t) BOOT_EXTRA_ARGS_SIZE);
-                memcpy(extra_cmdline(), &value[BOOT_ARGS_SIZE], len);
-            } else {
-                memcpy(cmdline(), value.data(), value.length());
-            }
+            set_cmdline(value);
         } else if (key == "os_version") {
             int patch_level = os_version() & 0x7ff;
             int a, b, c;
@@ -251,6 +308,7 @@ dyn_img_hdr *boot_img::create_hdr(const uint8_t *addr, format_t type) {
 
     if (h->page_size >= 0x02000000) {
         fprintf(stderr, "PXA_BOOT_HDR\n");
+        flags[PXA_FLAG] = true;
         hdr_addr = addr;
         return new dyn_img_pxa(addr);
     }
@@ -308,14 +366,48 @@ dyn_img_hdr *boot_img::create_hdr(const uint8_t *addr, format_t type) {
     return make_hdr(addr);
 }
 
-#define get_block(name)                 \
-name = hdr_addr + off;                  \
-off += hdr->name##_size();              \
+bool boot_img::parse_vnd_ramdisk_table() {
+    uint32_t num = hdr->vendor_ramdisk_table_entry_num();
+    uint32_t entry_sz = hdr->vendor_ramdisk_table_entry_size();
+    if (num == 0)
+        return false;
+    if (entry_sz < sizeof(vendor_ramdisk_table_entry_v4) ||
+        (uint64_t) num * entry_sz > hdr->vendor_ramdisk_table_size()) {
+        fprintf(stderr, "! Invalid vendor ramdisk table, treating ramdisk as a single blob\n");
+        return false;
+    }
+
+    for (uint32_t i = 0; i < num; ++i) {
+        auto entry = reinterpret_cast<const vendor_ramdisk_table_entry_v4 *>(
+                vendor_ramdisk_table + i * entry_sz);
+        if ((uint64_t) entry->ramdisk_offset + entry->ramdisk_size > hdr->ramdisk_size()) {
+            fprintf(stderr, "! Invalid vendor ramdisk table, treating ramdisk as a single blob\n");
+            vnd_ramdisks.clear();
+            return false;
+        }
+        vnd_ramdisks.push_back({entry, check_fmt_lg(ramdisk + entry->ramdisk_offset, entry->ramdisk_size)});
+    }
+
+    for (auto &r : vnd_ramdisks) {
+        fprintf(stderr, "%-*s [%s]\n", PADDING, "VND_RAMDISK", vnd_ramdisk_name(*r.entry).data());
+        fprintf(stderr, "%-*s [%s]\n", PADDING, "VND_RAMDISK_TYPE",
+                vnd_ramdisk_type_name(r.entry->ramdisk_type).data());
+        fprintf(stderr, "%-*s [%u]\n", PADDING, "VND_RAMDISK_SZ", r.entry->ramdisk_size);
+        fprintf(stderr, "%-*s [%s]\n", PADDING, "VND_RAMDISK_FMT", fmt2name[r.fmt]);
+    }
+    return true;
+}
+
+#define get_block(name)                                 \
+name = hdr_addr + off;                                  \
+blocks.push_back({#name, name, hdr->name##_size()});    \
+off += hdr->name##_size();                              \
 off = align_to(off, hdr->page_size());
 
 #define get_ignore(name)                                            \
 if (hdr->name##_size()) {                                           \
     auto blk_sz = align_to(hdr->name##_size(), hdr->page_size());   \
+    blocks.push_back({#name, hdr_addr + off, hdr->name##_size()});  \
     ignore_size += blk_sz;                                          \
     off += blk_sz;                                                  \
 }
@@ -341,11 +433,11 @@ void boot_img::parse_image(const uint8_t *addr, format_t type) {
     get_block(extra);
     get_block(recovery_dtbo);
     get_block(dtb);
+    get_block(vendor_ramdisk_table);
+    get_block(bootconfig);
 
     ignore = hdr_addr + off;
     get_ignore(signature)
-    get_ignore(vendor_ramdisk_table)
-    get_ignore(bootconfig)
 
     if (auto size = hdr->kernel_size()) {
         if (int dtb_off = find_dtb_offset(kernel, size); dtb_off > 0) {
@@ -401,9 +493,8 @@ void boot_img::parse_image(const uint8_t *addr, format_t type) {
         fprintf(stderr, "%-*s [%s]\n", PADDING, "KERNEL_FMT", fmt2name[k_fmt]);
     }
     if (auto size = hdr->ramdisk_size()) {
-        if (hdr->is_vendor && hdr->header_version() >= 4) {
-            // v4 vendor boot contains multiple ramdisks
-            // Do not try to mess with it for now
+        if (hdr->is_vendor && hdr->header_version() >= 4 && parse_vnd_ramdisk_table()) {
+            // v4 vendor boot contains multiple ramdisks, each of them is handled separately
             r_fmt = UNKNOWN;
         } else {
             r_fmt = check_fmt_lg(ramdisk, size);
@@ -418,7 +509,8 @@ void boot_img::parse_image(const uint8_t *addr, format_t type) {
             hdr->ramdisk_size() -= sizeof(mtk_hdr);
             r_fmt = check_fmt_lg(ramdisk, hdr->ramdisk_size());
         }
-        fprintf(stderr, "%-*s [%s]\n", PADDING, "RAMDISK_FMT", fmt2name[r_fmt]);
+        if (vnd_ramdisks.empty())
+            fprintf(stderr, "%-*s [%s]\n", PADDING, "RAMDISK_FMT", fmt2name[r_fmt]);
     }
     if (auto size = hdr->extra_size()) {
         e_fmt = check_fmt_lg(extra, size);
@@ -473,11 +565,116 @@ int split_image_dtb(const char *filename) {
     }
 }
 
+static const char *boot_flag_names[] = {
+    "mtk_kernel", "mtk_ramdisk", "chromeos", "dhtb", "seandroid", "lg_bump", "sha256",
+    "blob", "nookhd", "acclaim", "amonet", "avb", "zimage_kernel", "pxa"
+};
+static_assert(std::size(boot_flag_names) == BOOT_FLAGS_MAX);
+
+static rust::BootHeader boot_header(const boot_img &boot) {
+    auto hdr = boot.hdr;
+    rust::BootHeader info{};
+
+    if (boot.flags[PXA_FLAG])
+        info.format = "pxa";
+    else
+        info.format = hdr->is_vendor ? "aosp_vendor" : "aosp";
+    for (int i = 0; i < BOOT_FLAGS_MAX; ++i) {
+        if (boot.flags[i])
+            info.flags.push_back(boot_flag_names[i]);
+    }
+    info.header_version = hdr->header_version();
+    info.header_size = hdr->hdr_size();
+    info.page_size = hdr->page_size();
+    info.image_size = boot.map.sz;
+
+    if (char *n = hdr->name())
+        info.name = rust::String::lossy(n, strnlen(n, BOOT_NAME_SIZE));
+    string cmdline(hdr->cmdline(), strnlen(hdr->cmdline(), BOOT_ARGS_SIZE));
+    if (char *extra = hdr->extra_cmdline())
+        cmdline.append(extra, strnlen(extra, BOOT_EXTRA_ARGS_SIZE));
+    info.cmdline = rust::String::lossy(cmdline.data(), cmdline.length());
+    if (char *id = hdr->id()) {
+        string checksum;
+        int size = boot.flags[SHA256_FLAG] ? SHA256_DIGEST_SIZE : SHA_DIGEST_SIZE;
+        for (int i = 0; i < size; ++i) {
+            char hex[3];
+            ssprintf(hex, sizeof(hex), "%02hhx", id[i]);
+            checksum += hex;
+        }
+        info.id = checksum;
+    }
+    info.os_version = hdr->os_version();
+
+    for (auto &b : boot.blocks) {
+        if (b.size == 0)
+            continue;
+        string_view name(b.name);
+        format_t fmt;
+        if (name == "kernel")
+            fmt = boot.k_fmt;
+        else if (name == "ramdisk")
+            fmt = boot.r_fmt;
+        else if (name == "extra")
+            fmt = boot.e_fmt;
+        else
+            fmt = check_fmt(b.addr, b.size);
+        info.blocks.push_back({b.name, (uint64_t) (b.addr - boot.map.buf), b.size, fmt2name[fmt]});
+    }
+    if (hdr->kernel_dt_size) {
+        info.blocks.push_back({"kernel_dtb", (uint64_t) (boot.kernel_dtb - boot.map.buf),
+                               hdr->kernel_dt_size, fmt2name[DTB]});
+    }
+
+    for (auto &r : boot.vnd_ramdisks) {
+        rust::VendorRamdiskEntry entry{};
+        entry.name = rust::String::lossy(vnd_ramdisk_name(*r.entry));
+        entry.ramdisk_type = r.entry->ramdisk_type;
+        entry.offset = boot.ramdisk + r.entry->ramdisk_offset - boot.map.buf;
+        entry.size = r.entry->ramdisk_size;
+        entry.format = fmt2name[r.fmt];
+        for (int i = 0; i < VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE; ++i)
+            entry.board_id.push_back(r.entry->board_id[i]);
+        info.vendor_ramdisks.push_back(std::move(entry));
+    }
+
+    if (boot.flags[AVB_FLAG]) {
+        auto footer = boot.avb_footer;
+        auto vbmeta = boot.vbmeta;
+        info.has_avb = true;
+        info.avb.version_major = __builtin_bswap32(footer->version_major);
+        info.avb.version_minor = __builtin_bswap32(footer->version_minor);
+        info.avb.original_image_size = __builtin_bswap64(footer->original_image_size);
+        info.avb.vbmeta_offset = __builtin_bswap64(footer->vbmeta_offset);
+        info.avb.vbmeta_size = __builtin_bswap64(footer->vbmeta_size);
+        info.avb.algorithm_type = __builtin_bswap32(vbmeta->algorithm_type);
+        info.avb.flags = __builtin_bswap32(vbmeta->flags);
+        info.avb.rollback_index = __builtin_bswap64(vbmeta->rollback_index);
+        info.avb.rollback_index_location = __builtin_bswap32(vbmeta->rollback_index_location);
+        auto release = reinterpret_cast<const char *>(vbmeta->release_string);
+        info.avb.release_string = rust::String::lossy(release, strnlen(release, AVB_RELEASE_STRING_SIZE));
+    }
+
+    return info;
+}
+
 int unpack(const char *image, bool skip_decomp, bool hdr) {
     boot_img boot(image);
 
-    if (hdr)
+    if (hdr) {
         boot.hdr->dump_hdr_file();
+        if (!boot.vnd_ramdisks.empty()) {
+            FILE *fp = xfopen(HEADER_FILE, "a");
+            for (auto &r : boot.vnd_ramdisks) {
+                fprintf(fp, "vendor_ramdisk=%s:%s\n",
+                        vnd_ramdisk_type_name(r.entry->ramdisk_type).data(),
+                        vnd_ramdisk_name(*r.entry).data());
+            }
+            fclose(fp);
+        }
+        if (!rust::dump_boot_header(boot_header(boot), HEADER_JSON))
+            return 1;
+    }
 
     // Dump kernel
     if (!skip_decomp && COMPRESSED(boot.k_fmt)) {
@@ -494,7 +691,25 @@ int unpack(const char *image, bool skip_decomp, bool hdr) {
     dump(boot.kernel_dtb, boot.hdr->kernel_dt_size, KER_DTB_FILE);
 
     // Dump ramdisk
-    if (!skip_decomp && COMPRESSED(boot.r_fmt)) {
+    if (!boot.vnd_ramdisks.empty()) {
+        xmkdir(VND_RAMDISK_DIR, 0755);
+        vector<string> names;
+        for (auto &r : boot.vnd_ramdisks)
+            names.push_back(vnd_ramdisk_name(*r.entry));
+        auto files = vnd_ramdisk_files(names);
+        for (size_t i = 0; i < files.size(); ++i) {
+            auto &r = boot.vnd_ramdisks[i];
+            auto &file = files[i];
+            const uint8_t *buf = boot.ramdisk + r.entry->ramdisk_offset;
+            if (!skip_decomp && COMPRESSED(r.fmt)) {
+                int fd = creat(file.data(), 0644);
+                decompress(r.fmt, fd, buf, r.entry->ramdisk_size);
+                close(fd);
+            } else {
+                dump(buf, r.entry->ramdisk_size, file.data());
+            }
+        }
+    } else if (!skip_decomp && COMPRESSED(boot.r_fmt)) {
         if (boot.hdr->ramdisk_size() != 0) {
             int fd = creat(RAMDISK_FILE, 0644);
             decompress(boot.r_fmt, fd, boot.ramdisk, boot.hdr->ramdisk_size());
@@ -524,15 +739,85 @@ int unpack(const char *image, bool skip_decomp, bool hdr) {
     // Dump dtb
     dump(boot.dtb, boot.hdr->dtb_size(), DTB_FILE);
 
+    // Dump bootconfig
+    dump(boot.bootconfig, boot.hdr->bootconfig_size(), BOOTCONFIG_FILE);
+
     return boot.flags[CHROMEOS_FLAG] ? 2 : 0;
 }
 
+static vector<vendor_ramdisk_table_entry_v4> load_vnd_ramdisk_table(
+        const boot_img &boot, const rust::BootHeader *info) {
+    vector<vendor_ramdisk_table_entry_v4> table;
+    if (info) {
+        // Missing properties are already filled in with the original values
+        for (auto &r : info->vendor_ramdisks) {
+            vendor_ramdisk_table_entry_v4 entry{};
+            entry.ramdisk_type = r.ramdisk_type;
+            memcpy(entry.ramdisk_name, r.name.data(),
+                   std::min(r.name.size(), (size_t) VENDOR_RAMDISK_NAME_SIZE - 1));
+            auto ids = std::min(r.board_id.size(), (size_t) VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE);
+            memcpy(entry.board_id, r.board_id.data(), ids * sizeof(uint32_t));
+            table.push_back(entry);
+        }
+    } else if (access(HEADER_FILE, R_OK) == 0) {
+        parse_prop_file(HEADER_FILE, [&](string_view key, string_view value) -> bool {
+            if (key != "vendor_ramdisk")
+                return true;
+            vendor_ramdisk_table_entry_v4 entry{};
+            string_view type = "none";
+            string_view name = value;
+            if (auto pos = value.find(':'); pos != string_view::npos) {
+                type = value.substr(0, pos);
+                name = value.substr(pos + 1);
+            }
+            name = name.substr(0, VENDOR_RAMDISK_NAME_SIZE - 1);
+            entry.ramdisk_type = parse_vnd_ramdisk_type(type);
+            memcpy(entry.ramdisk_name, name.data(), name.length());
+            // Keep the hardware identifiers of existing ramdisks
+            for (auto &r : boot.vnd_ramdisks) {
+                if (vnd_ramdisk_name(*r.entry) == name) {
+                    memcpy(entry.board_id, r.entry->board_id, sizeof(entry.board_id));
+                    break;
+                }
+            }
+            table.push_back(entry);
+            return true;
+        });
+    }
+    if (table.empty()) {
+        for (auto &r : boot.vnd_ramdisks)
+            table.push_back(*r.entry);
+    }
+    return table;
+}
+
+static bool same_vnd_ramdisks(const rust::BootHeader &a, const rust::BootHeader &b) {
+    return std::equal(a.vendor_ramdisks.begin(), a.vendor_ramdisks.end(),
+                      b.vendor_ramdisks.begin(), b.vendor_ramdisks.end(),
+                      [](auto &x, auto &y) {
+        return x.name == y.name && x.ramdisk_type == y.ramdisk_type &&
+               std::equal(x.board_id.begin(), x.board_id.end(),
+                          y.board_id.begin(), y.board_id.end());
+    });
+}
+
+static format_t vnd_ramdisk_fmt(const boot_img &boot, string_view name) {
+    for (auto &r : boot.vnd_ramdisks) {
+        if (vnd_ramdisk_name(*r.entry) == name)
+            return r.fmt;
+    }
+    // All vendor ramdisks are concatenated together, new ones have to use the
+    // same compression method as the existing ones. Fallback to lz4 (legacy),
+    // which is what GKIs are required to use.
+    return boot.vnd_ramdisks.empty() ? LZ4_LEGACY : boot.vnd_ramdisks[0].fmt;
+}
+
 #define file_align_with(page_size) \
 write_zero(fd, align_padding(lseek(fd, 0, SEEK_CUR) - off.header, page_size))
 
 #define file_align() file_align_with(boot.hdr->page_size())
 
-void repack(const char *src_img, const char *out_img, bool skip_comp) {
+void repack(const char *src_img, const char *out_img, bool skip_comp, const char *avb_key) {
     const boot_img boot(src_img);
     fprintf(stderr, "Repack to boot image: [%s]\n", out_img);
 
@@ -553,10 +838,31 @@ void repack(const char *src_img, const char *out_img, bool skip_comp) {
     hdr->ramdisk_size() = 0;
     hdr->second_size() = 0;
     hdr->dtb_size() = 0;
+    hdr->vendor_ramdisk_table_size() = 0;
+    hdr->vendor_ramdisk_table_entry_num() = 0;
+    hdr->bootconfig_size() = 0;
     hdr->kernel_dt_size = 0;
+    bool vnd_v4 = hdr->is_vendor && hdr->header_version() >= 4;
 
+    // Header configurations. unpack dumps both the legacy header file and header.json,
+    // properties changed in header.json take precedence over the header file.
     if (access(HEADER_FILE, R_OK) == 0)
         hdr->load_hdr_file();
+    rust::BootHeader info{};
+    bool json_vnd_table = false;
+    if (access(HEADER_JSON, R_OK) == 0) {
+        auto orig = boot_header(boot);
+        info = boot_header(boot);
+        if (!rust::load_boot_header(info, HEADER_JSON))
+            exit(1);
+        if (info.name != orig.name)
+            hdr->set_name(string_view(info.name.data(), info.name.size()));
+        if (info.cmdline != orig.cmdline)
+            hdr->set_cmdline(string_view(info.cmdline.data(), info.cmdline.size()));
+        if (info.os_version != orig.os_version)
+            hdr->os_version() = info.os_version;
+        json_vnd_table = !same_vnd_ramdisks(info, orig);
+    }
 
     /***************
      * Write blocks
@@ -637,7 +943,34 @@ void repack(const char *src_img, const char *out_img, bool skip_comp) {
         // Copy MTK headers
         xwrite(fd, boot.r_hdr, sizeof(mtk_hdr));
     }
-    if (access(RAMDISK_FILE, R_OK) == 0) {
+    vector<vendor_ramdisk_table_entry_v4> ram_table;
+    if (vnd_v4) {
+        auto table = load_vnd_ramdisk_table(boot, json_vnd_table ? &info : nullptr);
+        vector<string> names;
+        for (auto &entry : table)
+            names.push_back(vnd_ramdisk_name(entry));
+        auto files = vnd_ramdisk_files(names);
+        for (size_t i = 0; i < table.size(); ++i) {
+            auto &entry = table[i];
+            auto &name = names[i];
+            auto &file = files[i];
+            if (access(file.data(), R_OK) != 0)
+                continue;
+            auto m = mmap_data(file.data());
+            auto r_fmt = vnd_ramdisk_fmt(boot, name);
+            entry.ramdisk_offset = hdr->ramdisk_size();
+            if (!skip_comp && !COMPRESSED_ANY(check_fmt(m.buf, m.sz)) && COMPRESSED(r_fmt)) {
+                entry.ramdisk_size = compress(r_fmt, fd, m.buf, m.sz);
+            } else {
+                entry.ramdisk_size = xwrite(fd, m.buf, m.sz);
+            }
+            hdr->ramdisk_size() += entry.ramdisk_size;
+            ram_table.push_back(entry);
+        }
+    }
+    if (!ram_table.empty()) {
+        file_align();
+    } else if (access(RAMDISK_FILE, R_OK) == 0) {
         auto m = mmap_data(RAMDISK_FILE);
         auto r_fmt = boot.r_fmt;
         if (!skip_comp && !hdr->is_vendor && hdr->header_version() == 4 && r_fmt != LZ4_LEGACY) {
@@ -653,6 +986,10 @@ void repack(const char *src_img, const char *out_img, bool skip_comp) {
             hdr->ramdisk_size() = xwrite(fd, m.buf, m.sz);
         }
         file_align();
+    } else if (vnd_v4) {
+        // Keep the original ramdisks, which are still described by the original table
+        hdr->ramdisk_size() = xwrite(fd, boot.ramdisk, boot.hdr->ramdisk_size());
+        file_align();
     }
 
     // second
@@ -688,6 +1025,28 @@ void repack(const char *src_img, const char *out_img, bool skip_comp) {
         file_align();
     }
 
+    // vendor ramdisk table
+    if (!ram_table.empty()) {
+        hdr->vendor_ramdisk_table_entry_num() = ram_table.size();
+        hdr->vendor_ramdisk_table_entry_size() = sizeof(vendor_ramdisk_table_entry_v4);
+        hdr->vendor_ramdisk_table_size() =
+                xwrite(fd, ram_table.data(), ram_table.size() * sizeof(vendor_ramdisk_table_entry_v4));
+        file_align();
+    } else if (vnd_v4 && boot.hdr->vendor_ramdisk_table_size()) {
+        // The table could not be parsed, or there is no vendor ramdisk to repack
+        hdr->vendor_ramdisk_table_entry_num() = boot.hdr->vendor_ramdisk_table_entry_num();
+        hdr->vendor_ramdisk_table_entry_size() = boot.hdr->vendor_ramdisk_table_entry_size();
+        hdr->vendor_ramdisk_table_size() =
+                xwrite(fd, boot.vendor_ramdisk_table, boot.hdr->vendor_ramdisk_table_size());
+        file_align();
+    }
+
+    // bootconfig
+    if (vnd_v4 && access(BOOTCONFIG_FILE, R_OK) == 0) {
+        hdr->bootconfig_size() = restore(fd, BOOTCONFIG_FILE);
+        file_align();
+    }
+
     // Directly copy ignored blobs
     if (boot.ignore_size) {
         // ignore_size should already be aligned
@@ -816,4 +1175,10 @@ void repack(const char *src_img, const char *out_img, bool skip_comp) {
         auto b_hdr = reinterpret_cast<blob_hdr *>(out.buf);
         b_hdr->size = off.total - sizeof(blob_hdr);
     }
+
+    if (boot.flags[AVB_FLAG] && avb_key) {
+        // Update the hash descriptor of the new image and re-sign vbmeta
+        if (!rust::update_avb_footer(src_img, out_img, avb_key))
+            exit(1);
+    }
 }
@@ -3,6 +3,8 @@
 #include <stdint.h>
 #include <utility>
 #include <bitset>
+#include <vector>
+#include <string_view>
 #include "format.hpp"
 
 /******************
@@ -109,6 +111,11 @@ struct AvbVBMetaImageHeader {
 #define VENDOR_RAMDISK_NAME_SIZE 32
 #define VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE 16
 
+#define VENDOR_RAMDISK_TYPE_NONE 0
+#define VENDOR_RAMDISK_TYPE_PLATFORM 1
+#define VENDOR_RAMDISK_TYPE_RECOVERY 2
+#define VENDOR_RAMDISK_TYPE_DLKM 3
+
 /* When the boot image header has a version of 0 - 2, the structure of the boot
  * image is as follows:
  *
@@ -369,8 +376,10 @@ struct dyn_img_hdr {
 
     // v4 specific
     decl_val(signature_size, uint32_t)
-    decl_val(vendor_ramdisk_table_size, uint32_t)
-    decl_val(bootconfig_size, uint32_t)
+    decl_var(vendor_ramdisk_table_size, 32)
+    decl_var(vendor_ramdisk_table_entry_num, 32)
+    decl_var(vendor_ramdisk_table_entry_size, 32)
+    decl_var(bootconfig_size, 32)
 
     virtual ~dyn_img_hdr() {
         free(raw);
@@ -383,6 +392,8 @@ struct dyn_img_hdr {
     const void *raw_hdr() const { return raw; }
     void print();
     void dump_hdr_file();
+    void set_name(std::string_view name);
+    void set_cmdline(std::string_view cmdline);
     void load_hdr_file();
 
 protected:
@@ -533,6 +544,8 @@ struct dyn_img_vnd_v4 : public dyn_img_vnd_v3 {
     impl_cls(vnd_v4)
 
     impl_val(vendor_ramdisk_table_size)
+    impl_val(vendor_ramdisk_table_entry_num)
+    impl_val(vendor_ramdisk_table_entry_size)
     impl_val(bootconfig_size)
 };
 
@@ -559,6 +572,7 @@ enum {
     AMONET_FLAG,
     AVB_FLAG,
     ZIMAGE_KERNEL,
+    PXA_FLAG,
     BOOT_FLAGS_MAX
 };
 
@@ -619,6 +633,23 @@ struct boot_img {
     const uint8_t *extra;
     const uint8_t *recovery_dtbo;
     const uint8_t *dtb;
+    const uint8_t *vendor_ramdisk_table;
+    const uint8_t *bootconfig;
+
+    // All blocks defined in header, before any special headers are stripped
+    struct blk_info {
+        const char *name;
+        const uint8_t *addr;
+        uint32_t size;
+    };
+    std::vector<blk_info> blocks;
+
+    // Ramdisk fragments listed in the v4 vendor ramdisk table
+    struct vnd_ramdisk {
+        const vendor_ramdisk_table_entry_v4 *entry;
+        format_t fmt;
+    };
+    std::vector<vnd_ramdisk> vnd_ramdisks;
 
     // Pointer to blocks defined in header, but we do not care
     const uint8_t *ignore;
@@ -629,4 +660,5 @@ struct boot_img {
 
     void parse_image(const uint8_t *addr, format_t type);
     dyn_img_hdr *create_hdr(const uint8_t *addr, format_t type);
+    bool parse_vnd_ramdisk_table();
 };
@@ -0,0 +1,123 @@
+use std::io::{Cursor, Read};
+
+use anyhow::{anyhow, Context};
+use byteorder::{ByteOrder, LittleEndian};
+
+// Patch formats produced by AOSP's bsdiff, used by (BROTLI_)BSDIFF payload operations
+// https://android.googlesource.com/platform/external/bsdiff/+/refs/heads/master/README.md
+
+const BSDIFF_MAGIC: &[u8] = b"BSDIFF40";
+const BSDF2_MAGIC: &[u8] = b"BSDF2";
+const HEADER_SIZE: usize = 32;
+
+macro_rules! bad_patch {
+    ($msg:literal) => {
+        anyhow!(concat!("invalid bsdiff patch: ", $msg))
+    };
+    ($($args:tt)*) => {
+        anyhow!("invalid bsdiff patch: {}", format_args!($($args)*))
+    };
+}
+
+// Signed integers are stored in sign-magnitude little endian
+fn offtin(buf: &[u8]) -> i64 {
+    let val = LittleEndian::read_u64(buf);
+    let mag = (val & !(1 << 63)) as i64;
+    if val & (1 << 63) != 0 {
+        -mag
+    } else {
+        mag
+    }
+}
+
+fn read_offt(r: &mut dyn Read) -> anyhow::Result<i64> {
+    let buf = &mut [0u8; 8];
+    r.read_exact(buf)?;
+    Ok(offtin(buf))
+}
+
+fn open_stream<'a>(compression: u8, data: &'a [u8]) -> anyhow::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        0 => Box::new(Cursor::new(data)),
+        1 => Box::new(bzip2_rs::DecoderReader::new(data)),
+        2 => Box::new(brotli_decompressor::Decompressor::new(data, 4096)),
+        _ => return Err(bad_patch!("unknown compression type {}", compression)),
+    })
+}
+
+fn to_len(v: i64) -> anyhow::Result<usize> {
+    usize::try_from(v).map_err(|_| bad_patch!("negative length"))
+}
+
+// The new data is at most max_size bytes, which bounds the buffer allocated for it
+pub fn bspatch(old: &[u8], patch: &[u8], max_size: u64) -> anyhow::Result<Vec<u8>> {
+    if patch.len() < HEADER_SIZE {
+        return Err(bad_patch!("header is truncated"));
+    }
+
+    // The legacy format always uses bzip2, BSDF2 specifies the compression of each stream
+    let compression = if patch.starts_with(BSDIFF_MAGIC) {
+        [1u8; 3]
+    } else if patch.starts_with(BSDF2_MAGIC) {
+        [patch[5], patch[6], patch[7]]
+    } else {
+        return Err(bad_patch!("invalid magic"));
+    };
+
+    let ctrl_len = to_len(offtin(&patch[8..16]))?;
+    let diff_len = to_len(offtin(&patch[16..24]))?;
+    let new_size = to_len(offtin(&patch[24..32]))?;
+    if new_size as u64 > max_size {
+        return Err(bad_patch!("new size {} is too large", new_size));
+    }
+
+    let ctrl_end = HEADER_SIZE
+        .checked_add(ctrl_len)
+        .filter(|end| *end <= patch.len())
+        .ok_or(bad_patch!("control stream is truncated"))?;
+    let diff_end = ctrl_end
+        .checked_add(diff_len)
+        .filter(|end| *end <= patch.len())
+        .ok_or(bad_patch!("diff stream is truncated"))?;
+
+    let mut ctrl = open_stream(compression[0], &patch[HEADER_SIZE..ctrl_end])?;
+    let mut diff = open_stream(compression[1], &patch[ctrl_end..diff_end])?;
+    let mut extra = open_stream(compression[2], &patch[diff_end..])?;
+
+    let mut new = vec![0u8; new_size];
+    let mut new_pos = 0usize;
+    let mut old_pos = 0i64;
+
+    while new_pos < new_size {
+        let diff_size = to_len(read_offt(&mut ctrl).context("failed to read control stream")?)?;
+        let extra_size = to_len(read_offt(&mut ctrl).context("failed to read control stream")?)?;
+        let seek = read_offt(&mut ctrl).context("failed to read control stream")?;
+
+        // Add the old data to the diff data
+        if diff_size > new_size - new_pos {
+            return Err(bad_patch!("diff data exceeds new size"));
+        }
+        let chunk = &mut new[new_pos..(new_pos + diff_size)];
+        diff.read_exact(chunk).context("failed to read diff stream")?;
+        for (i, b) in chunk.iter_mut().enumerate() {
+            let pos = old_pos.saturating_add(i as i64);
+            if pos >= 0 && (pos as usize) < old.len() {
+                *b = b.wrapping_add(old[pos as usize]);
+            }
+        }
+        new_pos += diff_size;
+        old_pos = old_pos.saturating_add(diff_size as i64);
+
+        // Copy over the extra data
+        if extra_size > new_size - new_pos {
+            return Err(bad_patch!("extra data exceeds new size"));
+        }
+        extra
+            .read_exact(&mut new[new_pos..(new_pos + extra_size)])
+            .context("failed to read extra stream")?;
+        new_pos += extra_size;
+        old_pos = old_pos.saturating_add(seek);
+    }
+
+    Ok(new)
+}
@@ -7,10 +7,12 @@ mod gen;
 
 fn main() {
     println!("cargo:rerun-if-changed=update_metadata.proto");
+    println!("cargo:rerun-if-changed=puffin.proto");
     protobuf_codegen::Codegen::new()
         .pure()
         .include(".")
         .input("update_metadata.proto")
+        .input("puffin.proto")
         .customize(Customize::default().gen_mod_rs(false))
         .out_dir(".")
         .run_from_script();
@@ -52,13 +52,16 @@ protected:
     } mode;
 
     gz_strm(mode_t mode, out_strm_ptr &&base) :
-            filter_out_stream(std::move(base)), mode(mode), strm{}, outbuf{0} {
+            filter_out_stream(std::move(base)), mode(mode), strm{}, header{}, outbuf{0} {
         switch(mode) {
         case DECODE:
             inflateInit2(&strm, 15 | 16);
             break;
         case ENCODE:
             deflateInit2(&strm, 9, Z_DEFLATED, 15 | 16, 8, Z_DEFAULT_STRATEGY);
+            // Fixed header fields, so that the output does not depend on the zlib build
+            header.os = 3;
+            deflateSetHeader(&strm, &header);
             break;
         default:
             break;
@@ -67,6 +70,7 @@ protected:
 
 private:
     z_stream strm;
+    gz_header header;
     uint8_t outbuf[CHUNK];
 
     bool do_write(const void *buf, size_t len, int flush) {
@@ -717,18 +721,3 @@ void compress(const char *method, const char *infile, const char *outfile) {
     if (rm_in)
         unlink(infile);
 }
-
-bool decompress(rust::Slice<const uint8_t> buf, int fd) {
-    format_t type = check_fmt(buf.data(), buf.length());
-
-    if (!COMPRESSED(type)) {
-        LOGE("Input file is not a supported compression format!\n");
-        return false;
-    }
-
-    auto strm = get_decoder(type, make_unique<fd_channel>(fd));
-    if (!strm->write(buf.data(), buf.length())) {
-        return false;
-    }
-    return true;
-}
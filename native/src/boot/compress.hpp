@@ -1,6 +1,5 @@
 #pragma once
 
-#include <cxx.h>
 #include <stream.hpp>
 
 #include "format.hpp"
@@ -9,4 +8,3 @@ out_strm_ptr get_encoder(format_t type, out_strm_ptr &&base);
 out_strm_ptr get_decoder(format_t type, out_strm_ptr &&base);
 void compress(const char *method, const char *infile, const char *outfile);
 void decompress(char *infile, const char *outfile);
-bool decompress(rust::Slice<const uint8_t> buf, int fd);
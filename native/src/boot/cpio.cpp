@@ -43,10 +43,12 @@ static uint32_t x8u(const char *hex) {
     return val;
 }
 
-cpio_entry::cpio_entry(uint32_t mode) : mode(mode), uid(0), gid(0), filesize(0), data(nullptr) {}
+cpio_entry::cpio_entry(uint32_t mode) :
+mode(mode), uid(0), gid(0), mtime(0), filesize(0), data(nullptr) {}
 
 cpio_entry::cpio_entry(const cpio_newc_header *h) :
-mode(x8u(h->mode)), uid(x8u(h->uid)), gid(x8u(h->gid)), filesize(x8u(h->filesize)), data(nullptr)
+mode(x8u(h->mode)), uid(x8u(h->uid)), gid(x8u(h->gid)), mtime(x8u(h->mtime)),
+filesize(x8u(h->filesize)), data(nullptr)
 {}
 
 void cpio::dump(const char *file) {
@@ -117,13 +119,33 @@ bool cpio::exists(const char *name) {
     return entries.count(name) != 0;
 }
 
+// https://reproducible-builds.org/specs/source-date-epoch/
+static bool source_date_epoch(uint32_t &epoch) {
+    const char *val = getenv("SOURCE_DATE_EPOCH");
+    if (val == nullptr || val[0] == '\0')
+        return false;
+    char *end;
+    errno = 0;
+    unsigned long long v = strtoull(val, &end, 10);
+    if (*end != '\0' || errno || v > UINT32_MAX) {
+        LOGW("Ignore invalid SOURCE_DATE_EPOCH [%s]\n", val);
+        return false;
+    }
+    epoch = v;
+    return true;
+}
+
 #define do_out(buf, len) pos += fwrite(buf, 1, len, out);
 #define out_align() do_out(zeros, align_padding(pos, 4))
 void cpio::dump(FILE *out) {
     size_t pos = 0;
+    // Entries are sorted by name, so inode numbers only depend on the set of entries
     unsigned inode = 300000;
     char header[111];
     char zeros[4] = {0};
+    // Entries carry varying mtimes, normalize them for reproducible output
+    uint32_t epoch;
+    bool reproducible = source_date_epoch(epoch);
     for (auto &e : entries) {
         sprintf(header, "070701%08x%08x%08x%08x%08x%08x%08x%08x%08x%08x%08x%08x%08x",
                 inode++,    // e->ino
@@ -131,7 +153,7 @@ void cpio::dump(FILE *out) {
                 e.second->uid,
                 e.second->gid,
                 1,          // e->nlink
-                0,          // e->mtime
+                reproducible ? epoch : e.second->mtime,
                 e.second->filesize,
                 0,          // e->devmajor
                 0,          // e->devminor
@@ -212,6 +234,83 @@ bool cpio::mv(const char *from, const char *to) {
     return false;
 }
 
+bool cpio::chmod(mode_t mode, const char *name) {
+    auto it = entries.find(name);
+    if (it != entries.end()) {
+        it->second->mode = (it->second->mode & S_IFMT) | (mode & 07777);
+        fprintf(stderr, "Change mode [%s] (%04o)\n", name, mode);
+        return true;
+    }
+    fprintf(stderr, "Cannot find entry %s\n", name);
+    return false;
+}
+
+bool cpio::chown(uid_t uid, gid_t gid, const char *name) {
+    auto it = entries.find(name);
+    if (it != entries.end()) {
+        it->second->uid = uid;
+        it->second->gid = gid;
+        fprintf(stderr, "Change owner [%s] (%u:%u)\n", name, uid, gid);
+        return true;
+    }
+    fprintf(stderr, "Cannot find entry %s\n", name);
+    return false;
+}
+
+void cpio::touch(const char *name, time_t mtime) {
+    auto it = entries.find(name);
+    if (it == entries.end()) {
+        insert(name, new cpio_entry(S_IFREG | 0644));
+        it = entries.find(name);
+    }
+    it->second->mtime = mtime;
+    fprintf(stderr, "Touch [%s] (%ld)\n", name, (long) mtime);
+}
+
+static void mode_str(uint32_t mode, char *s) {
+    switch (mode & S_IFMT) {
+        case S_IFDIR: s[0] = 'd'; break;
+        case S_IFLNK: s[0] = 'l'; break;
+        case S_IFCHR: s[0] = 'c'; break;
+        case S_IFBLK: s[0] = 'b'; break;
+        case S_IFIFO: s[0] = 'p'; break;
+        case S_IFSOCK: s[0] = 's'; break;
+        default: s[0] = '-'; break;
+    }
+    const char *rwx = "rwxrwxrwx";
+    for (int i = 0; i < 9; ++i)
+        s[i + 1] = (mode & (0400 >> i)) ? rwx[i] : '-';
+    if (mode & S_ISUID) s[3] = (mode & S_IXUSR) ? 's' : 'S';
+    if (mode & S_ISGID) s[6] = (mode & S_IXGRP) ? 's' : 'S';
+    if (mode & S_ISVTX) s[9] = (mode & S_IXOTH) ? 't' : 'T';
+    s[10] = '\0';
+}
+
+void cpio::ls(const char *name, bool l) {
+    size_t len = name ? strlen(name) : 0;
+    for (auto &e : entries) {
+        // List the entry itself and everything under it
+        if (len && (e.first.compare(0, len, name) != 0 ||
+                    (e.first[len] != '/' && e.first[len] != '\0')))
+            continue;
+        if (!l) {
+            printf("%s\n", e.first.data());
+            continue;
+        }
+        char mode[11];
+        char time[20];
+        mode_str(e.second->mode, mode);
+        time_t mtime = e.second->mtime;
+        strftime(time, sizeof(time), "%Y-%m-%d %H:%M", gmtime(&mtime));
+        printf("%s %5u %5u %10u %s %s", mode, e.second->uid, e.second->gid,
+               e.second->filesize, time, e.first.data());
+        if (S_ISLNK(e.second->mode)) {
+            printf(" -> %.*s", (int) e.second->filesize, (char *) e.second->data);
+        }
+        printf("\n");
+    }
+}
+
 #define pos_align(p) p = align_to(p, 4)
 
 void cpio::load_cpio(const char *buf, size_t sz) {
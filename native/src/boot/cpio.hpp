@@ -12,6 +12,7 @@ struct cpio_entry {
     uint32_t mode;
     uint32_t uid;
     uint32_t gid;
+    uint32_t mtime;
     uint32_t filesize;
     void *data;
 
@@ -40,6 +41,10 @@ public:
     void mkdir(mode_t mode, const char *name);
     void ln(const char *target, const char *name);
     bool mv(const char *from, const char *to);
+    bool chmod(mode_t mode, const char *name);
+    bool chown(uid_t uid, gid_t gid, const char *name);
+    void touch(const char *name, time_t mtime);
+    void ls(const char *name = nullptr, bool l = false);
 
 protected:
     entry_map entries;
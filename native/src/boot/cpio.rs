@@ -0,0 +1,626 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{create_dir_all, remove_dir, remove_file, DirBuilder, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::{chown, symlink, DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+use std::str;
+
+use anyhow::{anyhow, Context};
+
+use base::ResultExt;
+
+// The file type bits of the mode field are defined by the cpio format,
+// and do not depend on the mode_t of the platform
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const CRC_MAGIC: &[u8] = b"070702";
+const ODC_MAGIC: &[u8] = b"070707";
+const NEWC_HEADER_SIZE: usize = 110;
+const ODC_HEADER_SIZE: usize = 76;
+const TRAILER: &str = "TRAILER!!!";
+
+macro_rules! bad_cpio {
+    ($($args:tt)*) => {
+        anyhow!("bad cpio header: {}", format!($($args)*))
+    };
+}
+
+// The variants of the portable cpio formats. newc and crc share the same header
+// of 8 digit hex fields, crc additionally stores the sum of the data bytes.
+// odc uses octal fields of varying width and has no padding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CpioFormat {
+    #[default]
+    Newc,
+    Crc,
+    Odc,
+}
+
+impl CpioFormat {
+    fn from_magic(magic: &[u8]) -> Option<CpioFormat> {
+        match magic {
+            NEWC_MAGIC => Some(CpioFormat::Newc),
+            CRC_MAGIC => Some(CpioFormat::Crc),
+            ODC_MAGIC => Some(CpioFormat::Odc),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpioEntry {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // 0 for entries that were not loaded from an archive, they are assigned on dump
+    pub ino: u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub data: Vec<u8>,
+}
+
+impl CpioEntry {
+    pub fn new(mode: u32, data: Vec<u8>) -> CpioEntry {
+        CpioEntry {
+            mode,
+            nlink: 1,
+            data,
+            ..Default::default()
+        }
+    }
+
+    pub fn file_type(&self) -> u32 {
+        self.mode & S_IFMT
+    }
+}
+
+fn parse_field(field: &[u8], radix: u32) -> anyhow::Result<u32> {
+    let s = str::from_utf8(field).map_err(|_| bad_cpio!("invalid field"))?;
+    u32::from_str_radix(s, radix).map_err(|_| bad_cpio!("invalid field '{}'", s))
+}
+
+fn align_4(pos: usize) -> usize {
+    (pos + 3) & !3
+}
+
+// The fields of a header, in the order of the newc format
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    filesize: u32,
+    dev_major: u32,
+    dev_minor: u32,
+    rdev_major: u32,
+    rdev_minor: u32,
+    namesize: u32,
+    check: u32,
+}
+
+impl Header {
+    fn parse_newc(buf: &[u8]) -> anyhow::Result<Header> {
+        let field = |i: usize| parse_field(&buf[6 + i * 8..14 + i * 8], 16);
+        Ok(Header {
+            ino: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            filesize: field(6)?,
+            dev_major: field(7)?,
+            dev_minor: field(8)?,
+            rdev_major: field(9)?,
+            rdev_minor: field(10)?,
+            namesize: field(11)?,
+            check: field(12)?,
+        })
+    }
+
+    // odc only has a single 18 bit device number, which is split as the old 8:8 encoding
+    fn parse_odc(buf: &[u8]) -> anyhow::Result<Header> {
+        let field = |start: usize, len: usize| parse_field(&buf[start..start + len], 8);
+        let dev = field(6, 6)?;
+        let rdev = field(42, 6)?;
+        Ok(Header {
+            ino: field(12, 6)?,
+            mode: field(18, 6)?,
+            uid: field(24, 6)?,
+            gid: field(30, 6)?,
+            nlink: field(36, 6)?,
+            mtime: field(48, 11)?,
+            filesize: field(65, 11)?,
+            dev_major: dev >> 8,
+            dev_minor: dev & 0xff,
+            rdev_major: rdev >> 8,
+            rdev_minor: rdev & 0xff,
+            namesize: field(59, 6)?,
+            check: 0,
+        })
+    }
+
+    fn write_newc<W: Write>(&self, out: &mut W, format: CpioFormat) -> anyhow::Result<()> {
+        let magic = if format == CpioFormat::Crc {
+            "070702"
+        } else {
+            "070701"
+        };
+        write!(
+            out,
+            "{}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            magic,
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.mtime,
+            self.filesize,
+            self.dev_major,
+            self.dev_minor,
+            self.rdev_major,
+            self.rdev_minor,
+            self.namesize,
+            self.check
+        )?;
+        Ok(())
+    }
+
+    fn write_odc<W: Write>(&self, out: &mut W) -> anyhow::Result<()> {
+        let fits = |v: u32, digits: u32| (v as u64) < 8u64.pow(digits);
+        let dev = (self.dev_major << 8) | (self.dev_minor & 0xff);
+        let rdev = (self.rdev_major << 8) | (self.rdev_minor & 0xff);
+        let small = [
+            dev, self.ino, self.mode, self.uid, self.gid, self.nlink, rdev,
+        ];
+        if small.iter().any(|v| !fits(*v, 6)) || !fits(self.namesize, 6) {
+            return Err(anyhow!("entry metadata does not fit in odc format"));
+        }
+        write!(
+            out,
+            "070707{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:011o}{:06o}{:011o}",
+            dev,
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            rdev,
+            self.mtime,
+            self.namesize,
+            self.filesize
+        )?;
+        Ok(())
+    }
+}
+
+// Counts the bytes written, which newc and crc use for padding
+struct CountWriter<W> {
+    inner: W,
+    pos: usize,
+}
+
+impl<W: Write> CountWriter<W> {
+    fn write_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.inner.write_all(data)?;
+        self.pos += data.len();
+        Ok(())
+    }
+
+    fn write_header(&mut self, header: &Header, format: CpioFormat) -> anyhow::Result<()> {
+        match format {
+            CpioFormat::Odc => header.write_odc(&mut self.inner)?,
+            _ => header.write_newc(&mut self.inner, format)?,
+        }
+        self.pos += match format {
+            CpioFormat::Odc => ODC_HEADER_SIZE,
+            _ => NEWC_HEADER_SIZE,
+        };
+        Ok(())
+    }
+
+    fn align(&mut self, format: CpioFormat) -> anyhow::Result<()> {
+        if format != CpioFormat::Odc {
+            let zeros = [0u8; 4];
+            self.write_data(&zeros[..align_4(self.pos) - self.pos])?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Cpio {
+    // The format of the first archive loaded, used when dumping
+    pub format: CpioFormat,
+    pub entries: BTreeMap<String, Box<CpioEntry>>,
+}
+
+impl Cpio {
+    pub fn new() -> Cpio {
+        Cpio::default()
+    }
+
+    pub fn load_from_data(data: &[u8]) -> anyhow::Result<Cpio> {
+        let mut cpio = Cpio::new();
+        let mut format = None;
+        let mut pos = 0;
+        while pos < data.len() {
+            let magic = data
+                .get(pos..pos + 6)
+                .and_then(CpioFormat::from_magic)
+                .ok_or_else(|| bad_cpio!("invalid magic at offset {}", pos))?;
+            format.get_or_insert(magic);
+
+            let header_size = match magic {
+                CpioFormat::Odc => ODC_HEADER_SIZE,
+                _ => NEWC_HEADER_SIZE,
+            };
+            let buf = data
+                .get(pos..pos + header_size)
+                .ok_or_else(|| bad_cpio!("truncated header"))?;
+            let hdr = match magic {
+                CpioFormat::Odc => Header::parse_odc(buf)?,
+                _ => Header::parse_newc(buf)?,
+            };
+            pos += header_size;
+
+            // The name size includes the terminating NUL byte
+            let name = data
+                .get(pos..pos + hdr.namesize as usize)
+                .and_then(|n| n.split_last())
+                .ok_or_else(|| bad_cpio!("truncated name"))?
+                .1;
+            let name = str::from_utf8(name).map_err(|_| bad_cpio!("invalid name"))?;
+            pos += hdr.namesize as usize;
+            if magic != CpioFormat::Odc {
+                pos = align_4(pos);
+            }
+
+            if name == TRAILER {
+                // Android supports multiple cpio archives being concatenated,
+                // search for the next cpio header
+                let next = data.get(pos..).and_then(|rest| {
+                    rest.windows(6)
+                        .position(|w| CpioFormat::from_magic(w).is_some())
+                });
+                match next {
+                    Some(off) => {
+                        pos += off;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+
+            let file = data
+                .get(pos..pos + hdr.filesize as usize)
+                .ok_or_else(|| bad_cpio!("truncated data of '{}'", name))?;
+            pos += hdr.filesize as usize;
+            if magic != CpioFormat::Odc {
+                pos = align_4(pos);
+            }
+            if magic == CpioFormat::Crc {
+                let sum = file.iter().fold(0u32, |s, b| s.wrapping_add(*b as u32));
+                if sum != hdr.check {
+                    return Err(anyhow!("checksum mismatch of '{}'", name));
+                }
+            }
+            if name == "." || name == ".." {
+                continue;
+            }
+
+            let entry = CpioEntry {
+                mode: hdr.mode,
+                uid: hdr.uid,
+                gid: hdr.gid,
+                ino: hdr.ino,
+                nlink: hdr.nlink,
+                mtime: hdr.mtime,
+                dev_major: hdr.dev_major,
+                dev_minor: hdr.dev_minor,
+                rdev_major: hdr.rdev_major,
+                rdev_minor: hdr.rdev_minor,
+                data: file.to_vec(),
+            };
+            cpio.entries.insert(name.to_owned(), Box::new(entry));
+        }
+        cpio.format = format.unwrap_or_default();
+        Ok(cpio)
+    }
+
+    pub fn load_from_file(path: &str) -> anyhow::Result<Cpio> {
+        eprintln!("Loading cpio: [{}]", path);
+        let mut data = Vec::new();
+        File::open(path)
+            .with_context(|| format!("cannot open '{}'", path))?
+            .read_to_end(&mut data)?;
+        Cpio::load_from_data(&data)
+    }
+
+    // Entries keep the metadata they were loaded with, new entries get
+    // inode numbers that are not used by any other entry
+    pub fn write_to<W: Write>(&self, out: W, format: CpioFormat) -> anyhow::Result<()> {
+        self.write_entries(out, format, None)
+    }
+
+    // With an epoch, all entries get it as mtime, and inode numbers in the order of
+    // their names, so that the output only depends on the names and contents
+    pub fn write_reproducible<W: Write>(
+        &self,
+        out: W,
+        format: CpioFormat,
+        epoch: u32,
+    ) -> anyhow::Result<()> {
+        self.write_entries(out, format, Some(epoch))
+    }
+
+    fn write_entries<W: Write>(
+        &self,
+        out: W,
+        format: CpioFormat,
+        epoch: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let mut out = CountWriter { inner: out, pos: 0 };
+        // Same as magiskboot cpio, except for odc where inode numbers only have 18 bits
+        let base = if format == CpioFormat::Odc { 0 } else { 300000 };
+        let mut inode = match epoch {
+            Some(_) => base,
+            None => self
+                .entries
+                .values()
+                .map(|e| e.ino)
+                .max()
+                .unwrap_or(0)
+                .max(base),
+        };
+        for (name, entry) in self.entries.iter() {
+            let ino = if entry.ino == 0 || epoch.is_some() {
+                inode += 1;
+                inode
+            } else {
+                entry.ino
+            };
+            let check = match format {
+                CpioFormat::Crc => entry
+                    .data
+                    .iter()
+                    .fold(0u32, |s, b| s.wrapping_add(*b as u32)),
+                _ => 0,
+            };
+            let header = Header {
+                ino,
+                mode: entry.mode,
+                uid: entry.uid,
+                gid: entry.gid,
+                nlink: entry.nlink,
+                mtime: epoch.unwrap_or(entry.mtime),
+                filesize: entry.data.len() as u32,
+                dev_major: entry.dev_major,
+                dev_minor: entry.dev_minor,
+                rdev_major: entry.rdev_major,
+                rdev_minor: entry.rdev_minor,
+                namesize: name.len() as u32 + 1,
+                check,
+            };
+            out.write_header(&header, format)
+                .with_context(|| format!("cannot write entry '{}'", name))?;
+            out.write_data(name.as_bytes())?;
+            out.write_data(b"\0")?;
+            out.align(format)?;
+            if !entry.data.is_empty() {
+                out.write_data(&entry.data)?;
+                out.align(format)?;
+            }
+        }
+        let trailer = Header {
+            ino: 0,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            mtime: 0,
+            filesize: 0,
+            dev_major: 0,
+            dev_minor: 0,
+            rdev_major: 0,
+            rdev_minor: 0,
+            namesize: TRAILER.len() as u32 + 1,
+            check: 0,
+        };
+        out.write_header(&trailer, format)?;
+        out.write_data(TRAILER.as_bytes())?;
+        out.write_data(b"\0")?;
+        out.align(format)?;
+        out.inner.flush()?;
+        Ok(())
+    }
+
+    // Output is reproducible if SOURCE_DATE_EPOCH is set, same as magiskboot cpio
+    pub fn dump(&self, path: &str) -> anyhow::Result<()> {
+        eprintln!("Dump cpio: [{}]", path);
+        let file = File::create(path).with_context(|| format!("cannot create '{}'", path))?;
+        // Invalid values are ignored
+        let epoch = env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|val| val.parse().ok());
+        self.write_entries(BufWriter::new(file), self.format, epoch)
+    }
+
+    pub fn rm(&mut self, path: &str, recursive: bool) {
+        let path = norm_path(path);
+        if self.entries.remove(&path).is_some() {
+            eprintln!("Remove [{}]", path);
+        }
+        if recursive {
+            let prefix = format!("{}/", path);
+            self.entries.retain(|name, _| {
+                if name.starts_with(&prefix) {
+                    eprintln!("Remove [{}]", name);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    }
+
+    fn extract_entry(&self, path: &str, out: &Path) -> anyhow::Result<()> {
+        let entry = self
+            .entries
+            .get(path)
+            .ok_or_else(|| anyhow!("no such file"))?;
+        eprintln!("Extract [{}] to [{}]", path, out.display());
+
+        remove_file(out).ok();
+        remove_dir(out).ok();
+        if let Some(parent) = out.parent().filter(|p| !p.as_os_str().is_empty()) {
+            create_dir_all(parent)?;
+        }
+        match entry.file_type() {
+            S_IFDIR => DirBuilder::new().mode(entry.mode & 0o777).create(out)?,
+            S_IFREG => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(entry.mode & 0o777)
+                    .open(out)?;
+                file.write_all(&entry.data)?;
+                // Not running as root is fine
+                chown(out, Some(entry.uid), Some(entry.gid)).ok();
+            }
+            S_IFLNK => {
+                let target = str::from_utf8(&entry.data)?;
+                symlink(target, out)?;
+            }
+            _ => return Err(anyhow!("unknown entry type")),
+        }
+        Ok(())
+    }
+
+    pub fn extract(&self, path: &str, out: &str) -> anyhow::Result<()> {
+        let path = norm_path(path);
+        self.extract_entry(&path, Path::new(out))
+            .with_context(|| format!("cannot extract [{}]", path))
+    }
+
+    pub fn extract_all(&self) -> anyhow::Result<()> {
+        for path in self.entries.keys() {
+            self.extract_entry(path, Path::new(path))
+                .with_context(|| format!("cannot extract [{}]", path))?;
+        }
+        Ok(())
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.entries.contains_key(&norm_path(path))
+    }
+
+    pub fn add(&mut self, mode: u32, path: &str, file: &str) -> anyhow::Result<()> {
+        let mut data = Vec::new();
+        File::open(file)
+            .with_context(|| format!("cannot open '{}'", file))?
+            .read_to_end(&mut data)?;
+        self.entries.insert(
+            norm_path(path),
+            Box::new(CpioEntry::new(S_IFREG | (mode & 0o7777), data)),
+        );
+        eprintln!("Add entry [{}] ({:04o})", path, mode);
+        Ok(())
+    }
+
+    pub fn mkdir(&mut self, mode: u32, path: &str) {
+        self.entries.insert(
+            norm_path(path),
+            Box::new(CpioEntry::new(S_IFDIR | (mode & 0o7777), Vec::new())),
+        );
+        eprintln!("Create directory [{}] ({:04o})", path, mode);
+    }
+
+    pub fn ln(&mut self, target: &str, path: &str) {
+        self.entries.insert(
+            norm_path(path),
+            Box::new(CpioEntry::new(S_IFLNK, target.as_bytes().to_vec())),
+        );
+        eprintln!("Create symlink [{}] -> [{}]", path, target);
+    }
+
+    pub fn mv(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        let entry = self
+            .entries
+            .remove(&norm_path(from))
+            .ok_or_else(|| anyhow!("cannot find entry {}", from))?;
+        self.entries.insert(norm_path(to), entry);
+        eprintln!("Move [{}] -> [{}]", from, to);
+        Ok(())
+    }
+}
+
+// Entry names are stored without leading and trailing slashes
+fn norm_path(path: &str) -> String {
+    path.trim_matches('/').to_owned()
+}
+
+// The cxx bridge cannot return errors to C++, so they are logged instead
+
+pub fn cpio_new() -> Box<Cpio> {
+    Box::new(Cpio::new())
+}
+
+pub fn cpio_load(cpio: &mut Cpio, file: &str) -> bool {
+    match Cpio::load_from_file(file).log() {
+        Ok(c) => {
+            *cpio = c;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+pub fn cpio_dump(cpio: &Cpio, file: &str) -> bool {
+    cpio.dump(file).log().is_ok()
+}
+
+pub fn cpio_rm(cpio: &mut Cpio, path: &str, recursive: bool) {
+    cpio.rm(path, recursive)
+}
+
+pub fn cpio_extract(cpio: &Cpio, path: &str, out: &str) -> bool {
+    cpio.extract(path, out).log().is_ok()
+}
+
+pub fn cpio_extract_all(cpio: &Cpio) -> bool {
+    cpio.extract_all().log().is_ok()
+}
+
+pub fn cpio_exists(cpio: &Cpio, path: &str) -> bool {
+    cpio.exists(path)
+}
+
+pub fn cpio_add(cpio: &mut Cpio, mode: u32, path: &str, file: &str) -> bool {
+    cpio.add(mode, path, file).log().is_ok()
+}
+
+pub fn cpio_mkdir(cpio: &mut Cpio, mode: u32, path: &str) {
+    cpio.mkdir(mode, path)
+}
+
+pub fn cpio_ln(cpio: &mut Cpio, target: &str, path: &str) {
+    cpio.ln(target, path)
+}
+
+pub fn cpio_mv(cpio: &mut Cpio, from: &str, to: &str) -> bool {
+    cpio.mv(from, to).log().is_ok()
+}
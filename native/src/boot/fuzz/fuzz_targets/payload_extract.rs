@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use magiskboot_fuzz::extract_all;
+
+fuzz_target!(|data: &[u8]| {
+    extract_all(data, None, true);
+    extract_all(data, None, false);
+});
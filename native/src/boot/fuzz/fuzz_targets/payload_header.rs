@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use magiskboot::PayloadExtractor;
+
+// Only parse the header and the manifest
+fuzz_target!(|data: &[u8]| {
+    if let Ok(extractor) = PayloadExtractor::from_reader(data) {
+        extractor.partition_names();
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use magiskboot_fuzz::{extract_all, PayloadSpec};
+
+// Structurally valid payloads reach the operations far more often than raw bytes
+fuzz_target!(|spec: PayloadSpec| {
+    let payload = spec.build();
+    extract_all(&payload, spec.source.as_deref(), spec.verify);
+});
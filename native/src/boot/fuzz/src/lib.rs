@@ -0,0 +1,237 @@
+// Shared code of the payload fuzz targets, run them with `cargo fuzz run <target>`
+// in this directory. payload_synthetic builds payloads from a structured description,
+// covering every header field and operation type, including unknown operation types,
+// out of order or overlapping data, overlapping extents and huge lengths.
+
+use std::io;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+
+use arbitrary::Arbitrary;
+use lzma_rust2::{XzOptions, XzWriter};
+use protobuf::{EnumOrUnknown, Message, MessageField};
+use sha2::{Digest, Sha256};
+
+use magiskboot::update_metadata::install_operation::Type;
+use magiskboot::update_metadata::{
+    DeltaArchiveManifest, Extent, InstallOperation, PartitionInfo, PartitionUpdate,
+};
+use magiskboot::{PayloadExtractor, PAYLOAD_MAGIC};
+
+// Larger partitions only make the fuzzer slower
+const MAX_PARTITION_SIZE: u64 = 16 * 1024 * 1024;
+
+// Discards the output, so that partition sizes do not matter
+#[derive(Default)]
+struct NullSink {
+    pos: u64,
+    len: u64,
+}
+
+impl Write for NullSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for NullSink {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        self.pos = pos.ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+// Extract every partition of the payload, errors are expected and ignored
+pub fn extract_all(payload: &[u8], source: Option<&[u8]>, verify: bool) {
+    let Ok(mut extractor) = PayloadExtractor::from_seekable(Cursor::new(payload)) else {
+        return;
+    };
+    extractor.set_verify(verify);
+    let partitions: Vec<_> = extractor
+        .manifest()
+        .partitions
+        .iter()
+        .filter(|p| {
+            p.new_partition_info
+                .as_ref()
+                .is_some_and(|info| info.has_size() && info.size() <= MAX_PARTITION_SIZE)
+        })
+        .map(|p| p.partition_name().to_owned())
+        .collect();
+    for name in partitions {
+        let src = source.map(Cursor::new);
+        extractor
+            .extract_partition(&name, NullSink::default(), src, |_| {})
+            .ok();
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+pub enum OpType {
+    Known(u8),
+    Unknown(i32),
+}
+
+#[derive(Arbitrary, Debug)]
+pub enum OpData {
+    None,
+    // Appended to the data blobs
+    Raw(Vec<u8>),
+    // Compressed with xz, then appended to the data blobs
+    Xz(Vec<u8>),
+    // Any range of the data blobs, possibly overlapping other operations
+    Range { offset: u16, length: u16 },
+    // Lengths that are never backed by data
+    Huge { offset: u64, length: u64 },
+}
+
+#[derive(Arbitrary, Debug)]
+pub struct OperationSpec {
+    pub op_type: OpType,
+    pub data: OpData,
+    pub data_hash: bool,
+    pub src_extents: Vec<(u16, u16)>,
+    pub dst_extents: Vec<(u16, u16)>,
+    pub src_length: Option<u32>,
+}
+
+#[derive(Arbitrary, Debug)]
+pub struct PartitionSpec {
+    pub name: u8,
+    pub size: u32,
+    pub operations: Vec<OperationSpec>,
+}
+
+#[derive(Arbitrary, Debug)]
+pub struct PayloadSpec {
+    // The header fields default to the values of a valid payload.
+    // With version 1, the first two partitions are the rootfs and the kernel.
+    pub version: Option<u64>,
+    pub manifest_len: Option<u64>,
+    pub sig_len: u32,
+    pub block_size: Option<u32>,
+    pub minor_version: u8,
+    pub partitions: Vec<PartitionSpec>,
+    pub source: Option<Vec<u8>>,
+    pub verify: bool,
+}
+
+fn extent(&(start, num): &(u16, u16)) -> Extent {
+    let mut ext = Extent::new();
+    ext.set_start_block(start as u64);
+    ext.set_num_blocks(num as u64);
+    ext
+}
+
+fn op_type(t: &OpType) -> EnumOrUnknown<Type> {
+    match t {
+        OpType::Known(v) => EnumOrUnknown::from_i32(*v as i32 % 15),
+        OpType::Unknown(v) => EnumOrUnknown::from_i32(*v),
+    }
+}
+
+impl PayloadSpec {
+    // Serialize into payload.bin format
+    pub fn build(&self) -> Vec<u8> {
+        let mut manifest = DeltaArchiveManifest::new();
+        if let Some(block_size) = self.block_size {
+            manifest.set_block_size(block_size);
+        }
+        manifest.set_minor_version(self.minor_version as u32);
+
+        let mut blobs = Vec::new();
+        for p in self.partitions.iter() {
+            let mut partition = PartitionUpdate::new();
+            partition.set_partition_name(format!("p{}", p.name));
+            for op in p.operations.iter() {
+                let mut operation = InstallOperation::new();
+                operation.type_ = Some(op_type(&op.op_type));
+                operation.src_extents = op.src_extents.iter().map(extent).collect();
+                operation.dst_extents = op.dst_extents.iter().map(extent).collect();
+                if let Some(len) = op.src_length {
+                    operation.set_src_length(len as u64);
+                }
+
+                let blob = match &op.data {
+                    OpData::None => None,
+                    OpData::Raw(data) => Some(data.clone()),
+                    OpData::Xz(data) => {
+                        let mut xz = XzWriter::new(Vec::new(), XzOptions::with_preset(0)).unwrap();
+                        xz.write_all(data).unwrap();
+                        Some(xz.finish().unwrap())
+                    }
+                    OpData::Range { offset, length } => {
+                        operation.set_data_offset(*offset as u64);
+                        operation.set_data_length(*length as u64);
+                        let start = (*offset as usize).min(blobs.len());
+                        let end = (start + *length as usize).min(blobs.len());
+                        if op.data_hash {
+                            operation
+                                .set_data_sha256_hash(Sha256::digest(&blobs[start..end]).to_vec());
+                        }
+                        None
+                    }
+                    OpData::Huge { offset, length } => {
+                        operation.set_data_offset(*offset);
+                        operation.set_data_length(*length);
+                        None
+                    }
+                };
+                if let Some(blob) = blob {
+                    operation.set_data_offset(blobs.len() as u64);
+                    operation.set_data_length(blob.len() as u64);
+                    if op.data_hash {
+                        operation.set_data_sha256_hash(Sha256::digest(&blob).to_vec());
+                    }
+                    blobs.extend_from_slice(&blob);
+                }
+                partition.operations.push(operation);
+            }
+
+            let mut info = PartitionInfo::new();
+            info.set_size(p.size as u64);
+            partition.new_partition_info = MessageField::some(info);
+            manifest.partitions.push(partition);
+        }
+
+        // Major version 1 stores the first two partitions as rootfs and kernel
+        let legacy = self.version == Some(1);
+        if legacy {
+            let mut partitions = std::mem::take(&mut manifest.partitions).into_iter();
+            if let Some(mut p) = partitions.next() {
+                manifest.install_operations = std::mem::take(&mut p.operations);
+                manifest.new_rootfs_info = MessageField::from_option(p.new_partition_info.take());
+            }
+            if let Some(mut p) = partitions.next() {
+                manifest.kernel_install_operations = std::mem::take(&mut p.operations);
+                manifest.new_kernel_info = MessageField::from_option(p.new_partition_info.take());
+            }
+        }
+
+        let manifest = manifest.write_to_bytes().unwrap();
+        let mut payload = Vec::new();
+        payload.extend_from_slice(PAYLOAD_MAGIC.as_bytes());
+        payload.extend_from_slice(&self.version.unwrap_or(2).to_be_bytes());
+        let manifest_len = self.manifest_len.unwrap_or(manifest.len() as u64);
+        payload.extend_from_slice(&manifest_len.to_be_bytes());
+        if !legacy {
+            payload.extend_from_slice(&self.sig_len.to_be_bytes());
+        }
+        payload.extend_from_slice(&manifest);
+        if !legacy {
+            payload.resize(payload.len() + (self.sig_len as usize).min(4096), 0);
+        }
+        payload.extend_from_slice(&blobs);
+        payload
+    }
+}
@@ -0,0 +1,158 @@
+// Round trips of the Rust Cpio type through the newc, crc and odc formats.
+// Run with `cargo test` in the fuzz directory.
+
+use magiskboot::{Cpio, CpioEntry, CpioFormat, S_IFDIR, S_IFLNK, S_IFREG};
+
+const FORMATS: [CpioFormat; 3] = [CpioFormat::Newc, CpioFormat::Crc, CpioFormat::Odc];
+
+// Entries as loaded from an archive, with metadata that has to be preserved
+fn sample() -> Cpio {
+    let mut cpio = Cpio::new();
+    let mut add = |name: &str, mode: u32, ino: u32, data: &[u8]| {
+        let mut entry = CpioEntry::new(mode, data.to_vec());
+        entry.uid = 1000;
+        entry.gid = 2000;
+        entry.ino = ino;
+        entry.mtime = 1700000000 + ino;
+        entry.dev_major = 0xfd;
+        entry.dev_minor = 3;
+        cpio.entries.insert(name.to_owned(), Box::new(entry));
+    };
+    add("init", S_IFREG | 0o750, 11, b"#!/system/bin/sh\n");
+    add("system", S_IFDIR | 0o755, 12, b"");
+    add("system/bin/odd", S_IFREG | 0o644, 13, b"abcde");
+    add("bin", S_IFLNK | 0o777, 14, b"system/bin");
+    cpio
+}
+
+fn write(cpio: &Cpio, format: CpioFormat) -> Vec<u8> {
+    let mut out = Vec::new();
+    cpio.write_to(&mut out, format).unwrap();
+    out
+}
+
+#[test]
+fn round_trip() {
+    let cpio = sample();
+    for format in FORMATS {
+        let data = write(&cpio, format);
+        let loaded = Cpio::load_from_data(&data).unwrap();
+        assert_eq!(loaded.format, format);
+        assert_eq!(loaded.entries, cpio.entries, "{format:?}");
+        assert_eq!(write(&loaded, format), data, "{format:?}");
+    }
+}
+
+#[test]
+fn convert_formats() {
+    let cpio = sample();
+    for from in FORMATS {
+        let loaded = Cpio::load_from_data(&write(&cpio, from)).unwrap();
+        for to in FORMATS {
+            let converted = Cpio::load_from_data(&write(&loaded, to)).unwrap();
+            assert_eq!(converted.entries, cpio.entries, "{from:?} -> {to:?}");
+        }
+    }
+}
+
+#[test]
+fn crc_checksum() {
+    let cpio = sample();
+    let corrupt = |format| {
+        let mut data = write(&cpio, format);
+        let pos = data.windows(5).position(|w| w == b"abcde").unwrap();
+        data[pos] ^= 1;
+        Cpio::load_from_data(&data)
+    };
+    assert!(corrupt(CpioFormat::Crc).is_err());
+    assert!(corrupt(CpioFormat::Newc).is_ok());
+    assert!(corrupt(CpioFormat::Odc).is_ok());
+}
+
+#[test]
+fn new_entries() {
+    let mut cpio = sample();
+    cpio.mkdir(0o700, "/data/");
+    cpio.ln("/data", "sdcard");
+    assert!(cpio.exists("data"));
+    assert!(cpio.exists("/sdcard"));
+
+    for format in FORMATS {
+        let loaded = Cpio::load_from_data(&write(&cpio, format)).unwrap();
+        let data = &loaded.entries["data"];
+        let sdcard = &loaded.entries["sdcard"];
+        assert_eq!(data.mode, S_IFDIR | 0o700);
+        assert_eq!(sdcard.mode, S_IFLNK);
+        assert_eq!(sdcard.data, b"/data");
+        // Inodes of new entries do not collide with the ones that were loaded
+        let mut inodes: Vec<_> = loaded.entries.values().map(|e| e.ino).collect();
+        inodes.sort();
+        inodes.dedup();
+        assert_eq!(inodes.len(), loaded.entries.len());
+        assert!(inodes.iter().all(|ino| *ino != 0));
+    }
+}
+
+#[test]
+fn edit_entries() {
+    let mut cpio = sample();
+    cpio.mv("init", "init.real").unwrap();
+    assert!(cpio.mv("init", "init.real").is_err());
+    assert_eq!(cpio.entries["init.real"].ino, 11);
+    cpio.rm("system", true);
+    assert!(!cpio.exists("system"));
+    assert!(!cpio.exists("system/bin/odd"));
+
+    let loaded = Cpio::load_from_data(&write(&cpio, CpioFormat::Newc)).unwrap();
+    let names: Vec<_> = loaded.entries.keys().map(String::as_str).collect();
+    assert_eq!(names, ["bin", "init.real"]);
+}
+
+#[test]
+fn concatenated_archives() {
+    let first = sample();
+    let mut second = Cpio::new();
+    second.mkdir(0o755, "overlay");
+    let mut data = write(&first, CpioFormat::Newc);
+    // Archives are often padded to a block size
+    data.resize(data.len() + 512, 0);
+    data.extend(write(&second, CpioFormat::Odc));
+
+    let loaded = Cpio::load_from_data(&data).unwrap();
+    assert_eq!(loaded.format, CpioFormat::Newc);
+    assert_eq!(loaded.entries.len(), first.entries.len() + 1);
+    assert!(loaded.exists("overlay"));
+}
+
+#[test]
+fn reproducible() {
+    let cpio = sample();
+    let mut touched = sample();
+    for entry in touched.entries.values_mut() {
+        entry.mtime += 100;
+        entry.ino += 100;
+    }
+    for format in FORMATS {
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        cpio.write_reproducible(&mut a, format, 0).unwrap();
+        touched.write_reproducible(&mut b, format, 0).unwrap();
+        assert_eq!(a, b, "{format:?}");
+    }
+}
+
+#[test]
+fn odc_limits() {
+    let mut cpio = sample();
+    cpio.entries.get_mut("init").unwrap().uid = 1 << 18;
+    assert!(cpio.write_to(Vec::new(), CpioFormat::Odc).is_err());
+    assert!(cpio.write_to(Vec::new(), CpioFormat::Newc).is_ok());
+}
+
+#[test]
+fn truncated() {
+    let data = write(&sample(), CpioFormat::Newc);
+    for len in [3, 100, 200, data.len() - 200] {
+        assert!(Cpio::load_from_data(&data[..len]).is_err(), "{len}");
+    }
+}
@@ -0,0 +1,333 @@
+#!/usr/bin/env python3
+# Writes the puffin patch fixtures used by tests/puffpatch.rs.
+#
+# Each case is a source and a destination file containing deflate streams created by
+# zlib, and a PUF1 patch from the source to the destination. The deflates are puffed
+# with the encoding of AOSP's puffin, and the puffs are diffed into a BSDF2 patch with
+# bzip2 compressed streams.
+
+import bz2
+import os
+import struct
+import zlib
+
+# Deflate tables
+LENGTH_BASES = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+                35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258]
+LENGTH_EXTRA = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+                3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0]
+DIST_BASES = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385,
+              513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577]
+DIST_EXTRA = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7,
+              8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13]
+CODE_ORDER = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15]
+
+
+# Reads bits, and optionally copies them to out starting at bit out_pos
+class Bits:
+    def __init__(self, data, pos, out_pos=None):
+        self.data = data
+        self.pos = pos
+        self.out = 0
+        self.out_pos = out_pos
+
+    def read(self, n, copy=True):
+        val = 0
+        for i in range(n):
+            val |= (self.data[self.pos // 8] >> (self.pos % 8) & 1) << i
+            self.pos += 1
+        if copy and self.out_pos is not None:
+            self.out |= val << self.out_pos
+            self.out_pos += n
+        return val
+
+    # The padding of stored blocks depends on where the copy is placed
+    def padding(self):
+        val = self.read((8 - self.pos % 8) % 8, False)
+        if self.out_pos is not None:
+            self.out_pos += (8 - self.out_pos % 8) % 8
+        return val
+
+
+def huffman(lens):
+    # Canonical codes, keyed by (length, code) as read MSB first
+    table = {}
+    code = 0
+    for length in range(1, 16):
+        for sym, l in enumerate(lens):
+            if l == length:
+                table[(length, code)] = sym
+                code += 1
+        code <<= 1
+    return table
+
+
+def decode(bits, table):
+    code = 0
+    for length in range(1, 16):
+        code = code << 1 | bits.read(1)
+        if (length, code) in table:
+            return table[(length, code)]
+    raise ValueError('invalid code')
+
+
+class Puff:
+    def __init__(self):
+        self.out = bytearray()
+        self.literals = bytearray()
+
+    def flush(self):
+        n = len(self.literals)
+        if n == 0:
+            return
+        if n <= 127:
+            self.out.append(n - 1)
+        else:
+            self.out += b'\x7f' + struct.pack('>H', n - 128)
+        self.out += self.literals
+        self.literals = bytearray()
+
+    def literal(self, b):
+        self.literals.append(b)
+        if len(self.literals) == (1 << 16) + 127:
+            self.flush()
+
+    def length_distance(self, length, dist):
+        self.flush()
+        if length < 130:
+            self.out.append(0x80 | (length - 3))
+        else:
+            self.out += bytes([0xff, length - 130])
+        self.out += struct.pack('>H', dist - 1)
+
+    def end_of_block(self):
+        self.flush()
+        self.out += b'\xff\x81'
+
+    def metadata(self, meta):
+        self.flush()
+        self.out += struct.pack('>H', len(meta) - 1) + bytes(meta)
+
+
+def puff_blocks(bits):
+    puff = Puff()
+    final = 0
+    while not final:
+        final = bits.read(1)
+        btype = bits.read(2)
+        header = final << 7 | btype << 5
+        if btype == 0:
+            padding = bits.padding()
+            length = bits.read(16)
+            bits.read(16)
+            puff.metadata([header | padding])
+            for _ in range(length):
+                puff.literal(bits.read(8))
+            puff.end_of_block()
+            continue
+        if btype == 1:
+            puff.metadata([header])
+            lit = huffman([8] * 144 + [9] * 112 + [7] * 24 + [8] * 8)
+            dist = huffman([5] * 30)
+        else:
+            hlit, hdist, hclen = bits.read(5), bits.read(5), bits.read(4)
+            meta = [header, hlit, hdist, hclen]
+            code_lens = [0] * 19
+            for i in range(hclen + 4):
+                code_lens[CODE_ORDER[i]] = bits.read(3)
+            for i in range(0, hclen + 4, 2):
+                low = code_lens[CODE_ORDER[i + 1]] if i + 1 < hclen + 4 else 0
+                meta.append(code_lens[CODE_ORDER[i]] << 4 | low)
+            code_table = huffman(code_lens)
+            lens = []
+            while len(lens) < hlit + 257 + hdist + 1:
+                sym = decode(bits, code_table)
+                if sym < 16:
+                    meta.append(sym)
+                    lens.append(sym)
+                elif sym == 16:
+                    extra = bits.read(2)
+                    meta.append(16 + extra)
+                    lens += [lens[-1]] * (3 + extra)
+                elif sym == 17:
+                    extra = bits.read(3)
+                    meta.append(20 + extra)
+                    lens += [0] * (3 + extra)
+                else:
+                    extra = bits.read(7)
+                    meta.append(28 + extra)
+                    lens += [0] * (11 + extra)
+            puff.metadata(meta)
+            lit = huffman(lens[:hlit + 257])
+            dist = huffman(lens[hlit + 257:])
+        while True:
+            sym = decode(bits, lit)
+            if sym < 256:
+                puff.literal(sym)
+            elif sym == 256:
+                break
+            else:
+                i = sym - 257
+                length = LENGTH_BASES[i] + bits.read(LENGTH_EXTRA[i])
+                i = decode(bits, dist)
+                puff.length_distance(length, DIST_BASES[i] + bits.read(DIST_EXTRA[i]))
+        puff.end_of_block()
+    puff.flush()
+    return bytes(puff.out)
+
+
+# Puffs the deflate stream starting at bit start, returns the puff and the end bit
+def puff_deflate(data, start):
+    bits = Bits(data, start)
+    return puff_blocks(bits), bits.pos
+
+
+# The raw bytes between a deflate ending at bit end and the next one starting at bit start
+def raw(data, end, start):
+    if end == start:
+        return b''
+    out = bytearray(data[end // 8:(start + 7) // 8])
+    if start % 8:
+        out[-1] &= (1 << start % 8) - 1
+    out[0] >>= end % 8
+    return bytes(out)
+
+
+# Returns the puffed file and its stream info: deflate bit extents, puff extents, length
+def puff_file(data, starts):
+    puff = bytearray()
+    deflates = []
+    puffs = []
+    end = 0
+    for start in starts:
+        puff += raw(data, end, start)
+        stream, stop = puff_deflate(data, start)
+        deflates.append((start, stop - start))
+        puffs.append((len(puff), len(stream)))
+        puff += stream
+        end = stop
+    puff += raw(data, end, len(data) * 8)
+    return bytes(puff), (deflates, puffs, len(puff))
+
+
+def varint(v):
+    out = bytearray()
+    while True:
+        out.append(v & 0x7f | (0x80 if v > 0x7f else 0))
+        v >>= 7
+        if not v:
+            return bytes(out)
+
+
+def field(num, val):
+    if isinstance(val, int):
+        return varint(num << 3) + varint(val) if val else b''
+    return varint(num << 3 | 2) + varint(len(val)) + val
+
+
+def stream_info(info):
+    deflates, puffs, length = info
+    out = b''
+    for ext in deflates:
+        out += field(1, field(1, ext[0]) + field(2, ext[1]))
+    for ext in puffs:
+        out += field(2, field(1, ext[0]) + field(2, ext[1]))
+    return out + field(3, length)
+
+
+def offt(v):
+    return struct.pack('<Q', v if v >= 0 else -v | 1 << 63)
+
+
+# BSDF2 patch with one control tuple per chunk: diff against the same offset, and the
+# data past the end of the old file as extra data
+def bsdiff(old, new, chunk=1024):
+    ctrl = diff = extra = b''
+    for pos in range(0, len(new), chunk):
+        block = new[pos:pos + chunk]
+        same = max(0, min(len(block), len(old) - pos))
+        diff += bytes((b - o) & 0xff for b, o in zip(block[:same], old[pos:]))
+        extra += block[same:]
+        ctrl += offt(same) + offt(len(block) - same) + offt(0)
+    ctrl, diff, extra = (bz2.compress(s) for s in (ctrl, diff, extra))
+    return (b'BSDF2\x01\x01\x01' + offt(len(ctrl)) + offt(len(diff)) + offt(len(new))
+            + ctrl + diff + extra)
+
+
+def puffdiff(src, src_starts, dst, dst_starts):
+    src_puff, src_info = puff_file(src, src_starts)
+    dst_puff, dst_info = puff_file(dst, dst_starts)
+    header = field(1, 1) + field(2, stream_info(src_info)) + field(3, stream_info(dst_info))
+    return b'PUF1' + struct.pack('>I', len(header)) + header + bsdiff(src_puff, dst_puff)
+
+
+# A sync flush in the middle ends the first block with an empty stored block
+def deflate(data, level=6, strategy=zlib.Z_DEFAULT_STRATEGY):
+    c = zlib.compressobj(level, zlib.DEFLATED, -15, 9, strategy)
+    half = len(data) // 2
+    return c.compress(data[:half]) + c.flush(zlib.Z_SYNC_FLUSH) + c.compress(data[half:]) + c.flush()
+
+
+# Places each deflate after its raw prefix, shifted by the given number of bits. Deflates
+# are written again at their new position and packed with their exact bit length, so
+# that the next part starts where they end. Returns the file and the start bit of each
+# deflate.
+def build(parts):
+    out = 0
+    pos = 0
+    starts = []
+    for prefix, stream, shift in parts:
+        for b in prefix:
+            out |= b << pos
+            pos += 8
+        pos += shift
+        starts.append(pos)
+        bits = Bits(stream, 0, pos)
+        puff_blocks(bits)
+        out |= bits.out
+        pos = bits.out_pos
+    out |= 0xa5 << pos
+    pos += 8
+    return out.to_bytes((pos + 7) // 8, 'little'), starts
+
+
+def text(seed, size):
+    words = [b'boot', b'vendor', b'ramdisk', b'kernel', b'dtb', b'init', b'system',
+             b'magisk', b'payload', b'partition', b'\n']
+    out = bytearray()
+    x = seed
+    while len(out) < size:
+        x = (x * 1103515245 + 12345) & 0x7fffffff
+        out += words[(x >> 16) % len(words)] + b' '
+    return bytes(out[:size])
+
+
+def main():
+    out_dir = os.path.join(os.path.dirname(os.path.abspath(__file__)), 'puffdiff')
+    os.makedirs(out_dir, exist_ok=True)
+
+    src_text = text(1, 3000)
+    dst_text = src_text[:1000] + text(2, 500) + src_text[1200:]
+    cases = {
+        'stored': lambda t: [(b'GZ', deflate(t, 0), 0)],
+        'fixed': lambda t: [(b'GZ', deflate(t, 9, zlib.Z_FIXED), 0)],
+        'dynamic': lambda t: [(b'GZ', deflate(t), 0)],
+        # Deflates not starting on a byte boundary, sharing bytes with raw data, and one
+        # deflate right after another
+        'unaligned': lambda t: [
+            (b'\x12\x34', deflate(t[:800], 9, zlib.Z_FIXED), 3),
+            (b'', deflate(t[800:]), 0),
+            (b'\x56', deflate(t[:300], 0), 5),
+        ],
+    }
+    for name, layout in cases.items():
+        src, src_starts = build(layout(src_text))
+        dst, dst_starts = build(layout(dst_text))
+        files = {'src': src, 'dst': dst, 'patch': puffdiff(src, src_starts, dst, dst_starts)}
+        for ext, data in files.items():
+            with open(os.path.join(out_dir, f'{name}.{ext}'), 'wb') as f:
+                f.write(data)
+
+
+if __name__ == '__main__':
+    main()
@@ -0,0 +1,445 @@
+// Fixtures built with PayloadSpec for the paths that the fuzz targets are meant to reach.
+// Run with `cargo test` in the fuzz directory.
+
+use std::ffi::CString;
+use std::fs;
+use std::io::Cursor;
+
+use protobuf::{Message, MessageField};
+
+use magiskboot::puffin::{BitExtent, PatchHeader, StreamInfo};
+use magiskboot::{extract_boot_from_payload, PayloadError, PayloadExtractor};
+use magiskboot_fuzz::{OpData, OpType, OperationSpec, PartitionSpec, PayloadSpec};
+
+const BLOCK_SIZE: usize = 4096;
+
+fn full_payload(partitions: Vec<PartitionSpec>) -> PayloadSpec {
+    PayloadSpec {
+        version: None,
+        manifest_len: None,
+        sig_len: 0,
+        block_size: Some(BLOCK_SIZE as u32),
+        minor_version: 0,
+        partitions,
+        source: None,
+        verify: true,
+    }
+}
+
+fn partition(size: usize, operations: Vec<OperationSpec>) -> PartitionSpec {
+    PartitionSpec {
+        name: 0,
+        size: size as u32,
+        operations,
+    }
+}
+
+fn replace(data: OpData, dst_extents: Vec<(u16, u16)>) -> OperationSpec {
+    OperationSpec {
+        op_type: OpType::Known(0),
+        data,
+        data_hash: true,
+        src_extents: Vec::new(),
+        dst_extents,
+        src_length: None,
+    }
+}
+
+fn block(b: u8) -> Vec<u8> {
+    vec![b; BLOCK_SIZE]
+}
+
+fn extract(extractor: &mut PayloadExtractor) -> Result<Vec<u8>, PayloadError> {
+    let mut out = Cursor::new(Vec::new());
+    extractor.extract_partition("p0", &mut out, None::<Cursor<&[u8]>>, |_| {})?;
+    Ok(out.into_inner())
+}
+
+#[test]
+fn extract_replace() {
+    let mut spec = full_payload(vec![partition(
+        2 * BLOCK_SIZE,
+        vec![
+            replace(OpData::Raw(block(1)), vec![(0, 1)]),
+            replace(OpData::Xz(block(2)), vec![(1, 1)]),
+        ],
+    )]);
+    spec.partitions[0].operations[1].op_type = OpType::Known(8);
+    let payload = spec.build();
+
+    let expected = [block(1), block(2)].concat();
+    let mut extractor = PayloadExtractor::from_seekable(Cursor::new(&payload)).unwrap();
+    assert_eq!(extract(&mut extractor).unwrap(), expected);
+    let mut extractor = PayloadExtractor::from_reader(payload.as_slice()).unwrap();
+    assert_eq!(extract(&mut extractor).unwrap(), expected);
+}
+
+#[test]
+fn out_of_order_data() {
+    // The first operation reads data that comes after the one of the second operation,
+    // appended by a ZERO operation that does not write anything
+    let mut spec = full_payload(vec![partition(
+        2 * BLOCK_SIZE,
+        vec![
+            replace(
+                OpData::Huge {
+                    offset: BLOCK_SIZE as u64,
+                    length: BLOCK_SIZE as u64,
+                },
+                vec![(1, 1)],
+            ),
+            replace(OpData::Raw(block(1)), vec![(0, 1)]),
+            replace(OpData::Raw(block(2)), Vec::new()),
+        ],
+    )]);
+    spec.partitions[0].operations[2].op_type = OpType::Known(6);
+    let payload = spec.build();
+
+    let expected = [block(1), block(2)].concat();
+    let mut extractor = PayloadExtractor::from_seekable(Cursor::new(&payload)).unwrap();
+    assert_eq!(extract(&mut extractor).unwrap(), expected);
+    let mut extractor = PayloadExtractor::from_reader(payload.as_slice()).unwrap();
+    assert_eq!(extract(&mut extractor).unwrap(), expected);
+}
+
+#[test]
+fn major_version_1() {
+    let mut spec = full_payload(vec![
+        partition(
+            BLOCK_SIZE,
+            vec![replace(OpData::Raw(block(1)), vec![(0, 1)])],
+        ),
+        partition(
+            BLOCK_SIZE,
+            vec![replace(OpData::Raw(block(2)), vec![(0, 1)])],
+        ),
+    ]);
+    spec.version = Some(1);
+    let payload = spec.build();
+
+    let mut extractor = PayloadExtractor::from_reader(payload.as_slice()).unwrap();
+    assert_eq!(extractor.major_version(), 1);
+    assert_eq!(extractor.partition_names(), ["system", "boot"]);
+    for (name, expected) in [("system", block(1)), ("boot", block(2))] {
+        let mut out = Cursor::new(Vec::new());
+        extractor
+            .extract_partition(name, &mut out, None::<Cursor<&[u8]>>, |_| {})
+            .unwrap();
+        assert_eq!(out.into_inner(), expected);
+    }
+}
+
+#[test]
+fn huge_manifest_length() {
+    for len in [u64::MAX, 1 << 40, 64 * 1024 * 1024 + 1] {
+        let mut spec = full_payload(Vec::new());
+        spec.manifest_len = Some(len);
+        let payload = spec.build();
+        let err = PayloadExtractor::from_reader(payload.as_slice())
+            .err()
+            .unwrap();
+        assert!(matches!(err, PayloadError::Invalid(_)));
+    }
+}
+
+#[test]
+fn truncated_manifest() {
+    let mut spec = full_payload(vec![partition(BLOCK_SIZE, Vec::new())]);
+    spec.manifest_len = Some(1024 * 1024);
+    let payload = spec.build();
+    assert!(PayloadExtractor::from_reader(payload.as_slice()).is_err());
+}
+
+#[test]
+fn bad_header() {
+    let payload = full_payload(Vec::new()).build();
+    assert!(matches!(
+        PayloadExtractor::from_reader(&payload[1..]).err().unwrap(),
+        PayloadError::BadMagic
+    ));
+
+    let mut spec = full_payload(Vec::new());
+    spec.version = Some(3);
+    let payload = spec.build();
+    assert!(matches!(
+        PayloadExtractor::from_reader(payload.as_slice())
+            .err()
+            .unwrap(),
+        PayloadError::UnsupportedVersion(3)
+    ));
+
+    let mut spec = full_payload(Vec::new());
+    spec.sig_len = u32::MAX;
+    let payload = spec.build();
+    assert!(matches!(
+        PayloadExtractor::from_reader(payload.as_slice())
+            .err()
+            .unwrap(),
+        PayloadError::Invalid(_)
+    ));
+
+    for block_size in [0, 1000, 1 << 31] {
+        let mut spec = full_payload(Vec::new());
+        spec.block_size = Some(block_size);
+        let payload = spec.build();
+        assert!(matches!(
+            PayloadExtractor::from_reader(payload.as_slice())
+                .err()
+                .unwrap(),
+            PayloadError::Invalid(_)
+        ));
+    }
+}
+
+#[test]
+fn overlapping_extents() {
+    let spec = full_payload(vec![partition(
+        4 * BLOCK_SIZE,
+        vec![
+            replace(OpData::Raw(vec![0; 2 * BLOCK_SIZE]), vec![(0, 2)]),
+            replace(OpData::Raw(vec![0; 2 * BLOCK_SIZE]), vec![(1, 2)]),
+        ],
+    )]);
+    let payload = spec.build();
+    let err = PayloadExtractor::from_reader(payload.as_slice())
+        .err()
+        .unwrap();
+    assert!(matches!(err, PayloadError::Invalid(_)));
+}
+
+#[test]
+fn extent_out_of_partition() {
+    let spec = full_payload(vec![partition(
+        BLOCK_SIZE,
+        vec![replace(OpData::Raw(block(0)), vec![(1, 1)])],
+    )]);
+    let payload = spec.build();
+    let err = PayloadExtractor::from_reader(payload.as_slice())
+        .err()
+        .unwrap();
+    assert!(matches!(err, PayloadError::Invalid(_)));
+}
+
+#[test]
+fn huge_data_length() {
+    for (offset, length) in [(0, u64::MAX), (u64::MAX, 1), (0, 1 << 40)] {
+        let spec = full_payload(vec![partition(
+            BLOCK_SIZE,
+            vec![replace(OpData::Huge { offset, length }, vec![(0, 1)])],
+        )]);
+        let payload = spec.build();
+        let err = PayloadExtractor::from_reader(payload.as_slice())
+            .err()
+            .unwrap();
+        assert!(matches!(err, PayloadError::Invalid(_)));
+    }
+}
+
+#[test]
+fn unknown_operation() {
+    for op_type in [OpType::Unknown(-1), OpType::Unknown(1000)] {
+        let mut op = replace(OpData::Raw(block(0)), vec![(0, 1)]);
+        op.op_type = op_type;
+        let payload = full_payload(vec![partition(BLOCK_SIZE, vec![op])]).build();
+        let mut extractor = PayloadExtractor::from_seekable(Cursor::new(&payload)).unwrap();
+        let err = extract(&mut extractor).unwrap_err();
+        assert!(matches!(err, PayloadError::UnsupportedOperation(_)));
+    }
+}
+
+#[test]
+fn data_hash_mismatch() {
+    // The hash is computed before the data is appended by the ZERO operation
+    let mut spec = full_payload(vec![partition(
+        BLOCK_SIZE,
+        vec![
+            replace(
+                OpData::Range {
+                    offset: 0,
+                    length: BLOCK_SIZE as u16,
+                },
+                vec![(0, 1)],
+            ),
+            replace(OpData::Raw(block(1)), Vec::new()),
+        ],
+    )]);
+    spec.partitions[0].operations[1].op_type = OpType::Known(6);
+    let payload = spec.build();
+
+    let mut extractor = PayloadExtractor::from_seekable(Cursor::new(&payload)).unwrap();
+    let err = extract(&mut extractor).unwrap_err();
+    assert!(matches!(err, PayloadError::HashMismatch(_)));
+    let mut extractor = PayloadExtractor::from_seekable(Cursor::new(&payload)).unwrap();
+    extractor.set_verify(false);
+    assert_eq!(extract(&mut extractor).unwrap(), block(1));
+}
+
+#[test]
+fn shared_data() {
+    // Both operations write the data blob appended by the first one
+    let payload = full_payload(vec![partition(
+        2 * BLOCK_SIZE,
+        vec![
+            replace(OpData::Raw(block(1)), vec![(0, 1)]),
+            replace(
+                OpData::Range {
+                    offset: 0,
+                    length: BLOCK_SIZE as u16,
+                },
+                vec![(1, 1)],
+            ),
+        ],
+    )])
+    .build();
+
+    let expected = [block(1), block(1)].concat();
+    let mut extractor = PayloadExtractor::from_reader(payload.as_slice()).unwrap();
+    assert_eq!(extract(&mut extractor).unwrap(), expected);
+
+    // magiskboot extract reads the payload file with one or multiple threads
+    let dir = std::env::temp_dir();
+    let in_path = dir.join(format!("magiskboot-{}-shared.bin", std::process::id()));
+    let out_path = dir.join(format!("magiskboot-{}-shared.img", std::process::id()));
+    fs::write(&in_path, &payload).unwrap();
+    for threads in ["1", "2"] {
+        let args = [
+            in_path.to_str().unwrap(),
+            "p0",
+            out_path.to_str().unwrap(),
+            "-t",
+            threads,
+        ];
+        let args: Vec<_> = args.iter().map(|s| CString::new(*s).unwrap()).collect();
+        let argv: Vec<_> = args.iter().map(|s| s.as_ptr()).collect();
+        let ret = extract_boot_from_payload(argv.len() as i32, argv.as_ptr());
+        assert_eq!(ret, 0);
+        assert_eq!(fs::read(&out_path).unwrap(), expected);
+    }
+    fs::remove_file(&in_path).ok();
+    fs::remove_file(&out_path).ok();
+}
+
+fn stream_info(deflates: &[(u64, u64)], puffs: &[(u64, u64)], puff_length: u64) -> StreamInfo {
+    let extent = |&(offset, length): &(u64, u64)| {
+        let mut ext = BitExtent::new();
+        ext.offset = offset;
+        ext.length = length;
+        ext
+    };
+    let mut info = StreamInfo::new();
+    info.deflates = deflates.iter().map(extent).collect();
+    info.puffs = puffs.iter().map(extent).collect();
+    info.puff_length = puff_length;
+    info
+}
+
+// Puffin patch with an uncompressed BSDF2 patch that turns src_puff into dst_puff
+fn puffdiff(src: StreamInfo, dst: StreamInfo, src_puff: &[u8], dst_puff: &[u8]) -> Vec<u8> {
+    let len = src_puff.len().min(dst_puff.len());
+    let mut ctrl = Vec::new();
+    for v in [len, dst_puff.len() - len, 0] {
+        ctrl.extend((v as u64).to_le_bytes());
+    }
+    let diff: Vec<u8> = src_puff
+        .iter()
+        .zip(dst_puff)
+        .map(|(old, new)| new.wrapping_sub(*old))
+        .collect();
+
+    let mut header = PatchHeader::new();
+    header.version = 1;
+    header.src = MessageField::some(src);
+    header.dst = MessageField::some(dst);
+    let header = header.write_to_bytes().unwrap();
+
+    let mut patch = b"PUF1".to_vec();
+    patch.extend((header.len() as u32).to_be_bytes());
+    patch.extend(header);
+    patch.extend(b"BSDF2\0\0\0");
+    for v in [ctrl.len(), diff.len(), dst_puff.len()] {
+        patch.extend((v as u64).to_le_bytes());
+    }
+    patch.extend(ctrl);
+    patch.extend(diff);
+    patch.extend(&dst_puff[len..]);
+    patch
+}
+
+fn extract_puffdiff(patch: Vec<u8>, src: &[u8]) -> Result<Vec<u8>, PayloadError> {
+    let mut op = replace(OpData::Raw(patch), vec![(0, 1)]);
+    op.op_type = OpType::Known(9);
+    op.src_extents = vec![(0, 1)];
+    let payload = full_payload(vec![partition(BLOCK_SIZE, vec![op])]).build();
+
+    let mut extractor = PayloadExtractor::from_reader(payload.as_slice()).unwrap();
+    let mut out = Cursor::new(Vec::new());
+    extractor.extract_partition("p0", &mut out, Some(Cursor::new(src)), |_| {})?;
+    Ok(out.into_inner())
+}
+
+#[test]
+fn puffdiff_stored_to_fixed() {
+    // A stored block of "\x01" becomes a fixed Huffman block of "abc"
+    let src = [
+        &[0x01, 0x01, 0x00, 0xfe, 0xff, 0x01][..],
+        &[0; BLOCK_SIZE - 6],
+    ]
+    .concat();
+    let src_puff = [
+        &[0x00, 0x00, 0x80, 0x00, 0x01, 0xff, 0x81][..],
+        &[0; BLOCK_SIZE - 6],
+    ]
+    .concat();
+    let dst = [&[0x4b, 0x4c, 0x4a, 0x06, 0x00][..], &[0; BLOCK_SIZE - 5]].concat();
+    // The last byte of the deflate is shared with the raw data that follows it
+    let dst_puff = [
+        &[0x00, 0x00, 0xa0, 0x02, b'a', b'b', b'c', 0xff, 0x81][..],
+        &[0; BLOCK_SIZE - 4],
+    ]
+    .concat();
+
+    let patch = puffdiff(
+        stream_info(&[(0, 48)], &[(0, 7)], src_puff.len() as u64),
+        stream_info(&[(0, 34)], &[(0, 9)], dst_puff.len() as u64),
+        &src_puff,
+        &dst_puff,
+    );
+    assert_eq!(extract_puffdiff(patch, &src).unwrap(), dst);
+}
+
+#[test]
+fn puffdiff_unaligned_dynamic() {
+    // Dynamic Huffman block of 802 bits, puffed into 197 bytes
+    let deflate: &[u8] = &[
+        0x55, 0x8d, 0xd1, 0x09, 0xc3, 0x30, 0x0c, 0x44, 0x57, 0xb9, 0x01, 0x4a, 0x76, 0xc8, 0x00,
+        0x19, 0x42, 0xb5, 0x65, 0x47, 0x84, 0x48, 0x46, 0x92, 0x29, 0x74, 0xfa, 0x06, 0xfc, 0xd5,
+        0xaf, 0x3b, 0x1e, 0xc7, 0xbb, 0x83, 0xba, 0xc4, 0x05, 0x09, 0x10, 0x62, 0x4a, 0x32, 0xac,
+        0xc1, 0x06, 0x2b, 0xc2, 0xa6, 0x17, 0x7e, 0xa2, 0xe5, 0x87, 0x9c, 0xd1, 0xcc, 0x51, 0x66,
+        0xa4, 0xdd, 0xf2, 0x15, 0xed, 0xd8, 0xb5, 0xba, 0x49, 0xdd, 0x70, 0x2c, 0xc5, 0xdb, 0x2c,
+        0x31, 0x28, 0xcb, 0xc9, 0x81, 0x3c, 0x79, 0x01, 0xb9, 0xa9, 0xf3, 0xeb, 0x6f, 0x33, 0x75,
+        0x50, 0xb9, 0x9e, 0x43, 0xad, 0x70, 0x5e, 0x5d, 0x72, 0xfb, 0x01,
+    ];
+
+    // Starts at bit 11 and ends at bit 813, surrounded by raw bits
+    let mut src = vec![0xcd; BLOCK_SIZE];
+    src[1..103].fill(0);
+    src[0] = 0xab;
+    src[1] = 0b101;
+    for (i, b) in deflate.iter().enumerate() {
+        src[i + 1] |= b << 3;
+        src[i + 2] |= b >> 5;
+    }
+    src[101] |= 0xe0;
+
+    // Round trip through the puffed stream
+    let puff_length = 2 + 197 + (BLOCK_SIZE - 101) as u64;
+    let info = || stream_info(&[(11, 802)], &[(2, 197)], puff_length);
+    let puff = vec![0; puff_length as usize];
+    let patch = puffdiff(info(), info(), &puff, &puff);
+    assert_eq!(extract_puffdiff(patch, &src).unwrap(), src);
+
+    // Puff extents must match the deflates
+    let bad_info = stream_info(&[(11, 802)], &[(1, 197)], puff_length - 1);
+    let patch = puffdiff(bad_info, info(), &puff[1..], &puff);
+    assert!(extract_puffdiff(patch, &src).is_err());
+}
@@ -0,0 +1,65 @@
+// Puffin patches between files with deflate streams, written by tests/fixtures/puffdiff.py
+
+use std::fs;
+
+use magiskboot::puffpatch;
+
+const MAX_SIZE: u64 = 1024 * 1024;
+
+fn fixture(name: &str) -> Vec<u8> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/puffdiff");
+    fs::read(format!("{dir}/{name}")).unwrap()
+}
+
+fn check(case: &str) {
+    let src = fixture(&format!("{case}.src"));
+    let patch = fixture(&format!("{case}.patch"));
+    let dst = puffpatch(&src, &patch, MAX_SIZE).unwrap();
+    assert_eq!(dst, fixture(&format!("{case}.dst")));
+}
+
+#[test]
+fn stored_blocks() {
+    check("stored");
+}
+
+#[test]
+fn fixed_blocks() {
+    check("fixed");
+}
+
+#[test]
+fn dynamic_blocks() {
+    check("dynamic");
+}
+
+#[test]
+fn unaligned_streams() {
+    check("unaligned");
+}
+
+#[test]
+fn corrupted_source() {
+    // The length of the first stored block no longer matches its complement
+    let mut src = fixture("stored.src");
+    src[5] ^= 1;
+    let patch = fixture("stored.patch");
+    assert!(puffpatch(&src, &patch, MAX_SIZE).is_err());
+}
+
+#[test]
+fn corrupted_patch() {
+    let src = fixture("dynamic.src");
+    let patch = fixture("dynamic.patch");
+    assert!(puffpatch(&src, &patch[..patch.len() / 2], MAX_SIZE).is_err());
+    assert!(puffpatch(&src, &patch[1..], MAX_SIZE).is_err());
+}
+
+#[test]
+fn size_limit() {
+    // Puffs larger than the limit are rejected before they are allocated
+    let src = fixture("stored.src");
+    let patch = fixture("stored.patch");
+    assert!(puffpatch(&src, &patch, 1024).is_err());
+    assert!(puffpatch(&src, &patch, MAX_SIZE).is_ok());
+}
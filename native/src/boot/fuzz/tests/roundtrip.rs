@@ -0,0 +1,299 @@
+// Round trips through the magiskboot commands that write images, checked with the
+// code that reads them back. Run with `cargo test` in the fuzz directory.
+
+use std::ffi::{c_char, CString};
+use std::fs;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+use magiskboot::ffi::{AvbFooterInfo, BootBlock, BootHeader, VendorRamdiskEntry};
+use magiskboot::{
+    create_payload, dump_boot_header, load_boot_header, update_avb_footer, vbmeta_commands,
+    PayloadExtractor,
+};
+
+const AVB_KEY: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../../../../tools/keys/verity.pk8"
+);
+
+// A file in the temporary directory that is removed when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(name: &str) -> TempFile {
+        TempFile(std::env::temp_dir().join(format!("magiskboot-{}-{}", std::process::id(), name)))
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+fn run(cmd: fn(i32, *const *const c_char) -> bool, args: &[&str]) -> bool {
+    let args: Vec<_> = args.iter().map(|s| CString::new(*s).unwrap()).collect();
+    let argv: Vec<_> = args.iter().map(|s| s.as_ptr()).collect();
+    cmd(argv.len() as i32, argv.as_ptr())
+}
+
+// Not compressible, so that REPLACE operations are generated along with REPLACE_XZ
+fn pseudo_random(len: usize, seed: u32) -> Vec<u8> {
+    let mut x = seed | 1;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect()
+}
+
+fn extract_all(payload: &str, names: &[&str]) -> Vec<Vec<u8>> {
+    let mut extractor = PayloadExtractor::from_seekable(File::open(payload).unwrap()).unwrap();
+    assert_eq!(extractor.partition_names(), names);
+    names
+        .iter()
+        .map(|name| {
+            let mut out = Cursor::new(Vec::new());
+            extractor
+                .extract_partition(name, &mut out, None::<File>, |_| {})
+                .unwrap();
+            out.into_inner()
+        })
+        .collect()
+}
+
+#[test]
+fn mkpayload_extract() {
+    // Multiple chunks, a zero chunk, compressible data and a partial last block
+    let boot = [
+        pseudo_random(2 * 1024 * 1024, 1),
+        vec![0; 2 * 1024 * 1024],
+        vec![b'a'; 5000],
+    ]
+    .concat();
+    let vendor_boot = pseudo_random(4096, 2);
+    let images = [("boot", &boot), ("vendor_boot", &vendor_boot)];
+
+    let mut args = Vec::new();
+    let mut files = Vec::new();
+    for (name, data) in images {
+        let file = TempFile::new(&format!("{name}.img"));
+        fs::write(&file.0, data).unwrap();
+        args.push(format!("{name}={}", file.path()));
+        files.push(file);
+    }
+    let payload = TempFile::new("payload.bin");
+
+    for compress in [true, false] {
+        let mut cmd = vec![payload.path()];
+        if !compress {
+            cmd.insert(0, "-r");
+        }
+        cmd.extend(args.iter().map(String::as_str));
+        assert!(run(create_payload, &cmd));
+
+        let extracted = extract_all(payload.path(), &["boot", "vendor_boot"]);
+        assert_eq!(extracted, [boot.clone(), vendor_boot.clone()]);
+
+        // A stream has to provide the same data
+        let data = fs::read(&payload.0).unwrap();
+        let mut extractor = PayloadExtractor::from_reader(data.as_slice()).unwrap();
+        let mut out = Cursor::new(Vec::new());
+        extractor
+            .extract_partition("boot", &mut out, None::<File>, |_| {})
+            .unwrap();
+        assert_eq!(out.into_inner(), boot);
+    }
+}
+
+const IMAGE_SIZE: usize = 8192;
+const PARTITION_SIZE: usize = 16384;
+const SALT: &[u8] = b"salt";
+
+fn image_digest(image: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update(SALT)
+        .chain_update(image)
+        .finalize()
+        .to_vec()
+}
+
+fn hash_descriptor(name: &str, image_size: u64, digest: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend(image_size.to_be_bytes());
+    let mut algorithm = [0u8; 32];
+    algorithm[..6].copy_from_slice(b"sha256");
+    body.extend(algorithm);
+    for len in [name.len(), SALT.len(), 32] {
+        body.extend((len as u32).to_be_bytes());
+    }
+    // Flags and reserved bytes
+    body.extend([0; 64]);
+    body.extend(name.as_bytes());
+    body.extend(SALT);
+    body.extend(digest);
+    body.resize(body.len().next_multiple_of(8), 0);
+
+    let mut desc = Vec::new();
+    desc.extend(2u64.to_be_bytes());
+    desc.extend((body.len() as u64).to_be_bytes());
+    desc.extend(body);
+    desc
+}
+
+// An unsigned vbmeta image with only descriptors in the auxiliary data block
+fn unsigned_vbmeta(descriptors: &[u8]) -> Vec<u8> {
+    let aux_size = descriptors.len().next_multiple_of(64) as u64;
+    let mut hdr = vec![0u8; 256];
+    hdr[..4].copy_from_slice(b"AVB0");
+    hdr[4..8].copy_from_slice(&1u32.to_be_bytes());
+    hdr[20..28].copy_from_slice(&aux_size.to_be_bytes());
+    hdr[104..112].copy_from_slice(&(descriptors.len() as u64).to_be_bytes());
+    let mut vbmeta = [hdr, descriptors.to_vec()].concat();
+    vbmeta.resize(256 + aux_size as usize, 0);
+    vbmeta
+}
+
+// The image, followed by the vbmeta image and the footer at the end of the partition
+fn avb_image(image: &[u8], vbmeta: &[u8]) -> Vec<u8> {
+    let mut data = [image, vbmeta].concat();
+    data.resize(PARTITION_SIZE - 64, 0);
+    data.extend(b"AVBf");
+    data.extend(1u32.to_be_bytes());
+    data.extend(0u32.to_be_bytes());
+    data.extend((image.len() as u64).to_be_bytes());
+    data.extend((image.len() as u64).to_be_bytes());
+    data.extend((vbmeta.len() as u64).to_be_bytes());
+    data.resize(PARTITION_SIZE, 0);
+    data
+}
+
+#[test]
+fn avb_resign() {
+    let orig_image = pseudo_random(IMAGE_SIZE, 3);
+    let image = pseudo_random(IMAGE_SIZE / 2, 4);
+    let dtbo = hash_descriptor("dtbo", 4096, &[1; 32]);
+    let descriptors = [
+        hash_descriptor("boot", IMAGE_SIZE as u64, &image_digest(&orig_image)),
+        dtbo.clone(),
+    ]
+    .concat();
+    let vbmeta = unsigned_vbmeta(&descriptors);
+    let orig = TempFile::new("orig.img");
+    fs::write(&orig.0, avb_image(&orig_image, &vbmeta)).unwrap();
+    let file = TempFile::new("avb.img");
+    fs::write(&file.0, avb_image(&image, &vbmeta)).unwrap();
+
+    assert!(!run(vbmeta_commands, &[file.path(), "verify"]));
+    assert!(update_avb_footer(orig.path(), file.path(), AVB_KEY));
+    assert!(run(
+        vbmeta_commands,
+        &[file.path(), "verify", "-k", AVB_KEY]
+    ));
+
+    // Only the descriptor of the image is updated
+    let data = fs::read(&file.0).unwrap();
+    let expected = hash_descriptor("boot", image.len() as u64, &image_digest(&image));
+    assert!(data.windows(expected.len()).any(|w| w == expected));
+    assert!(data.windows(dtbo.len()).any(|w| w == dtbo));
+
+    // Flags can be patched, and the image is signed again
+    assert!(run(
+        vbmeta_commands,
+        &[file.path(), "patch", "-k", AVB_KEY, "3"]
+    ));
+    assert!(run(
+        vbmeta_commands,
+        &[file.path(), "verify", "-k", AVB_KEY]
+    ));
+}
+
+fn vendor_boot_header() -> BootHeader {
+    let ramdisk = |name: &str, offset: u64| VendorRamdiskEntry {
+        name: name.to_owned(),
+        ramdisk_type: 1,
+        offset,
+        size: 4096,
+        format: "lz4_legacy".to_owned(),
+        board_id: vec![0; 16],
+    };
+    BootHeader {
+        format: "vendor_boot".to_owned(),
+        flags: Vec::new(),
+        header_version: 4,
+        header_size: 2128,
+        page_size: 4096,
+        image_size: 65536,
+        name: String::new(),
+        cmdline: "console=ttyS0".to_owned(),
+        id: String::new(),
+        os_version: 0,
+        blocks: vec![BootBlock {
+            name: "ramdisk".to_owned(),
+            offset: 4096,
+            size: 8192,
+            format: "lz4_legacy".to_owned(),
+        }],
+        vendor_ramdisks: vec![ramdisk("", 0), ramdisk("dlkm", 4096)],
+        has_avb: false,
+        avb: AvbFooterInfo {
+            version_major: 0,
+            version_minor: 0,
+            original_image_size: 0,
+            vbmeta_offset: 0,
+            vbmeta_size: 0,
+            algorithm_type: 0,
+            flags: 0,
+            rollback_index: 0,
+            rollback_index_location: 0,
+            release_string: String::new(),
+        },
+    }
+}
+
+#[test]
+fn header_json() {
+    let file = TempFile::new("header.json");
+    assert!(dump_boot_header(&vendor_boot_header(), file.path()));
+    let json = fs::read_to_string(&file.0).unwrap();
+
+    // Unchanged and applied properties load
+    let mut hdr = vendor_boot_header();
+    assert!(load_boot_header(&mut hdr, file.path()));
+    assert_eq!(hdr.cmdline, "console=ttyS0");
+    let edited = json
+        .replace("console=ttyS0", "console=ttyMSM0")
+        .replace("\"dlkm\"", "\"vendor_dlkm\"");
+    fs::write(&file.0, edited).unwrap();
+    let mut hdr = vendor_boot_header();
+    assert!(load_boot_header(&mut hdr, file.path()));
+    assert_eq!(hdr.cmdline, "console=ttyMSM0");
+    assert_eq!(hdr.vendor_ramdisks[1].name, "vendor_dlkm");
+    assert_eq!(hdr.vendor_ramdisks[1].ramdisk_type, 1);
+
+    // Properties describing the original image cannot be changed
+    for (from, to) in [
+        ("\"page_size\": 4096", "\"page_size\": 2048"),
+        ("\"header_version\": 4", "\"header_version\": 3"),
+        ("\"size\": 8192", "\"size\": 4096"),
+        ("\"format\": \"lz4_legacy\"", "\"format\": \"gzip\""),
+        ("\"avb\": null", "\"avb\": {}"),
+        ("\"cmdline\"", "\"cmd_line\""),
+    ] {
+        assert!(json.contains(from), "{from}");
+        fs::write(&file.0, json.replacen(from, to, 1)).unwrap();
+        let mut hdr = vendor_boot_header();
+        assert!(!load_boot_header(&mut hdr, file.path()), "{to}");
+    }
+}
@@ -0,0 +1,334 @@
+use std::fs;
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use base::ResultExt;
+
+use crate::ffi::{BootHeader, VendorRamdiskEntry};
+
+// JSON description of a boot image, written by `unpack -h` and read back by `repack`.
+// Only name, cmdline, os_version, os_patch_level and the vendor ramdisk table are
+// used when repacking, everything else is recomputed from the actual components.
+// Those other properties have to keep the values of the original image, so that
+// changes to them are reported instead of being dropped.
+
+const VENDOR_RAMDISK_TYPES: [&str; 4] = ["none", "platform", "recovery", "dlkm"];
+
+const APPLIED_PROPERTIES: [&str; 5] = [
+    "name",
+    "cmdline",
+    "os_version",
+    "os_patch_level",
+    "vendor_ramdisk_table",
+];
+
+// The offset, size and format of vendor ramdisks are recomputed from their files
+const APPLIED_RAMDISK_PROPERTIES: [&str; 3] = ["name", "type", "board_id"];
+
+#[derive(Serialize)]
+struct HeaderJson<'a> {
+    format: &'a str,
+    flags: &'a [String],
+    header_version: u32,
+    header_size: u32,
+    page_size: u32,
+    image_size: u64,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    name: &'a str,
+    cmdline: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    os_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    os_patch_level: Option<String>,
+    components: Vec<ComponentJson<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    vendor_ramdisk_table: Vec<VendorRamdiskJson<'a>>,
+    avb: Option<AvbJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct ComponentJson<'a> {
+    name: &'a str,
+    offset: u64,
+    size: u64,
+    format: &'a str,
+}
+
+#[derive(Serialize)]
+struct VendorRamdiskJson<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    ramdisk_type: String,
+    offset: u64,
+    size: u64,
+    format: &'a str,
+    board_id: &'a [u32],
+}
+
+#[derive(Serialize)]
+struct AvbJson<'a> {
+    version: String,
+    original_image_size: u64,
+    vbmeta_offset: u64,
+    vbmeta_size: u64,
+    algorithm_type: u32,
+    flags: u32,
+    rollback_index: u64,
+    rollback_index_location: u32,
+    release_string: &'a str,
+}
+
+#[derive(Deserialize)]
+struct HeaderConfig {
+    name: Option<String>,
+    cmdline: Option<String>,
+    os_version: Option<String>,
+    os_patch_level: Option<String>,
+    vendor_ramdisk_table: Option<Vec<VendorRamdiskConfig>>,
+}
+
+#[derive(Deserialize)]
+struct VendorRamdiskConfig {
+    name: String,
+    #[serde(rename = "type", default)]
+    ramdisk_type: Option<serde_json::Value>,
+    board_id: Option<Vec<u32>>,
+}
+
+fn vendor_ramdisk_type_name(ty: u32) -> String {
+    match VENDOR_RAMDISK_TYPES.get(ty as usize) {
+        Some(name) => name.to_string(),
+        None => ty.to_string(),
+    }
+}
+
+fn parse_vendor_ramdisk_type(value: &serde_json::Value) -> anyhow::Result<u32> {
+    if let Some(ty) = value.as_u64() {
+        return u32::try_from(ty).map_err(|_| anyhow!("invalid vendor ramdisk type {ty}"));
+    }
+    let name = value
+        .as_str()
+        .ok_or_else(|| anyhow!("invalid vendor ramdisk type {value}"))?;
+    VENDOR_RAMDISK_TYPES
+        .iter()
+        .position(|t| *t == name)
+        .map(|ty| ty as u32)
+        .ok_or_else(|| anyhow!("unknown vendor ramdisk type '{name}'"))
+}
+
+// For version "A.B.C" and patch level "Y-M":
+//   os_version = A[31:25] B[24:18] C[17:11] (Y-2000)[10:4] M[3:0]
+
+fn format_os_version(os_version: u32) -> String {
+    let version = os_version >> 11;
+    format!(
+        "{}.{}.{}",
+        (version >> 14) & 0x7f,
+        (version >> 7) & 0x7f,
+        version & 0x7f
+    )
+}
+
+fn format_os_patch_level(os_version: u32) -> String {
+    let patch_level = os_version & 0x7ff;
+    format!("{}-{:02}", (patch_level >> 4) + 2000, patch_level & 0xf)
+}
+
+fn parse_numbers<const N: usize>(s: &str, sep: char) -> Option<[u32; N]> {
+    let mut out = [0u32; N];
+    let mut parts = s.split(sep);
+    for v in out.iter_mut() {
+        *v = parts.next()?.trim().parse().ok()?;
+    }
+    parts.next().is_none().then_some(out)
+}
+
+fn parse_os_version(os_version: u32, s: &str) -> anyhow::Result<u32> {
+    let [a, b, c] = parse_numbers(s, '.')
+        .filter(|v| v.iter().all(|n| *n < 128))
+        .ok_or_else(|| anyhow!("invalid os_version '{s}'"))?;
+    Ok((((a << 14) | (b << 7) | c) << 11) | (os_version & 0x7ff))
+}
+
+fn parse_os_patch_level(os_version: u32, s: &str) -> anyhow::Result<u32> {
+    let [y, m] = parse_numbers(s, '-')
+        .filter(|[y, m]| (2000..2128).contains(y) && (1..=12).contains(m))
+        .ok_or_else(|| anyhow!("invalid os_patch_level '{s}'"))?;
+    Ok((os_version & !0x7ff) | ((y - 2000) << 4) | m)
+}
+
+fn header_json(hdr: &BootHeader) -> HeaderJson<'_> {
+    let components = hdr
+        .blocks
+        .iter()
+        .map(|b| ComponentJson {
+            name: &b.name,
+            offset: b.offset,
+            size: b.size,
+            format: &b.format,
+        })
+        .collect();
+    let vendor_ramdisk_table = hdr
+        .vendor_ramdisks
+        .iter()
+        .map(|r| VendorRamdiskJson {
+            name: &r.name,
+            ramdisk_type: vendor_ramdisk_type_name(r.ramdisk_type),
+            offset: r.offset,
+            size: r.size,
+            format: &r.format,
+            board_id: &r.board_id,
+        })
+        .collect();
+    let avb = hdr.has_avb.then(|| AvbJson {
+        version: format!("{}.{}", hdr.avb.version_major, hdr.avb.version_minor),
+        original_image_size: hdr.avb.original_image_size,
+        vbmeta_offset: hdr.avb.vbmeta_offset,
+        vbmeta_size: hdr.avb.vbmeta_size,
+        algorithm_type: hdr.avb.algorithm_type,
+        flags: hdr.avb.flags,
+        rollback_index: hdr.avb.rollback_index,
+        rollback_index_location: hdr.avb.rollback_index_location,
+        release_string: &hdr.avb.release_string,
+    });
+    HeaderJson {
+        format: &hdr.format,
+        flags: &hdr.flags,
+        header_version: hdr.header_version,
+        header_size: hdr.header_size,
+        page_size: hdr.page_size,
+        image_size: hdr.image_size,
+        name: &hdr.name,
+        cmdline: &hdr.cmdline,
+        id: &hdr.id,
+        os_version: (hdr.os_version != 0).then(|| format_os_version(hdr.os_version)),
+        os_patch_level: (hdr.os_version != 0).then(|| format_os_patch_level(hdr.os_version)),
+        components,
+        vendor_ramdisk_table,
+        avb,
+    }
+}
+
+fn dump_header(hdr: &BootHeader, file: &str) -> anyhow::Result<()> {
+    let mut out = serde_json::to_string_pretty(&header_json(hdr))?;
+    out.push('\n');
+    fs::write(file, out).with_context(|| format!("cannot write {file}"))?;
+    Ok(())
+}
+
+fn check_unchanged(property: &str, orig: Option<&Value>, value: &Value) -> anyhow::Result<()> {
+    match orig {
+        Some(orig) if orig == value => Ok(()),
+        Some(orig) => Err(anyhow!(
+            "{property} cannot be changed by repack, the original image has {orig}"
+        )),
+        None => Err(anyhow!("unknown property {property}")),
+    }
+}
+
+// hdr describes the original image, which properties that are not applied have to match
+fn check_properties(hdr: &BootHeader, json: &Value) -> anyhow::Result<()> {
+    let orig = serde_json::to_value(header_json(hdr))?;
+    let Some(config) = json.as_object() else {
+        return Err(anyhow!("header is not a JSON object"));
+    };
+    for (key, value) in config {
+        if !APPLIED_PROPERTIES.contains(&key.as_str()) {
+            check_unchanged(key, orig.get(key), value)?;
+        }
+    }
+
+    let Some(table) = config.get("vendor_ramdisk_table").and_then(Value::as_array) else {
+        return Ok(());
+    };
+    let orig_table = orig
+        .get("vendor_ramdisk_table")
+        .and_then(Value::as_array)
+        .map_or(&[][..], Vec::as_slice);
+    for (i, ramdisk) in table.iter().enumerate() {
+        let Some(ramdisk) = ramdisk.as_object() else {
+            continue;
+        };
+        // Ramdisks can be reordered and renamed
+        let name = ramdisk.get("name");
+        let orig = orig_table
+            .iter()
+            .find(|r| r.get("name") == name)
+            .or(orig_table.get(i));
+        for (key, value) in ramdisk {
+            if !APPLIED_RAMDISK_PROPERTIES.contains(&key.as_str()) {
+                let property = format!("{key} of vendor ramdisk {}", name.unwrap_or(&Value::Null));
+                check_unchanged(&property, orig.and_then(|r| r.get(key)), value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn load_header(hdr: &mut BootHeader, file: &str) -> anyhow::Result<()> {
+    let data = fs::read(file).with_context(|| format!("cannot read {file}"))?;
+    let json: Value =
+        serde_json::from_slice(&data).with_context(|| format!("invalid header file {file}"))?;
+    check_properties(hdr, &json).with_context(|| format!("invalid header file {file}"))?;
+    let config: HeaderConfig =
+        serde_json::from_value(json).with_context(|| format!("invalid header file {file}"))?;
+
+    if let Some(name) = config.name {
+        hdr.name = name;
+    }
+    if let Some(cmdline) = config.cmdline {
+        hdr.cmdline = cmdline;
+    }
+    if let Some(v) = config.os_version {
+        hdr.os_version = parse_os_version(hdr.os_version, &v)?;
+    }
+    if let Some(v) = config.os_patch_level {
+        hdr.os_version = parse_os_patch_level(hdr.os_version, &v)?;
+    }
+    if let Some(table) = config.vendor_ramdisk_table {
+        let mut ramdisks = Vec::new();
+        for r in table {
+            if r.name.len() >= 32 {
+                return Err(anyhow!("vendor ramdisk name '{}' is too long", r.name));
+            }
+            // Keep the type and hardware identifiers of existing ramdisks if not specified
+            let old = hdr.vendor_ramdisks.iter().find(|o| o.name == r.name);
+            let ramdisk_type = match &r.ramdisk_type {
+                Some(ty) => parse_vendor_ramdisk_type(ty)?,
+                None => old.map(|o| o.ramdisk_type).unwrap_or(0),
+            };
+            let board_id = match r.board_id {
+                Some(ids) if ids.len() > 16 => {
+                    return Err(anyhow!(
+                        "too many board ids for vendor ramdisk '{}'",
+                        r.name
+                    ));
+                }
+                Some(ids) => ids,
+                None => old.map(|o| o.board_id.clone()).unwrap_or_default(),
+            };
+            ramdisks.push(VendorRamdiskEntry {
+                name: r.name,
+                ramdisk_type,
+                offset: 0,
+                size: 0,
+                format: String::new(),
+                board_id,
+            });
+        }
+        hdr.vendor_ramdisks = ramdisks;
+    }
+    Ok(())
+}
+
+pub fn dump_boot_header(hdr: &BootHeader, file: &str) -> bool {
+    dump_header(hdr, file).log().is_ok()
+}
+
+pub fn load_boot_header(hdr: &mut BootHeader, file: &str) -> bool {
+    load_header(hdr, file).log().is_ok()
+}
@@ -2,25 +2,103 @@
 
 extern crate core;
 
+pub use avb::update_avb_footer;
 pub use base;
+pub use cpio::*;
+pub use header::*;
 pub use payload::*;
+pub use payload_create::*;
+pub use puffpatch::puffpatch;
+pub use vbmeta::*;
 
+mod avb;
+mod bspatch;
+mod cpio;
+mod header;
 mod payload;
-mod update_metadata;
+mod payload_create;
+pub mod puffin;
+mod puffpatch;
+mod sign;
+mod sparse;
+pub mod update_metadata;
+mod util;
+mod vbmeta;
 
 #[cxx::bridge]
 pub mod ffi {
-    unsafe extern "C++" {
-        include!("compress.hpp");
-        fn decompress(buf: &[u8], fd: i32) -> bool;
+    #[namespace = "rust"]
+    struct BootBlock {
+        name: String,
+        offset: u64,
+        size: u64,
+        format: String,
+    }
+
+    #[namespace = "rust"]
+    struct VendorRamdiskEntry {
+        name: String,
+        ramdisk_type: u32,
+        offset: u64,
+        size: u64,
+        format: String,
+        board_id: Vec<u32>,
+    }
+
+    #[namespace = "rust"]
+    struct AvbFooterInfo {
+        version_major: u32,
+        version_minor: u32,
+        original_image_size: u64,
+        vbmeta_offset: u64,
+        vbmeta_size: u64,
+        algorithm_type: u32,
+        flags: u32,
+        rollback_index: u64,
+        rollback_index_location: u32,
+        release_string: String,
+    }
+
+    #[namespace = "rust"]
+    struct BootHeader {
+        format: String,
+        flags: Vec<String>,
+        header_version: u32,
+        header_size: u32,
+        page_size: u32,
+        image_size: u64,
+        name: String,
+        cmdline: String,
+        id: String,
+        os_version: u32,
+        blocks: Vec<BootBlock>,
+        vendor_ramdisks: Vec<VendorRamdiskEntry>,
+        has_avb: bool,
+        avb: AvbFooterInfo,
     }
 
     #[namespace = "rust"]
     extern "Rust" {
-        unsafe fn extract_boot_from_payload(
-            partition: *const c_char,
-            in_path: *const c_char,
-            out_path: *const c_char,
-        ) -> bool;
+        unsafe fn extract_boot_from_payload(argc: i32, argv: *const *const c_char) -> i32;
+        unsafe fn create_payload(argc: i32, argv: *const *const c_char) -> bool;
+        unsafe fn vbmeta_commands(argc: i32, argv: *const *const c_char) -> bool;
+
+        type Cpio;
+        fn cpio_new() -> Box<Cpio>;
+        fn cpio_load(cpio: &mut Cpio, file: &str) -> bool;
+        fn cpio_dump(cpio: &Cpio, file: &str) -> bool;
+        fn cpio_rm(cpio: &mut Cpio, path: &str, recursive: bool);
+        fn cpio_extract(cpio: &Cpio, path: &str, out: &str) -> bool;
+        fn cpio_extract_all(cpio: &Cpio) -> bool;
+        fn cpio_exists(cpio: &Cpio, path: &str) -> bool;
+        fn cpio_add(cpio: &mut Cpio, mode: u32, path: &str, file: &str) -> bool;
+        fn cpio_mkdir(cpio: &mut Cpio, mode: u32, path: &str);
+        fn cpio_ln(cpio: &mut Cpio, target: &str, path: &str);
+        fn cpio_mv(cpio: &mut Cpio, from: &str, to: &str) -> bool;
+
+        fn dump_boot_header(hdr: &BootHeader, file: &str) -> bool;
+        fn load_boot_header(hdr: &mut BootHeader, file: &str) -> bool;
+
+        fn update_avb_footer(orig_img: &str, img: &str, key: &str) -> bool;
     }
 }
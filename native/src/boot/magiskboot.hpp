@@ -5,6 +5,7 @@
 #include "boot-rs.hpp"
 
 #define HEADER_FILE     "header"
+#define HEADER_JSON     "header.json"
 #define KERNEL_FILE     "kernel"
 #define RAMDISK_FILE    "ramdisk.cpio"
 #define SECOND_FILE     "second"
@@ -12,14 +13,18 @@
 #define KER_DTB_FILE    "kernel_dtb"
 #define RECV_DTBO_FILE  "recovery_dtbo"
 #define DTB_FILE        "dtb"
+#define BOOTCONFIG_FILE "bootconfig"
+#define VND_RAMDISK_DIR "vendor_ramdisk"
 #define NEW_BOOT        "new-boot.img"
 
 int unpack(const char *image, bool skip_decomp = false, bool hdr = false);
-void repack(const char *src_img, const char *out_img, bool skip_comp = false);
+void repack(const char *src_img, const char *out_img, bool skip_comp = false,
+            const char *avb_key = nullptr);
 int split_image_dtb(const char *filename);
 int hexpatch(const char *file, const char *from, const char *to);
 int cpio_commands(int argc, char *argv[]);
 int dtb_commands(int argc, char *argv[]);
+int bootconfig_commands(int argc, char *argv[]);
 
 uint32_t patch_verity(void *buf, uint32_t size);
 uint32_t patch_encryption(void *buf, uint32_t size);
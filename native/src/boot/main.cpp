@@ -23,17 +23,28 @@ Supported actions:
     Unpack <bootimg> to its individual components, each component to
     a file with its corresponding file name in the current directory.
     Supported components: kernel, kernel_dtb, ramdisk.cpio, second,
-    dtb, extra, and recovery_dtbo.
+    dtb, extra, recovery_dtbo, and bootconfig.
+    For v4 vendor boot images, each ramdisk in the vendor ramdisk table
+    is unpacked to 'vendor_ramdisk/<name>.cpio' ('vendor_ramdisk/ramdisk.cpio'
+    if the name is empty) instead of a merged ramdisk.cpio. '/' in names is
+    replaced with '_', and a ramdisk whose file is already used by an earlier
+    entry is unpacked to 'vendor_ramdisk/<name>.<index>.cpio'.
     By default, each component will be decompressed on-the-fly.
     If '-n' is provided, all decompression operations will be skipped;
     each component will remain untouched, dumped in its original format.
     If '-h' is provided, the boot image header information will be
     dumped to the file 'header', which can be used to modify header
-    configurations during repacking.
+    configurations during repacking. Each vendor ramdisk is recorded as
+    'vendor_ramdisk=<type>:<name>' in table order, where <type> is one of
+    none, platform, recovery, or dlkm. A JSON description of <bootimg> is
+    also dumped to the file 'header.json': format, flags, header version,
+    page size, name, cmdline, checksum, OS version and patch level, the
+    offset, size and format of every component (offsets are relative to
+    the start of <bootimg>), the vendor ramdisk table, and AVB footer details.
     Return values:
     0:valid    1:error    2:chromeos
 
-  repack [-n] <origbootimg> [outbootimg]
+  repack [-n] [-k KEY] <origbootimg> [outbootimg]
     Repack boot image components using files from the current directory
     to [outbootimg], or 'new-boot.img' if not specified.
     <origbootimg> is the original boot image used to unpack the components.
@@ -42,16 +53,74 @@ Supported actions:
     in the current directory is already compressed, then no addition
     compression will be performed for that specific component.
     If '-n' is provided, all compression operations will be skipped.
+    If 'header' exists, its name, cmdline, os_version, os_patch_level, and
+    vendor_ramdisk=<type>:<name> lines are applied.
+    If 'header.json' exists, its name, cmdline, os_version, os_patch_level,
+    and vendor_ramdisk_table (name, type, and board_id of each entry) are
+    applied if they differ from <origbootimg>, taking precedence over
+    'header'. All other properties describe <origbootimg> and cannot be
+    changed; repack fails if they differ from it.
+    For v4 vendor boot images, the vendor ramdisk table is rebuilt from the
+    header configurations, or from the original table if there are none.
+    Vendor ramdisk types are none, platform, recovery, or dlkm.
+    Ramdisks without a file in 'vendor_ramdisk/' are dropped.
+    If '-k KEY' is provided and <origbootimg> has an AVB footer, the digest
+    of the hash descriptor of <origbootimg> is updated for the new image, and
+    the vbmeta image is re-signed with KEY, an RSA private key (PEM or DER)
+    of 2048 or 4096 bits, using SHA256_RSA2048 or SHA256_RSA4096 accordingly.
     If env variable PATCHVBMETAFLAG is set to true, all disable flags in
     the boot image's vbmeta header will be set.
 
-  extract <payload.bin> [partition] [outfile]
+  extract [-n] [-S] [-t N] [-s SRCIMG] [-k KEY] <payload.bin> [partition] [outfile]
     Extract [partition] from <payload.bin> to [outfile].
     If [outfile] is not specified, then output to '[partition].img'.
     If [partition] is not specified, then attempt to extract either
     'init_boot' or 'boot'. Which partition was chosen can be determined
     by whichever 'init_boot.img' or 'boot.img' exists.
-    <payload.bin> can be '-' to be STDIN.
+    [partition] can be a comma separated list of partitions, or 'all'.
+    In that case, all partitions are extracted in a single pass, and
+    [outfile] is the directory to store '[partition].img' files.
+    <payload.bin> can be '-' to be STDIN, or a full OTA zip. In that case,
+    the stored payload.bin is read directly from the zip, and it is
+    checked against payload_properties.txt if the zip has one.
+    Delta (incremental) payloads are applied on top of the original
+    partition image, which has to be provided with '-s SRCIMG'. When
+    extracting multiple partitions, SRCIMG is the directory containing
+    the original '[partition].img' files.
+    Both major version 1 (Chrome OS / Brillo) and 2 payloads are
+    supported. In major version 1 payloads, the kernel and rootfs are
+    named 'boot' and 'system'. The detected version is printed.
+    By default, the SHA-256 hashes recorded in the payload are checked
+    for each operation and the extracted partition. If the extracted
+    partition has an AVB footer, its hash or hash tree descriptor is
+    verified as well. The verity hash tree described in the payload is
+    generated if the payload does not carry it.
+    If '-n' is provided, all hash verification will be skipped.
+    If '-S' is provided, output Android sparse images instead.
+    If '-k KEY' is provided, the metadata and payload signatures are
+    verified against KEY, which can be a public key, a certificate,
+    or a zip of certificates like 'otacerts.zip' (PEM or DER).
+    If <payload.bin> is a file, operations are extracted by multiple
+    threads in parallel. '-t N' sets the number of threads, which
+    defaults to the number of CPUs. Signature verification with '-k'
+    reads the payload sequentially in a single thread.
+    Return values:
+    0:success      1:error             2:invalid magic
+    3:unsupported payload version      4:delta payload without SRCIMG
+    5:partition not found              6:unsupported operation
+    7:decompression failure            8:hash mismatch
+    9:signature verification failure   10:invalid payload
+    11:I/O error
+
+  extract -l [-j] <payload.bin>
+    List the partitions and metadata stored in <payload.bin> without
+    extracting anything. If '-j' is provided, print in JSON format.
+
+  mkpayload [-r] <payload.bin> <partition>=<image> [...]
+    Create a full, unsigned <payload.bin> from partition images.
+    Each image is split into 2MB chunks, stored as ZERO operations if
+    the chunk is empty, else as REPLACE_XZ or REPLACE operations,
+    whichever is smaller. If '-r' is provided, xz compression is skipped.
 
   hexpatch <file> <hexpattern1> <hexpattern2>
     Search <hexpattern1> in <file>, and replace it with <hexpattern2>
@@ -59,6 +128,10 @@ Supported actions:
   cpio <incpio> [commands...]
     Do cpio commands to <incpio> (modifications are done in-place)
     Each command is a single argument, add quotes for each command.
+    If env variable SOURCE_DATE_EPOCH is set, the mtime of all entries
+    is set to its value. Together with inode numbers assigned in the
+    order of entry names, the output only depends on the entries'
+    names, contents, and metadata, making patched images reproducible.
     Supported commands:
       exists ENTRY
         Return 0 if ENTRY exists, else return 1
@@ -70,6 +143,16 @@ Supported actions:
         Create a symlink to TARGET with the name ENTRY
       mv SOURCE DEST
         Move SOURCE to DEST
+      chmod MODE ENTRY
+        Change the permissions of ENTRY to MODE
+      chown UID:GID ENTRY
+        Change the owner of ENTRY to UID:GID
+      touch [-t TIME] ENTRY
+        Set the modification time of ENTRY to TIME in seconds since epoch,
+        or the current time. Create ENTRY as an empty file if not exists
+      ls [-l] [ENTRY]
+        List ENTRY and all entries under it, or all entries. Specify [-l]
+        to show mode, uid, gid, size, mtime, and symlink targets
       add MODE ENTRY INFILE
         Add INFILE as ENTRY in permissions MODE; replaces ENTRY if exists
       extract [ENTRY OUT]
@@ -85,9 +168,26 @@ Supported actions:
         Create ramdisk backups from ORIG
       restore
         Restore ramdisk from ramdisk backup stored within incpio
+      diff ORIG
+        List entries added, removed, or changed in incpio compared
+        with ORIG, as well as changed permissions and owners
+      merge STOCK [BASE]
+        Move the changes made to incpio onto the new stock ramdisk STOCK.
+        The changes are the difference between incpio and BASE, which
+        is restored from the ramdisk backup within incpio if not set.
+        Entries changed in both incpio and STOCK keep the incpio version.
+        If incpio has ramdisk backups, they are recreated against STOCK
       sha1
         Print stock boot SHA1 if previously backed up in ramdisk
 
+  cpio <incpio> -f <script>
+    Do the cpio commands listed in <script> to <incpio>, one per line.
+    Empty lines and lines starting with '#' are ignored. Arguments
+    containing spaces can be quoted with ' or ", or escaped with \.
+    Only add, rm, mv, ln, mkdir, chmod, chown, and touch are supported.
+    All commands are checked before any of them is applied, and <incpio>
+    is only replaced if all of them succeeded.
+
   dtb <file> <action> [args...]
     Do dtb related actions to <file>
     Supported actions:
@@ -103,6 +203,45 @@ Supported actions:
         Return values:
         0:valid    1:error
 
+  bootconfig <img> <action> [args...]
+    Do bootconfig related actions to the bootconfig section of <img>
+    Only v4 vendor boot images have a bootconfig section
+    Supported actions:
+      print
+        Print all bootconfig parameters
+      get KEY
+        Print the value of KEY, return 1 if KEY does not exist
+      set KEY=VALUE
+        Set KEY to VALUE, adding KEY if it does not exist. VALUE can be a
+        comma separated array, quote values containing , ; # { or }
+      rm KEY
+        Remove KEY, return 1 if KEY does not exist
+    Modifications are done directly to <img> in-place. The bootconfig trailer
+    (size, checksum and magic) is regenerated if the section has one.
+    The modified section has to fit in the pages used by the original one.
+    Comments are kept, sections with nested keys cannot be modified.
+
+  vbmeta <img> <action> [args...]
+    Do vbmeta related actions to <img>, a vbmeta image or an image with
+    an AVB footer
+    Supported actions:
+      print
+        Print the vbmeta header and all descriptors (hash, hashtree,
+        chain partition, property, and kernel cmdline)
+      patch [-k KEY] [FLAGS]
+        Set the vbmeta flags to FLAGS, either a number or a comma separated
+        list of hashtree-disabled and verification-disabled ('0' clears
+        all flags). If '-k KEY' is provided, re-sign the vbmeta image with
+        KEY, an RSA private key (PEM or DER) of 2048 or 4096 bits.
+        Modifications are done directly to <img> in-place
+      verify [-k KEY]
+        Verify the vbmeta signature with the embedded public key. If '-k KEY'
+        is provided, also check that the embedded key matches KEY, which can
+        be a private key, a public key, a certificate, or a zip of
+        certificates.
+        Return values:
+        0:valid    1:error
+
   split <file>
     Split image.*-dtb into kernel + kernel_dtb
 
@@ -152,6 +291,7 @@ int main(int argc, char *argv[]) {
     if (action == "cleanup") {
         fprintf(stderr, "Cleaning up...\n");
         unlink(HEADER_FILE);
+        unlink(HEADER_JSON);
         unlink(KERNEL_FILE);
         unlink(RAMDISK_FILE);
         unlink(SECOND_FILE);
@@ -159,6 +299,8 @@ int main(int argc, char *argv[]) {
         unlink(EXTRA_FILE);
         unlink(RECV_DTBO_FILE);
         unlink(DTB_FILE);
+        unlink(BOOTCONFIG_FILE);
+        rm_rf(VND_RAMDISK_DIR);
     } else if (argc > 2 && action == "sha1") {
         uint8_t sha1[SHA_DIGEST_SIZE];
         auto m = mmap_data(argv[2]);
@@ -189,13 +331,24 @@ int main(int argc, char *argv[]) {
         }
         return unpack(argv[idx], nodecomp, hdr);
     } else if (argc > 2 && action == "repack") {
-        if (argv[2] == "-n"sv) {
-            if (argc == 3)
+        int idx = 2;
+        bool nocomp = false;
+        const char *key = nullptr;
+        for (;;) {
+            if (idx >= argc)
                 usage(argv[0]);
-            repack(argv[3], argv[4] ? argv[4] : NEW_BOOT, true);
-        } else {
-            repack(argv[2], argv[3] ? argv[3] : NEW_BOOT);
+            if (argv[idx] == "-n"sv) {
+                nocomp = true;
+            } else if (argv[idx] == "-k"sv) {
+                if (++idx >= argc)
+                    usage(argv[0]);
+                key = argv[idx];
+            } else {
+                break;
+            }
+            ++idx;
         }
+        repack(argv[idx], argv[idx + 1] ? argv[idx + 1] : NEW_BOOT, nocomp, key);
     } else if (argc > 2 && action == "decompress") {
         decompress(argv[2], argv[3]);
     } else if (argc > 2 && str_starts(action, "compress")) {
@@ -208,12 +361,15 @@ int main(int argc, char *argv[]) {
     } else if (argc > 3 && action == "dtb") {
         if (dtb_commands(argc - 2, argv + 2))
             usage(argv[0]);
+    } else if (argc > 3 && action == "bootconfig") {
+        if (bootconfig_commands(argc - 2, argv + 2))
+            usage(argv[0]);
+    } else if (argc > 3 && action == "vbmeta") {
+        return rust::vbmeta_commands(argc - 2, argv + 2) ? 0 : 1;
     } else if (argc > 2 && action == "extract") {
-        return rust::extract_boot_from_payload(
-                argv[2],
-                argc > 3 ? argv[3] : nullptr,
-                argc > 4 ? argv[4] : nullptr
-                ) ? 0 : 1;
+        return rust::extract_boot_from_payload(argc - 2, argv + 2);
+    } else if (argc > 3 && action == "mkpayload") {
+        return rust::create_payload(argc - 2, argv + 2) ? 0 : 1;
     } else {
         usage(argv[0]);
     }
@@ -1,212 +1,1584 @@
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
 
 use anyhow::{anyhow, Context};
+use base64::Engine;
 use byteorder::{BigEndian, ReadBytesExt};
-use protobuf::{EnumFull, Message};
+use protobuf::{EnumFull, Message, MessageField};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use zip::{CompressionMethod, ZipArchive};
 
+use base::libc;
 use base::libc::c_char;
-use base::{ptr_to_str_result, ReadSeekExt, StrErr};
+use base::{ptr_to_str_result, ReadExt};
 use base::{ResultExt, WriteExt};
 
-use crate::ffi;
+use crate::avb::{hash_tree, verify_footer, HashAlgorithm};
+use crate::bspatch::bspatch;
+use crate::puffpatch::puffpatch;
+use crate::sign::{load_verifying_keys, VerifyingKey};
+use crate::sparse::write_sparse_image;
 use crate::update_metadata::install_operation::Type;
-use crate::update_metadata::DeltaArchiveManifest;
+use crate::update_metadata::{
+    DeltaArchiveManifest, Extent, InstallOperation, PartitionUpdate, Signatures,
+};
+use crate::util::to_hex;
+
+#[derive(Debug, Error)]
+pub enum PayloadError {
+    #[error("invalid payload magic")]
+    BadMagic,
+    #[error("unsupported payload version: {0}")]
+    UnsupportedVersion(u64),
+    #[error("delta payloads require the source partition image, please specify it with -s")]
+    DeltaPayload,
+    #[error("partition '{0}' not found")]
+    MissingPartition(String),
+    #[error("unsupported operation type: {0}")]
+    UnsupportedOperation(String),
+    #[error("failed to decompress {0} data")]
+    Decompression(String, #[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("{0}")]
+    HashMismatch(String),
+    #[error("{0}")]
+    Signature(&'static str),
+    #[error("invalid payload: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl PayloadError {
+    // Exit codes of `magiskboot extract`, 1 is used for any other error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PayloadError::BadMagic => 2,
+            PayloadError::UnsupportedVersion(_) => 3,
+            PayloadError::DeltaPayload => 4,
+            PayloadError::MissingPartition(_) => 5,
+            PayloadError::UnsupportedOperation(_) => 6,
+            PayloadError::Decompression(..) => 7,
+            PayloadError::HashMismatch(_) => 8,
+            PayloadError::Signature(_) => 9,
+            PayloadError::Invalid(_) => 10,
+            PayloadError::Io(_) => IO_ERROR_EXIT_CODE,
+        }
+    }
+}
+
+const IO_ERROR_EXIT_CODE: i32 = 11;
+
+// Exit code of the first PayloadError or I/O error that caused the error
+fn exit_code(err: &anyhow::Error) -> i32 {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<PayloadError>() {
+            return e.exit_code();
+        }
+        if cause.is::<io::Error>() {
+            return IO_ERROR_EXIT_CODE;
+        }
+    }
+    1
+}
 
 macro_rules! bad_payload {
-    ($msg:literal) => {
-        anyhow!(concat!("invalid payload: ", $msg))
-    };
     ($($args:tt)*) => {
-        anyhow!("invalid payload: {}", format_args!($($args)*))
+        PayloadError::Invalid(format!($($args)*))
     };
 }
 
-const PAYLOAD_MAGIC: &str = "CrAU";
+pub const PAYLOAD_MAGIC: &str = "CrAU";
 
-fn do_extract_boot_from_payload(
-    in_path: &str,
-    partition_name: Option<&str>,
-    out_path: Option<&str>,
-) -> anyhow::Result<()> {
-    let mut reader = BufReader::new(if in_path == "-" {
-        unsafe { File::from_raw_fd(0) }
+// Major version 1 is used by Chrome OS and early Brillo payloads, which do not have
+// a manifest signature and describe the kernel and rootfs in dedicated manifest fields
+const CHROMEOS_MAJOR_VERSION: u64 = 1;
+const BRILLO_MAJOR_VERSION: u64 = 2;
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+// Extents starting at this block are not backed by any data, and read as zeros
+const SPARSE_HOLE: u64 = u64::MAX;
+
+// Hard limits on sizes read from the payload, so that a malicious header cannot
+// make us allocate huge buffers. They are far beyond what delta_generator produces.
+const MAX_MANIFEST_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_SIGNATURE_SIZE: u64 = 64 * 1024;
+const MAX_PROPERTIES_SIZE: u64 = 64 * 1024;
+// Applies to both the data and the source extents of a single operation
+const MAX_OPERATION_SIZE: u64 = 512 * 1024 * 1024;
+
+// Partition images are accessed with positional I/O, so that operations can be applied
+// in any order. Files are used directly, other streams are adapted with SeekImage and SeekSink.
+trait ImageSource {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+}
+
+trait ImageSink {
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    // Make the range read back as zeros
+    fn zero_at(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        write_zeros_at(self, offset, len)
+    }
+}
+
+fn write_zeros_at<S: ImageSink + ?Sized>(sink: &mut S, offset: u64, len: u64) -> io::Result<()> {
+    let buf = [0u8; 4096];
+    let mut pos = 0;
+    while pos < len {
+        let l = std::cmp::min(buf.len() as u64, len - pos);
+        sink.write_at(&buf[..l as usize], offset + pos)?;
+        pos += l;
+    }
+    Ok(())
+}
+
+impl ImageSource for &File {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+}
+
+impl ImageSink for &File {
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write_all_at(buf, offset)
+    }
+
+    // Deallocate the blocks if the filesystem supports punching holes
+    fn zero_at(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let ret = unsafe {
+            libc::fallocate(
+                self.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            write_zeros_at(self, offset, len)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+struct SeekImage<T>(T);
+
+impl<R: Read + Seek> ImageSource for SeekImage<R> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.0.seek(SeekFrom::Start(offset))?;
+        self.0.read_exact(buf)
+    }
+}
+
+// Writes past the partition size, which are the padding of the last block, are dropped
+struct SeekSink<W> {
+    inner: W,
+    size: u64,
+}
+
+impl<W: Write + Seek> ImageSink for SeekSink<W> {
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if offset >= self.size {
+            return Ok(());
+        }
+        let len = std::cmp::min(buf.len() as u64, self.size - offset) as usize;
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.write_all(&buf[..len])
+    }
+}
+
+fn read_extents<T: ImageSource>(
+    src: &mut T,
+    extents: &[Extent],
+    block_size: u64,
+) -> Result<Vec<u8>, PayloadError> {
+    let mut data = Vec::new();
+    for ext in extents {
+        let start_block = ext
+            .start_block
+            .ok_or(bad_payload!("start block not found"))?;
+        let num_blocks = ext.num_blocks.ok_or(bad_payload!("num blocks not found"))?;
+        let len = (num_blocks * block_size) as usize;
+        let pos = data.len();
+        data.resize(pos + len, 0u8);
+        if start_block != SPARSE_HOLE {
+            src.read_at(&mut data[pos..], start_block * block_size)?;
+        }
+    }
+    Ok(data)
+}
+
+// Writes a continuous stream of data into the blocks described by extents
+struct ExtentWriter<'a, S> {
+    out: &'a mut S,
+    extents: std::slice::Iter<'a, Extent>,
+    block_size: u64,
+    offset: u64,
+    remain: u64,
+}
+
+impl<'a, S: ImageSink> ExtentWriter<'a, S> {
+    fn new(out: &'a mut S, extents: &'a [Extent], block_size: u64) -> Self {
+        ExtentWriter {
+            out,
+            extents: extents.iter(),
+            block_size,
+            offset: 0,
+            remain: 0,
+        }
+    }
+}
+
+impl<S: ImageSink> Write for ExtentWriter<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.remain == 0 {
+            let ext = self.extents.next().ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data exceeds dst extents",
+            ))?;
+            let (Some(start_block), Some(num_blocks)) = (ext.start_block, ext.num_blocks) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid dst extent",
+                ));
+            };
+            self.offset = start_block * self.block_size;
+            self.remain = num_blocks * self.block_size;
+        }
+        let len = std::cmp::min(buf.len() as u64, self.remain) as usize;
+        self.out.write_at(&buf[..len], self.offset)?;
+        self.offset += len as u64;
+        self.remain -= len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_extents<S: ImageSink>(
+    out: &mut S,
+    extents: &[Extent],
+    block_size: u64,
+    data: &[u8],
+) -> Result<(), PayloadError> {
+    ExtentWriter::new(out, extents, block_size).write_all(data)?;
+    Ok(())
+}
+
+fn zero_extents<S: ImageSink>(
+    out: &mut S,
+    extents: &[Extent],
+    block_size: u64,
+) -> Result<(), PayloadError> {
+    for ext in extents {
+        let start_block = ext
+            .start_block
+            .ok_or(bad_payload!("start block not found"))?;
+        let num_blocks = ext.num_blocks.ok_or(bad_payload!("num blocks not found"))?;
+        out.zero_at(start_block * block_size, num_blocks * block_size)?;
+    }
+    Ok(())
+}
+
+// Decompress the data of REPLACE_* operations while writing it out
+fn decompress_to<W: Write>(data_type: Type, data: &[u8], out: &mut W) -> anyhow::Result<()> {
+    match data_type {
+        Type::REPLACE_BZ => {
+            io::copy(&mut bzip2_rs::DecoderReader::new(data), out)?;
+        }
+        Type::REPLACE_XZ => {
+            io::copy(&mut lzma_rust2::XzReader::new(data, true), out)?;
+        }
+        Type::ZSTD => {
+            io::copy(&mut ruzstd::StreamingDecoder::new(data)?, out)?;
+        }
+        _ => out.write_all(data)?,
+    }
+    Ok(())
+}
+
+fn check_hash(data: &[u8], hash: &[u8]) -> bool {
+    hash.is_empty() || Sha256::digest(data).as_slice() == hash
+}
+
+fn verify_signatures(keys: &[VerifyingKey], digest: &[u8], data: &[u8]) -> bool {
+    let Ok(signatures) = Signatures::parse_from_bytes(data) else {
+        return false;
+    };
+    signatures.signatures.iter().any(|sig| {
+        // EC signatures are padded to a fixed size
+        let mut data = sig.data();
+        if let Some(len) = sig.unpadded_signature_size {
+            data = &data[..std::cmp::min(len as usize, data.len())];
+        }
+        keys.iter().any(|key| key.verify_digest(digest, data))
+    })
+}
+
+// Reads the payload forward only, and hashes everything read if signatures are verified
+struct PayloadReader<R> {
+    inner: R,
+    hasher: Option<Sha256>,
+}
+
+impl<R> PayloadReader<R> {
+    fn digest(&self) -> Vec<u8> {
+        match &self.hasher {
+            Some(hasher) => hasher.clone().finalize().to_vec(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for PayloadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+// Applies the install operations of a partition to the output image
+#[derive(Clone)]
+struct PartitionWriter<'a, S, T> {
+    partition: &'a PartitionUpdate,
+    out: S,
+    src: Option<T>,
+    block_size: u64,
+    verify: bool,
+}
+
+impl<S: ImageSink, T: ImageSource> PartitionWriter<'_, S, T> {
+    fn name(&self) -> &str {
+        self.partition.partition_name()
+    }
+
+    fn apply_operation(
+        &mut self,
+        idx: usize,
+        operation: &InstallOperation,
+        data: &[u8],
+    ) -> Result<(), PayloadError> {
+        if self.verify && !data.is_empty() && !check_hash(data, operation.data_sha256_hash()) {
+            return Err(PayloadError::HashMismatch(format!(
+                "data hash mismatch in partition '{}' operation #{}",
+                self.name(),
+                idx
+            )));
+        }
+
+        let block_size = self.block_size;
+        let data_type = operation
+            .type_
+            .ok_or(bad_payload!("operation type not found"))?
+            .enum_value()
+            .map_err(|v| PayloadError::UnsupportedOperation(v.to_string()))?;
+
+        let out = &mut self.out;
+        match data_type {
+            Type::REPLACE | Type::REPLACE_BZ | Type::REPLACE_XZ | Type::ZSTD => {
+                let mut writer = ExtentWriter::new(out, &operation.dst_extents, block_size);
+                decompress_to(data_type, data, &mut writer).map_err(|e| {
+                    PayloadError::Decompression(data_type.descriptor().name().to_owned(), e.into())
+                })?;
+            }
+            Type::ZERO | Type::DISCARD => {
+                zero_extents(out, &operation.dst_extents, block_size)?;
+            }
+            Type::SOURCE_COPY | Type::SOURCE_BSDIFF | Type::BROTLI_BSDIFF | Type::PUFFDIFF => {
+                let src = self.src.as_mut().ok_or(bad_payload!(
+                    "{} operation in full payload",
+                    data_type.descriptor().name()
+                ))?;
+                let mut src = read_extents(src, &operation.src_extents, block_size)?;
+                if let Some(src_len) = operation.src_length {
+                    src.truncate(src_len as usize);
+                }
+                if self.verify && !check_hash(&src, operation.src_sha256_hash()) {
+                    return Err(PayloadError::HashMismatch(format!(
+                        "source hash mismatch in partition '{}' operation #{}, \
+                         is the source image correct?",
+                        self.name(),
+                        idx
+                    )));
+                }
+                if data_type == Type::SOURCE_COPY {
+                    write_extents(out, &operation.dst_extents, block_size, &src)?;
+                } else {
+                    let dst_len = operation
+                        .dst_extents
+                        .iter()
+                        .map(|e| e.num_blocks() * block_size)
+                        .sum::<u64>()
+                        .min(MAX_OPERATION_SIZE);
+                    let new = if data_type == Type::PUFFDIFF {
+                        // Puffed streams are larger than the data they are converted from
+                        puffpatch(&src, data, MAX_OPERATION_SIZE)
+                    } else {
+                        bspatch(&src, data, dst_len)
+                    }
+                    .map_err(|e| {
+                        bad_payload!("failed to apply {}: {:#}", data_type.descriptor().name(), e)
+                    })?;
+                    write_extents(out, &operation.dst_extents, block_size, &new)?;
+                }
+            }
+            _ => {
+                return Err(PayloadError::UnsupportedOperation(
+                    data_type.descriptor().name().to_owned(),
+                ));
+            }
+        };
+        Ok(())
+    }
+}
+
+// A partition being extracted from the payload
+struct ExtractTarget<'a> {
+    partition: &'a PartitionUpdate,
+    out_path: String,
+    out_file: File,
+    src_file: Option<File>,
+}
+
+impl ExtractTarget<'_> {
+    fn name(&self) -> &str {
+        self.partition.partition_name()
+    }
+
+    fn writer(&self, block_size: u64, verify: bool) -> PartitionWriter<'_, &File, &File> {
+        PartitionWriter {
+            partition: self.partition,
+            out: &self.out_file,
+            src: self.src_file.as_ref(),
+            block_size,
+            verify,
+        }
+    }
+
+    fn set_size(&self) -> anyhow::Result<()> {
+        let info = &self.partition.new_partition_info;
+        if info.has_size() {
+            self.out_file.set_len(info.size())?;
+        }
+        Ok(())
+    }
+
+    // Like update_engine, generate the verity hash tree described by the hash_tree_* fields,
+    // as payloads usually leave it out to be computed on the device
+    fn write_hash_tree(&self, block_size: u64) -> anyhow::Result<()> {
+        let p = self.partition;
+        let (Some(data_ext), Some(tree_ext)) = (
+            p.hash_tree_data_extent.as_ref(),
+            p.hash_tree_extent.as_ref(),
+        ) else {
+            return Ok(());
+        };
+        let algorithm = HashAlgorithm::from_name(p.hash_tree_algorithm())?;
+        let (_, tree) = hash_tree(
+            &self.out_file,
+            data_ext.start_block() * block_size,
+            data_ext.num_blocks() * block_size,
+            block_size as u32,
+            algorithm,
+            p.hash_tree_salt(),
+        )?;
+        if tree.len() as u64 > tree_ext.num_blocks() * block_size {
+            return Err(bad_payload!(
+                "hash tree exceeds its extent in partition '{}'",
+                self.name()
+            )
+            .into());
+        }
+
+        let offset = tree_ext.start_block() * block_size;
+        let mut stored = vec![0u8; tree.len()];
+        self.out_file.read_exact_at(&mut stored, offset)?;
+        if stored == tree {
+            return Ok(());
+        }
+        if stored.iter().any(|b| *b != 0) {
+            return Err(PayloadError::HashMismatch(format!(
+                "hash tree mismatch in partition '{}'",
+                self.name()
+            ))
+            .into());
+        }
+        self.out_file.write_all_at(&tree, offset)?;
+        Ok(())
+    }
+
+    fn write_sparse(&self, block_size: u64) -> anyhow::Result<()> {
+        // DISCARD blocks read as undefined, so they do not have to be flashed
+        let mut dont_care = Vec::new();
+        for op in self.partition.operations.iter() {
+            if matches!(op.type_.map(|t| t.enum_value()), Some(Ok(Type::DISCARD))) {
+                for ext in op.dst_extents.iter() {
+                    dont_care.push((ext.start_block(), ext.num_blocks()));
+                }
+            }
+        }
+
+        let tmp = format!("{}.sparse", self.out_path);
+        let mut out =
+            BufWriter::new(File::create(&tmp).with_context(|| format!("cannot write to '{tmp}'"))?);
+        let size = self.out_file.metadata()?.len();
+        write_sparse_image(
+            &self.out_file,
+            size,
+            block_size as u32,
+            &dont_care,
+            &mut out,
+        )?;
+        out.flush()?;
+        drop(out);
+        rename(&tmp, &self.out_path)?;
+        Ok(())
+    }
+
+    fn verify(&mut self) -> anyhow::Result<()> {
+        let info = &self.partition.new_partition_info;
+        if !info.has_hash() {
+            return Ok(());
+        }
+        self.out_file.seek(SeekFrom::Start(0))?;
+        let mut hasher = Sha256::new();
+        let len = std::io::copy(&mut (&mut self.out_file).take(info.size()), &mut hasher)?;
+        if len != info.size() || hasher.finalize().as_slice() != info.hash() {
+            return Err(PayloadError::HashMismatch(format!(
+                "hash mismatch in partition '{}'",
+                self.name()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+fn open_output(path: &str) -> anyhow::Result<File> {
+    // The output is read back for verification
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("cannot write to '{path}'"))
+}
+
+// Values from payload_properties.txt, which is stored next to payload.bin in OTA zips
+#[derive(Default)]
+struct PayloadProperties {
+    file_size: Option<u64>,
+    metadata_size: Option<u64>,
+    metadata_hash: Option<Vec<u8>>,
+}
+
+impl PayloadProperties {
+    fn parse(text: &str) -> PayloadProperties {
+        let mut props = PayloadProperties::default();
+        let b64 = base64::engine::general_purpose::STANDARD;
+        for line in text.lines() {
+            let Some((key, val)) = line.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "FILE_SIZE" => props.file_size = val.trim().parse().ok(),
+                "METADATA_SIZE" => props.metadata_size = val.trim().parse().ok(),
+                "METADATA_HASH" => props.metadata_hash = b64.decode(val.trim()).ok(),
+                _ => {}
+            }
+        }
+        props
+    }
+}
+
+// Locate payload.bin in an OTA zip and seek the file to the start of its data
+fn open_payload_zip(mut file: File) -> anyhow::Result<(File, PayloadProperties)> {
+    let mut zip = ZipArchive::new(&file).context("invalid zip file")?;
+
+    let props = match zip.by_name("payload_properties.txt") {
+        Ok(mut entry) => {
+            let mut text = String::new();
+            (&mut entry)
+                .take(MAX_PROPERTIES_SIZE)
+                .read_to_string(&mut text)?;
+            PayloadProperties::parse(&text)
+        }
+        Err(_) => PayloadProperties::default(),
+    };
+
+    let entry = zip
+        .by_name("payload.bin")
+        .map_err(|_| anyhow!("payload.bin not found in zip"))?;
+    // The payload has to be stored uncompressed so it can be read in place
+    if entry.compression() != CompressionMethod::Stored {
+        return Err(anyhow!("payload.bin in zip is compressed"));
+    }
+    let offset = entry.data_start();
+    let size = entry.size();
+    drop(entry);
+    drop(zip);
+
+    if props.file_size.is_some_and(|s| s != size) {
+        return Err(anyhow!(
+            "payload.bin size does not match payload_properties.txt"
+        ));
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    Ok((file, props))
+}
+
+// Open either a bare payload.bin or an OTA zip containing it
+fn open_payload(in_path: &str) -> anyhow::Result<(BufReader<File>, PayloadProperties)> {
+    if in_path == "-" {
+        let file = unsafe { File::from_raw_fd(0) };
+        return Ok((BufReader::new(file), PayloadProperties::default()));
+    }
+
+    let mut file = File::open(in_path).with_context(|| format!("cannot open '{in_path}'"))?;
+    let mut magic = [0u8; 4];
+    let is_zip = file.read_exact(&mut magic).is_ok() && magic == ZIP_MAGIC;
+    let (file, props) = if is_zip {
+        open_payload_zip(file)?
     } else {
-        File::open(in_path).with_context(|| format!("cannot open '{in_path}'"))?
-    });
+        file.rewind()?;
+        (file, PayloadProperties::default())
+    };
+    Ok((BufReader::new(file), props))
+}
+
+// Header fields that are not part of the manifest
+struct PayloadHeader {
+    major_version: u64,
+    // Length of the manifest signature following the manifest
+    manifest_sig_len: u32,
+}
+
+// Major version 1 payloads only have the kernel and rootfs partitions. They are
+// converted to PartitionUpdates with the names update_engine uses for them on Android.
+fn convert_legacy_manifest(manifest: &mut DeltaArchiveManifest) {
+    let legacy = [
+        (
+            "system",
+            std::mem::take(&mut manifest.install_operations),
+            manifest.old_rootfs_info.take(),
+            manifest.new_rootfs_info.take(),
+        ),
+        (
+            "boot",
+            std::mem::take(&mut manifest.kernel_install_operations),
+            manifest.old_kernel_info.take(),
+            manifest.new_kernel_info.take(),
+        ),
+    ];
+    for (name, operations, old_info, new_info) in legacy {
+        // The signature blob is referenced by a dummy operation writing to a sparse hole,
+        // so that old clients would not treat it as unused data
+        let operations: Vec<_> = operations
+            .into_iter()
+            .filter(|op| {
+                op.dst_extents.is_empty()
+                    || op
+                        .dst_extents
+                        .iter()
+                        .any(|e| e.start_block() != SPARSE_HOLE)
+            })
+            .collect();
+        if operations.is_empty() && new_info.is_none() {
+            continue;
+        }
+        let mut partition = PartitionUpdate::new();
+        partition.set_partition_name(name.to_owned());
+        partition.operations = operations;
+        partition.old_partition_info = MessageField::from_option(old_info);
+        partition.new_partition_info = MessageField::from_option(new_info);
+        manifest.partitions.push(partition);
+    }
+}
 
+fn read_manifest<R: Read>(
+    reader: &mut R,
+    props: &PayloadProperties,
+) -> Result<(DeltaArchiveManifest, PayloadHeader), PayloadError> {
     let buf = &mut [0u8; 4];
     reader.read_exact(buf)?;
 
     if buf != PAYLOAD_MAGIC.as_bytes() {
-        return Err(bad_payload!("invalid magic"));
+        return Err(PayloadError::BadMagic);
     }
 
     let version = reader.read_u64::<BigEndian>()?;
-    if version != 2 {
-        return Err(bad_payload!("unsupported version: {}", version));
+    if version != CHROMEOS_MAJOR_VERSION && version != BRILLO_MAJOR_VERSION {
+        return Err(PayloadError::UnsupportedVersion(version));
     }
 
-    let manifest_len = reader.read_u64::<BigEndian>()? as usize;
+    let manifest_len = reader.read_u64::<BigEndian>()?;
     if manifest_len == 0 {
         return Err(bad_payload!("manifest length is zero"));
     }
+    if manifest_len > MAX_MANIFEST_SIZE {
+        return Err(bad_payload!(
+            "manifest length {} is too large",
+            manifest_len
+        ));
+    }
+    let manifest_len = manifest_len as usize;
 
-    let manifest_sig_len = reader.read_u32::<BigEndian>()?;
-    if manifest_sig_len == 0 {
-        return Err(bad_payload!("manifest signature length is zero"));
+    // Unsigned payloads do not have a manifest signature,
+    // and major version 1 does not have the field at all
+    let manifest_sig_len = if version == CHROMEOS_MAJOR_VERSION {
+        0
+    } else {
+        reader.read_u32::<BigEndian>()?
+    };
+    if manifest_sig_len as u64 > MAX_SIGNATURE_SIZE {
+        return Err(bad_payload!(
+            "manifest signature length {} is too large",
+            manifest_sig_len
+        ));
     }
 
-    let mut buf = Vec::new();
-    buf.resize(manifest_len, 0u8);
+    let mut buf = vec![0u8; manifest_len];
+    reader.read_exact(&mut buf)?;
 
-    let manifest = {
-        let manifest = &mut buf[..manifest_len];
-        reader.read_exact(manifest)?;
-        DeltaArchiveManifest::parse_from_bytes(&manifest)?
+    // The metadata described in payload_properties.txt is the header and the manifest
+    let header_size = if version == CHROMEOS_MAJOR_VERSION {
+        20
+    } else {
+        24
     };
-    if !manifest.has_minor_version() || manifest.minor_version() != 0 {
+    let metadata_size = header_size + manifest_len as u64;
+    if props.metadata_size.is_some_and(|s| s != metadata_size) {
         return Err(bad_payload!(
-            "delta payloads are not supported, please use a full payload file"
+            "metadata size does not match payload_properties.txt"
         ));
     }
+    if let Some(hash) = &props.metadata_hash {
+        let mut hasher = Sha256::new();
+        hasher.update(PAYLOAD_MAGIC);
+        hasher.update(version.to_be_bytes());
+        hasher.update((manifest_len as u64).to_be_bytes());
+        if version != CHROMEOS_MAJOR_VERSION {
+            hasher.update(manifest_sig_len.to_be_bytes());
+        }
+        hasher.update(&buf);
+        if hasher.finalize().as_slice() != hash.as_slice() {
+            return Err(bad_payload!(
+                "metadata hash does not match payload_properties.txt"
+            ));
+        }
+    }
 
+    let mut manifest = DeltaArchiveManifest::parse_from_bytes(&buf)
+        .map_err(|e| bad_payload!("failed to parse manifest: {}", e))?;
+    if !manifest.has_minor_version() {
+        return Err(bad_payload!("minor version not found"));
+    }
+    if version == CHROMEOS_MAJOR_VERSION {
+        convert_legacy_manifest(&mut manifest);
+    }
+    check_manifest(&manifest)?;
+
+    Ok((
+        manifest,
+        PayloadHeader {
+            major_version: version,
+            manifest_sig_len,
+        },
+    ))
+}
+
+// Total number of blocks in extents that all end within max_blocks
+fn count_blocks(extents: &[Extent], max_blocks: u64, allow_hole: bool) -> Option<u64> {
+    let mut total: u64 = 0;
+    for ext in extents {
+        let (Some(start), Some(num)) = (ext.start_block, ext.num_blocks) else {
+            return None;
+        };
+        if !(allow_hole && start == SPARSE_HOLE) && start.checked_add(num)? > max_blocks {
+            return None;
+        }
+        total = total.checked_add(num)?;
+    }
+    Some(total)
+}
+
+// Validate all sizes and extents up front, so that extraction can do block arithmetic
+// without overflowing and never allocates more than MAX_OPERATION_SIZE per operation
+fn check_manifest(manifest: &DeltaArchiveManifest) -> Result<(), PayloadError> {
     let block_size = manifest.block_size() as u64;
+    if !block_size.is_power_of_two() || !(512..=65536).contains(&block_size) {
+        return Err(bad_payload!("invalid block size {}", block_size));
+    }
+    if manifest.signatures_size() > MAX_SIGNATURE_SIZE {
+        return Err(bad_payload!(
+            "signatures size {} is too large",
+            manifest.signatures_size()
+        ));
+    }
 
-    let partition = match partition_name {
-        None => {
-            let boot = manifest
-                .partitions
-                .iter()
-                .find(|p| p.partition_name() == "init_boot");
-            let boot = match boot {
-                Some(boot) => Some(boot),
-                None => manifest
-                    .partitions
+    for partition in manifest.partitions.iter() {
+        let name = partition.partition_name();
+        let max_blocks = match partition.new_partition_info.as_ref() {
+            Some(info) if info.has_size() => info.size().div_ceil(block_size),
+            _ => u64::MAX / block_size,
+        };
+
+        let mut dst = Vec::new();
+        for (idx, op) in partition.operations.iter().enumerate() {
+            if op.data_length() > MAX_OPERATION_SIZE
+                // File offsets are signed
+                || op
+                    .data_offset()
+                    .checked_add(op.data_length())
+                    .filter(|end| *end <= i64::MAX as u64)
+                    .is_none()
+            {
+                return Err(bad_payload!(
+                    "invalid data of partition '{}' operation #{}",
+                    name,
+                    idx
+                ));
+            }
+            let src_blocks = count_blocks(&op.src_extents, u64::MAX / block_size, true)
+                .filter(|n| *n <= MAX_OPERATION_SIZE / block_size);
+            let dst_blocks = count_blocks(&op.dst_extents, max_blocks, false);
+            if src_blocks.is_none() || dst_blocks.is_none() {
+                return Err(bad_payload!(
+                    "invalid extents in partition '{}' operation #{}",
+                    name,
+                    idx
+                ));
+            }
+            dst.extend(
+                op.dst_extents
                     .iter()
-                    .find(|p| p.partition_name() == "boot"),
+                    .map(|e| (e.start_block(), e.num_blocks())),
+            );
+        }
+
+        // Every block is written by at most one operation, which parallel extraction relies on
+        dst.sort_unstable();
+        if dst.windows(2).any(|w| w[0].0 + w[0].1 > w[1].0) {
+            return Err(bad_payload!(
+                "overlapping dst extents in partition '{}'",
+                name
+            ));
+        }
+
+        let hash_tree = [
+            partition.hash_tree_data_extent.as_ref(),
+            partition.hash_tree_extent.as_ref(),
+        ];
+        for ext in hash_tree.into_iter().flatten() {
+            if count_blocks(std::slice::from_ref(ext), max_blocks, false).is_none() {
+                return Err(bad_payload!(
+                    "invalid hash tree extent in partition '{}'",
+                    name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Progress of PayloadExtractor::extract_partition, reported after each operation
+pub struct ExtractProgress {
+    pub completed_operations: usize,
+    pub total_operations: usize,
+    pub bytes_processed: u64,
+    pub total_bytes: u64,
+}
+
+// Where PayloadExtractor reads the data blobs of operations from
+trait DataSource {
+    fn read_data(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    // The digest of everything read so far, if the payload is hashed
+    fn digest(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // The payload file and the offset of its data, if it can be read positionally
+    fn file(&self) -> Option<(&File, u64)> {
+        None
+    }
+}
+
+// Streams can only be read forward
+struct StreamSource<R> {
+    reader: PayloadReader<R>,
+    pos: u64,
+}
+
+impl<R: Read> DataSource for StreamSource<R> {
+    fn read_data(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let skip = offset.checked_sub(self.pos).ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "payload data at offset {offset} was already read, \
+                 partitions have to be extracted in the order of their data"
+            ),
+        ))?;
+        self.reader.skip(skip as usize)?;
+        self.reader.read_exact(buf)?;
+        self.pos = offset + buf.len() as u64;
+        Ok(())
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        self.reader.digest()
+    }
+}
+
+struct SeekSource<R> {
+    reader: R,
+    data_start: u64,
+}
+
+impl<R: Read + Seek> DataSource for SeekSource<R> {
+    fn read_data(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.reader
+            .seek(SeekFrom::Start(self.data_start + offset))?;
+        self.reader.read_exact(buf)?;
+        Ok(())
+    }
+}
+
+struct FileSource {
+    file: File,
+    data_start: u64,
+}
+
+impl DataSource for FileSource {
+    fn read_data(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buf, self.data_start + offset)
+    }
+
+    fn file(&self) -> Option<(&File, u64)> {
+        Some((&self.file, self.data_start))
+    }
+}
+
+// Sort the install operations of all partitions with data_offset so we will only ever
+// need to seek forward. This makes it possible to support non-seekable input file
+// descriptors, and to extract multiple partitions with a single pass.
+fn sorted_operations<'a, S, T>(
+    writers: &[PartitionWriter<'a, S, T>],
+) -> Vec<(usize, usize, &'a InstallOperation)> {
+    let mut operations = Vec::new();
+    for (i, writer) in writers.iter().enumerate() {
+        for (idx, operation) in writer.partition.operations.iter().enumerate() {
+            operations.push((i, idx, operation));
+        }
+    }
+    operations.sort_by_key(|(_, _, op)| op.data_offset.unwrap_or(0));
+    operations
+}
+
+fn apply_operations<S: ImageSink, T: ImageSource>(
+    source: &mut dyn DataSource,
+    writers: &mut [PartitionWriter<S, T>],
+    mut progress: impl FnMut(&ExtractProgress),
+) -> Result<(), PayloadError> {
+    let operations = sorted_operations(writers);
+    let mut status = ExtractProgress {
+        completed_operations: 0,
+        total_operations: operations.len(),
+        bytes_processed: 0,
+        total_bytes: operations.iter().map(|(_, _, op)| op.data_length()).sum(),
+    };
+
+    let mut buf = Vec::new();
+    // Operations can share a data blob, which a stream can only provide once
+    let mut last_data = None;
+    for (i, idx, operation) in operations {
+        // SOURCE_COPY operations do not carry any data in the payload
+        let data_len = operation.data_length.unwrap_or(0) as usize;
+        if data_len != 0 {
+            let data_offset = operation
+                .data_offset
+                .ok_or(bad_payload!("data offset not found"))?;
+            if last_data != Some((data_offset, data_len)) {
+                buf.resize(data_len, 0u8);
+                source.read_data(&mut buf, data_offset)?;
+                last_data = Some((data_offset, data_len));
+            }
+        }
+        writers[i].apply_operation(idx, operation, &buf[..data_len])?;
+
+        status.completed_operations += 1;
+        status.bytes_processed += data_len as u64;
+        progress(&status);
+    }
+    Ok(())
+}
+
+// The payload signature covers everything before the signature blob
+fn verify_payload_signature(
+    source: &mut dyn DataSource,
+    manifest: &DeltaArchiveManifest,
+    keys: &[VerifyingKey],
+) -> Result<(), PayloadError> {
+    let sig_offset = manifest
+        .signatures_offset
+        .ok_or(PayloadError::Signature("payload is not signed"))?;
+    source.read_data(&mut [], sig_offset)?;
+    let digest = source.digest();
+    let mut sig = vec![0u8; manifest.signatures_size() as usize];
+    source.read_data(&mut sig, sig_offset)?;
+    if !verify_signatures(keys, &digest, &sig) {
+        return Err(PayloadError::Signature(
+            "payload signature verification failed",
+        ));
+    }
+    Ok(())
+}
+
+// Operations write to separate blocks, so with positional I/O they can be read,
+// decompressed and applied by multiple threads at the same time
+fn apply_operations_parallel(
+    payload: &File,
+    data_start: u64,
+    writers: &[PartitionWriter<&File, &File>],
+    threads: usize,
+) -> anyhow::Result<()> {
+    let operations = sorted_operations(writers);
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    let run = |buf: &mut Vec<u8>, i: usize, idx: usize, operation: &InstallOperation| {
+        let data_len = operation.data_length.unwrap_or(0) as usize;
+        buf.resize(data_len, 0u8);
+        if data_len != 0 {
+            let data_offset = operation
+                .data_offset
+                .ok_or(bad_payload!("data offset not found"))?;
+            payload
+                .read_exact_at(buf, data_start + data_offset)
+                .context("failed to read payload")?;
+        }
+        writers[i].clone().apply_operation(idx, operation, buf)?;
+        anyhow::Ok(())
+    };
+
+    let worker = || -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        while !failed.load(Ordering::Relaxed) {
+            let Some(&(i, idx, operation)) = operations.get(next.fetch_add(1, Ordering::Relaxed))
+            else {
+                break;
             };
-            boot.ok_or(anyhow!("boot partition not found"))?
+            if let Err(e) = run(&mut buf, i, idx, operation) {
+                failed.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(())
+    };
+
+    thread::scope(|s| {
+        let workers: Vec<_> = (0..threads).map(|_| s.spawn(worker)).collect();
+        workers.into_iter().try_for_each(|w| {
+            w.join()
+                .unwrap_or_else(|_| Err(anyhow!("extraction thread panicked")))
+        })
+    })
+}
+
+// Extracts partitions from a payload.bin provided by any reader
+pub struct PayloadExtractor<'a> {
+    manifest: DeltaArchiveManifest,
+    major_version: u64,
+    source: Box<dyn DataSource + 'a>,
+    verify: bool,
+}
+
+impl<'a> PayloadExtractor<'a> {
+    // For readers that can only be read forward, like pipes
+    pub fn from_reader<R: Read + 'a>(mut reader: R) -> Result<PayloadExtractor<'a>, PayloadError> {
+        let (manifest, header) = read_manifest(&mut reader, &PayloadProperties::default())?;
+        ReadExt::skip(&mut reader, header.manifest_sig_len as usize)?;
+        Ok(PayloadExtractor {
+            manifest,
+            major_version: header.major_version,
+            source: Box::new(StreamSource {
+                reader: PayloadReader {
+                    inner: reader,
+                    hasher: None,
+                },
+                pos: 0,
+            }),
+            verify: true,
+        })
+    }
+
+    // For seekable readers, partitions can be extracted in any order
+    pub fn from_seekable<R: Read + Seek + 'a>(
+        mut reader: R,
+    ) -> Result<PayloadExtractor<'a>, PayloadError> {
+        let (manifest, header) = read_manifest(&mut reader, &PayloadProperties::default())?;
+        let data_start = reader.seek(SeekFrom::Current(header.manifest_sig_len as i64))?;
+        Ok(PayloadExtractor {
+            manifest,
+            major_version: header.major_version,
+            source: Box::new(SeekSource { reader, data_start }),
+            verify: true,
+        })
+    }
+
+    // Open a payload.bin or an OTA zip, "-" is STDIN. When keys are given, the metadata
+    // signature is verified and the whole payload is hashed for the payload signature.
+    fn open(in_path: &str, keys: Option<&[VerifyingKey]>) -> anyhow::Result<PayloadExtractor<'a>> {
+        let (inner, props) = open_payload(in_path)?;
+        let mut reader = PayloadReader {
+            inner,
+            hasher: keys.map(|_| Sha256::new()),
+        };
+        let (manifest, header) = read_manifest(&mut reader, &props)?;
+
+        // The metadata signature covers the payload header and the manifest
+        let metadata_digest = reader.digest();
+        let mut metadata_sig = vec![0u8; header.manifest_sig_len as usize];
+        reader.read_exact(&mut metadata_sig)?;
+        // Major version 1 payloads only have the payload signature
+        if let Some(keys) = keys.filter(|_| header.major_version != CHROMEOS_MAJOR_VERSION) {
+            if !verify_signatures(keys, &metadata_digest, &metadata_sig) {
+                return Err(
+                    PayloadError::Signature("metadata signature verification failed").into(),
+                );
+            }
         }
-        Some(name) => manifest
+
+        // Signature verification hashes the whole payload, which has to be read sequentially
+        let source: Box<dyn DataSource> = if keys.is_some() || in_path == "-" {
+            Box::new(StreamSource { reader, pos: 0 })
+        } else {
+            let data_start = reader.inner.stream_position()?;
+            Box::new(FileSource {
+                file: reader.inner.into_inner(),
+                data_start,
+            })
+        };
+        Ok(PayloadExtractor {
+            manifest,
+            major_version: header.major_version,
+            source,
+            verify: true,
+        })
+    }
+
+    // Hash verification of data, source and output is enabled by default
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    // Major version 1 manifests are converted to the partitions of major version 2
+    pub fn manifest(&self) -> &DeltaArchiveManifest {
+        &self.manifest
+    }
+
+    pub fn major_version(&self) -> u64 {
+        self.major_version
+    }
+
+    pub fn partition_names(&self) -> Vec<&str> {
+        self.manifest
+            .partitions
+            .iter()
+            .map(|p| p.partition_name())
+            .collect()
+    }
+
+    // Extract a partition to out. Delta payloads require the source partition image.
+    // As out is not read back, only the data and source hashes are verified.
+    pub fn extract_partition<W: Write + Seek, S: Read + Seek>(
+        &mut self,
+        name: &str,
+        out: W,
+        src: Option<S>,
+        progress: impl FnMut(&ExtractProgress),
+    ) -> Result<(), PayloadError> {
+        let partition = self
+            .manifest
             .partitions
             .iter()
             .find(|p| p.partition_name() == name)
-            .ok_or(anyhow!("partition '{name}' not found"))?,
-    };
+            .ok_or_else(|| PayloadError::MissingPartition(name.to_owned()))?;
 
-    let out_str: String;
-    let out_path = match out_path {
-        None => {
-            out_str = format!("{}.img", partition.partition_name());
-            out_str.as_str()
+        let mut writer = PartitionWriter {
+            partition,
+            out: SeekSink {
+                inner: out,
+                size: partition
+                    .new_partition_info
+                    .as_ref()
+                    .map_or(u64::MAX, |info| info.size()),
+            },
+            src: src.map(SeekImage),
+            block_size: self.manifest.block_size() as u64,
+            verify: self.verify,
+        };
+
+        apply_operations(
+            self.source.as_mut(),
+            std::slice::from_mut(&mut writer),
+            progress,
+        )?;
+
+        // Blocks at the end might not be written by any operation
+        let info = &partition.new_partition_info;
+        if info.has_size() {
+            let out = &mut writer.out.inner;
+            let len = out.seek(SeekFrom::End(0))?;
+            if len < info.size() {
+                out.write_zeros((info.size() - len) as usize)?;
+            }
         }
-        Some(s) => s,
+        writer.out.inner.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct PartitionEntry<'a> {
+    name: &'a str,
+    size: Option<u64>,
+    hash: Option<String>,
+    operations: usize,
+    operation_types: Vec<String>,
+    filesystem_type: Option<&'a str>,
+    version: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct GroupEntry<'a> {
+    name: &'a str,
+    size: Option<u64>,
+    partitions: &'a [String],
+}
+
+#[derive(Serialize)]
+struct PayloadInfo<'a> {
+    major_version: u64,
+    minor_version: u32,
+    block_size: u32,
+    security_patch_level: Option<&'a str>,
+    partial_update: bool,
+    dynamic_partition_groups: Vec<GroupEntry<'a>>,
+    partitions: Vec<PartitionEntry<'a>>,
+}
+
+fn do_list_payload(in_path: &str, json: bool) -> anyhow::Result<()> {
+    let (mut reader, props) = open_payload(in_path)?;
+    let (manifest, header) = read_manifest(&mut reader, &props)?;
+
+    let mut partitions = Vec::new();
+    for p in manifest.partitions.iter() {
+        let mut operation_types = Vec::new();
+        for op in p.operations.iter() {
+            let name = match op.type_.map(|t| t.enum_value()) {
+                Some(Ok(t)) => t.descriptor().name().to_owned(),
+                _ => "UNKNOWN".to_owned(),
+            };
+            if !operation_types.contains(&name) {
+                operation_types.push(name);
+            }
+        }
+        let info = p.new_partition_info.as_ref();
+        partitions.push(PartitionEntry {
+            name: p.partition_name(),
+            size: info.and_then(|i| i.size),
+            hash: info.and_then(|i| i.hash.as_deref()).map(to_hex),
+            operations: p.operations.len(),
+            operation_types,
+            filesystem_type: p.filesystem_type.as_deref(),
+            version: p.version.as_deref(),
+        });
+    }
+
+    let dynamic_partition_groups = manifest
+        .dynamic_partition_metadata
+        .as_ref()
+        .map(|m| {
+            m.groups
+                .iter()
+                .map(|g| GroupEntry {
+                    name: g.name(),
+                    size: g.size,
+                    partitions: &g.partition_names,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let info = PayloadInfo {
+        major_version: header.major_version,
+        minor_version: manifest.minor_version(),
+        block_size: manifest.block_size(),
+        security_patch_level: manifest.security_patch_level.as_deref(),
+        partial_update: manifest.partial_update(),
+        dynamic_partition_groups,
+        partitions,
     };
 
-    let mut out_file =
-        File::create(out_path).with_context(|| format!("cannot write to '{out_path}'"))?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
 
-    // Skip the manifest signature
-    reader.skip(manifest_sig_len as usize)?;
+    println!("{:<15} [{}]", "MAJOR_VERSION", info.major_version);
+    println!("{:<15} [{}]", "MINOR_VERSION", info.minor_version);
+    println!("{:<15} [{}]", "BLOCK_SIZE", info.block_size);
+    if let Some(level) = info.security_patch_level {
+        println!("{:<15} [{}]", "PATCH_LEVEL", level);
+    }
+    for g in info.dynamic_partition_groups.iter() {
+        println!("{:<15} [{}]", "GROUP", g.name);
+        if let Some(size) = g.size {
+            println!("  {:<13} [{}]", "SIZE", size);
+        }
+        println!("  {:<13} [{}]", "PARTITIONS", g.partitions.join(" "));
+    }
+    for p in info.partitions.iter() {
+        println!("{:<15} [{}]", "PARTITION", p.name);
+        if let Some(size) = p.size {
+            println!("  {:<13} [{}]", "SIZE", size);
+        }
+        if let Some(hash) = &p.hash {
+            println!("  {:<13} [{}]", "SHA256", hash);
+        }
+        println!("  {:<13} [{}]", "OPERATIONS", p.operations);
+        println!("  {:<13} [{}]", "OP_TYPES", p.operation_types.join(" "));
+        if let Some(fs_type) = p.filesystem_type {
+            println!("  {:<13} [{}]", "FS_TYPE", fs_type);
+        }
+        if let Some(version) = p.version {
+            println!("  {:<13} [{}]", "FS_VERSION", version);
+        }
+    }
 
-    // Sort the install operations with data_offset so we will only ever need to seek forward
-    // This makes it possible to support non-seekable input file descriptors
-    let mut operations = partition.operations.clone();
-    operations.sort_by_key(|e| e.data_offset.unwrap_or(0));
-    let mut curr_data_offset: u64 = 0;
+    Ok(())
+}
 
-    for operation in operations.iter() {
-        let data_len = operation
-            .data_length
-            .ok_or(bad_payload!("data length not found"))? as usize;
+struct ExtractOptions<'a> {
+    src_path: Option<&'a str>,
+    key_path: Option<&'a str>,
+    verify: bool,
+    threads: usize,
+    sparse: bool,
+}
 
-        let data_offset = operation
-            .data_offset
-            .ok_or(bad_payload!("data offset not found"))?;
+fn do_extract_boot_from_payload(
+    in_path: &str,
+    partition_names: Option<&str>,
+    out_path: Option<&str>,
+    opts: &ExtractOptions,
+) -> anyhow::Result<()> {
+    let keys = opts.key_path.map(load_verifying_keys).transpose()?;
+    // The targets borrow the partitions of the manifest while the data is read from source
+    let PayloadExtractor {
+        manifest,
+        major_version,
+        mut source,
+        ..
+    } = PayloadExtractor::open(in_path, keys.as_deref())?;
+    eprintln!(
+        "{:<15} [{}.{}]",
+        "PAYLOAD_VERSION",
+        major_version,
+        manifest.minor_version()
+    );
 
-        let data_type = operation
-            .type_
-            .ok_or(bad_payload!("operation type not found"))?
-            .enum_value()
-            .map_err(|_| bad_payload!("operation type not valid"))?;
+    // Delta payloads are applied on top of the partition images the OTA was generated against
+    let is_delta = manifest.minor_version() != 0;
+    if is_delta && opts.src_path.is_none() {
+        return Err(PayloadError::DeltaPayload.into());
+    }
 
-        buf.resize(data_len, 0u8);
-        let data = &mut buf[..data_len];
-
-        // Skip to the next offset and read data
-        let skip = data_offset - curr_data_offset;
-        reader.skip(skip as usize)?;
-        reader.read_exact(data)?;
-        curr_data_offset = data_offset + data_len as u64;
-
-        let out_offset = operation
-            .dst_extents
-            .get(0)
-            .ok_or(bad_payload!("dst extents not found"))?
-            .start_block
-            .ok_or(bad_payload!("start block not found"))?
-            * block_size;
+    let block_size = manifest.block_size() as u64;
 
-        match data_type {
-            Type::REPLACE => {
-                out_file.seek(SeekFrom::Start(out_offset))?;
-                out_file.write_all(&data)?;
-            }
-            Type::ZERO => {
-                for ext in operation.dst_extents.iter() {
-                    let out_seek = ext
-                        .start_block
-                        .ok_or(bad_payload!("start block not found"))?
-                        * block_size;
-                    let num_blocks = ext.num_blocks.ok_or(bad_payload!("num blocks not found"))?;
-                    out_file.seek(SeekFrom::Start(out_seek))?;
-                    out_file.write_zeros(num_blocks as usize)?;
-                }
-            }
-            Type::REPLACE_BZ | Type::REPLACE_XZ => {
-                out_file.seek(SeekFrom::Start(out_offset))?;
-                if !ffi::decompress(data, out_file.as_raw_fd()) {
-                    return Err(bad_payload!("decompression failed"));
-                }
-            }
-            _ => {
-                return Err(bad_payload!(
-                    "unsupported operation type: {}",
-                    data_type.descriptor().name()
-                ));
+    let find_partition = |name: &str| {
+        manifest
+            .partitions
+            .iter()
+            .find(|p| p.partition_name() == name)
+    };
+
+    // Multiple partitions are specified as a comma separated list, or 'all'
+    let multiple = matches!(partition_names, Some(n) if n == "all" || n.contains(','));
+    let partitions = match partition_names {
+        None => {
+            let boot = find_partition("init_boot")
+                .or_else(|| find_partition("boot"))
+                .ok_or_else(|| PayloadError::MissingPartition("boot".to_owned()))?;
+            vec![boot]
+        }
+        Some("all") => manifest.partitions.iter().collect(),
+        Some(names) => names
+            .split(',')
+            .map(|name| {
+                find_partition(name).ok_or_else(|| PayloadError::MissingPartition(name.to_owned()))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+
+    if multiple {
+        let out_dir = out_path.unwrap_or(".");
+        create_dir_all(out_dir).with_context(|| format!("cannot create '{out_dir}'"))?;
+    }
+
+    let mut targets = Vec::new();
+    for partition in partitions {
+        let name = partition.partition_name();
+        // When extracting multiple partitions, out_path and src_path are directories
+        let (out, src) = if multiple {
+            let out_dir = out_path.unwrap_or(".");
+            let src = opts.src_path.map(|dir| format!("{dir}/{name}.img"));
+            (format!("{out_dir}/{name}.img"), src)
+        } else {
+            let out = out_path.map_or_else(|| format!("{name}.img"), str::to_owned);
+            (out, opts.src_path.map(str::to_owned))
+        };
+        let src_file = match src {
+            Some(path) if is_delta => {
+                Some(File::open(&path).with_context(|| format!("cannot open '{path}'"))?)
             }
+            _ => None,
         };
+        targets.push(ExtractTarget {
+            partition,
+            out_file: open_output(&out)?,
+            out_path: out,
+            src_file,
+        });
+    }
+    // Size the output beforehand, so that blocks not written by any operation are holes.
+    // This also allows operations to be applied in any order.
+    for target in targets.iter() {
+        target.set_size()?;
+    }
+
+    let mut writers: Vec<_> = targets
+        .iter()
+        .map(|target| target.writer(block_size, opts.verify))
+        .collect();
+    match source.file() {
+        Some((payload, data_start)) if opts.threads > 1 => {
+            apply_operations_parallel(payload, data_start, &writers, opts.threads)?
+        }
+        _ => apply_operations(source.as_mut(), &mut writers, |_| {})?,
+    }
+    drop(writers);
+
+    if let Some(keys) = &keys {
+        if let Err(e) = verify_payload_signature(source.as_mut(), &manifest, keys) {
+            // Do not leave any data from a tampered payload behind
+            for target in targets.iter() {
+                remove_file(&target.out_path).ok();
+            }
+            return Err(e.into());
+        }
+    }
+
+    for target in targets.iter() {
+        // The last block might be padded beyond the partition size
+        target.set_size()?;
+        target.write_hash_tree(block_size)?;
+    }
+
+    if opts.verify {
+        for target in targets.iter_mut() {
+            target.verify()?;
+            verify_footer(&target.out_file, target.name())?;
+        }
+    }
+
+    if opts.sparse {
+        for target in targets.iter() {
+            target.write_sparse(block_size)?;
+        }
     }
 
     Ok(())
 }
 
-pub fn extract_boot_from_payload(
-    in_path: *const c_char,
-    partition: *const c_char,
-    out_path: *const c_char,
-) -> bool {
-    fn inner(
-        in_path: *const c_char,
-        partition: *const c_char,
-        out_path: *const c_char,
-    ) -> anyhow::Result<()> {
-        let in_path = ptr_to_str_result(in_path)?;
-        let partition = match ptr_to_str_result(partition) {
-            Ok(s) => Some(s),
-            Err(StrErr::NullPointer) => None,
-            Err(e) => Err(e)?,
-        };
-        let out_path = match ptr_to_str_result(out_path) {
-            Ok(s) => Some(s),
-            Err(StrErr::NullPointer) => None,
-            Err(e) => Err(e)?,
+pub fn extract_boot_from_payload(argc: i32, argv: *const *const c_char) -> i32 {
+    fn inner(argc: i32, argv: *const *const c_char) -> anyhow::Result<()> {
+        let mut args = Vec::new();
+        for i in 0..argc as usize {
+            args.push(ptr_to_str_result(unsafe { *argv.add(i) })?);
+        }
+
+        let mut opts = ExtractOptions {
+            src_path: None,
+            key_path: None,
+            verify: true,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            sparse: false,
         };
-        do_extract_boot_from_payload(in_path, partition, out_path)
+        let mut list = false;
+        let mut json = false;
+        let mut pos_args = Vec::new();
+        let mut iter = args.into_iter();
+        while let Some(arg) = iter.next() {
+            match arg {
+                "-s" => {
+                    opts.src_path = Some(iter.next().ok_or(anyhow!("-s requires an argument"))?)
+                }
+                "-k" => {
+                    opts.key_path = Some(iter.next().ok_or(anyhow!("-k requires an argument"))?)
+                }
+                "-n" => opts.verify = false,
+                "-S" => opts.sparse = true,
+                "-t" => {
+                    let arg = iter.next().ok_or(anyhow!("-t requires an argument"))?;
+                    opts.threads = arg
+                        .parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or(anyhow!("invalid thread count '{arg}'"))?;
+                }
+                "-l" => list = true,
+                "-j" => json = true,
+                // A single '-' is STDIN
+                _ if arg.len() > 1 && arg.starts_with('-') => {
+                    return Err(anyhow!("unknown option '{arg}'"));
+                }
+                _ => pos_args.push(arg),
+            }
+        }
+
+        let in_path = *pos_args
+            .first()
+            .ok_or(anyhow!("payload.bin is not specified"))?;
+        if list {
+            return do_list_payload(in_path, json).context("Failed to list payload");
+        }
+        let partitions = pos_args.get(1).copied();
+        let out_path = pos_args.get(2).copied();
+        do_extract_boot_from_payload(in_path, partitions, out_path, &opts)
             .context("Failed to extract from payload")?;
         Ok(())
     }
-    inner(in_path, partition, out_path).log().is_ok()
+    match inner(argc, argv).log() {
+        Ok(_) => 0,
+        Err(e) => exit_code(&e),
+    }
 }
@@ -0,0 +1,181 @@
+use std::fs::{remove_file, File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, Context};
+use byteorder::{BigEndian, WriteBytesExt};
+use lzma_rust2::{XzOptions, XzWriter};
+use protobuf::{Message, MessageField};
+use sha2::{Digest, Sha256};
+
+use base::libc::c_char;
+use base::{ptr_to_str_result, ResultExt};
+
+use crate::payload::PAYLOAD_MAGIC;
+use crate::update_metadata::install_operation::Type;
+use crate::update_metadata::{
+    DeltaArchiveManifest, Extent, InstallOperation, PartitionInfo, PartitionUpdate,
+};
+
+const BLOCK_SIZE: u64 = 4096;
+// Same as the default chunk size of full payloads generated by delta_generator
+const CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+
+fn compress_xz(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut xz = XzWriter::new(Vec::new(), XzOptions::with_preset(6))?;
+    xz.write_all(data)?;
+    xz.finish()
+}
+
+// Split the image into chunks, and append the data of each operation to blobs
+fn create_partition<W: Write>(
+    name: &str,
+    image: &mut File,
+    blobs: &mut W,
+    blobs_len: &mut u64,
+    compress: bool,
+) -> anyhow::Result<PartitionUpdate> {
+    let size = image.metadata()?.len();
+    let mut partition = PartitionUpdate::new();
+    partition.set_partition_name(name.to_owned());
+
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE as usize];
+    let mut offset = 0;
+    image.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(image);
+    while offset < size {
+        let len = std::cmp::min(CHUNK_SIZE, size - offset) as usize;
+        reader.read_exact(&mut chunk[..len])?;
+        hasher.update(&chunk[..len]);
+
+        // The destination extents cover whole blocks, the last one is padded with zeros
+        let padded_len = (len as u64).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        chunk[len..padded_len as usize].fill(0);
+        let data = &chunk[..padded_len as usize];
+
+        let mut extent = Extent::new();
+        extent.set_start_block(offset / BLOCK_SIZE);
+        extent.set_num_blocks(padded_len / BLOCK_SIZE);
+        let mut operation = InstallOperation::new();
+        operation.dst_extents.push(extent);
+
+        if data.iter().all(|b| *b == 0) {
+            operation.set_type(Type::ZERO);
+        } else {
+            let xz = if compress {
+                Some(compress_xz(data)?)
+            } else {
+                None
+            };
+            let blob = match xz {
+                Some(ref xz) if xz.len() < data.len() => {
+                    operation.set_type(Type::REPLACE_XZ);
+                    xz.as_slice()
+                }
+                _ => {
+                    operation.set_type(Type::REPLACE);
+                    data
+                }
+            };
+            operation.set_data_offset(*blobs_len);
+            operation.set_data_length(blob.len() as u64);
+            operation.set_data_sha256_hash(Sha256::digest(blob).to_vec());
+            blobs.write_all(blob)?;
+            *blobs_len += blob.len() as u64;
+        }
+        partition.operations.push(operation);
+        offset += len as u64;
+    }
+
+    let mut info = PartitionInfo::new();
+    info.set_size(size);
+    info.set_hash(hasher.finalize().to_vec());
+    partition.new_partition_info = MessageField::some(info);
+    Ok(partition)
+}
+
+fn do_create_payload(
+    out_path: &str,
+    images: &[(&str, &str)],
+    compress: bool,
+) -> anyhow::Result<()> {
+    // The manifest has to be written before the data, so spool the data to a file first
+    let data_path = format!("{out_path}.data");
+    let mut data_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&data_path)
+        .with_context(|| format!("cannot write to '{data_path}'"))?;
+    remove_file(&data_path)?;
+
+    let mut manifest = DeltaArchiveManifest::new();
+    manifest.set_block_size(BLOCK_SIZE as u32);
+    // Full payloads have minor version 0
+    manifest.set_minor_version(0);
+
+    let mut blobs = BufWriter::new(&mut data_file);
+    let mut blobs_len = 0;
+    for (name, path) in images {
+        let mut image = File::open(path).with_context(|| format!("cannot open '{path}'"))?;
+        let partition = create_partition(name, &mut image, &mut blobs, &mut blobs_len, compress)
+            .with_context(|| format!("failed to add partition '{name}'"))?;
+        manifest.partitions.push(partition);
+    }
+    blobs.flush()?;
+    drop(blobs);
+
+    let manifest = manifest.write_to_bytes()?;
+    let mut out = BufWriter::new(
+        File::create(out_path).with_context(|| format!("cannot write to '{out_path}'"))?,
+    );
+    out.write_all(PAYLOAD_MAGIC.as_bytes())?;
+    out.write_u64::<BigEndian>(2)?;
+    out.write_u64::<BigEndian>(manifest.len() as u64)?;
+    // The payload is not signed
+    out.write_u32::<BigEndian>(0)?;
+    out.write_all(&manifest)?;
+    data_file.seek(SeekFrom::Start(0))?;
+    io::copy(&mut data_file, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+pub fn create_payload(argc: i32, argv: *const *const c_char) -> bool {
+    fn inner(argc: i32, argv: *const *const c_char) -> anyhow::Result<()> {
+        let mut args = Vec::new();
+        for i in 0..argc as usize {
+            args.push(ptr_to_str_result(unsafe { *argv.add(i) })?);
+        }
+
+        let mut compress = true;
+        let mut pos_args = Vec::new();
+        for arg in args {
+            match arg {
+                "-r" => compress = false,
+                _ if arg.starts_with('-') => return Err(anyhow!("unknown option '{arg}'")),
+                _ => pos_args.push(arg),
+            }
+        }
+
+        let (out_path, images) = pos_args
+            .split_first()
+            .ok_or(anyhow!("payload.bin is not specified"))?;
+        if images.is_empty() {
+            return Err(anyhow!("no partition images specified"));
+        }
+        let images = images
+            .iter()
+            .map(|arg| {
+                arg.split_once('=').ok_or(anyhow!(
+                    "invalid argument '{arg}', expected <partition>=<image>"
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        do_create_payload(out_path, &images, compress).context("Failed to create payload")
+    }
+    inner(argc, argv).log().is_ok()
+}
@@ -0,0 +1,781 @@
+use anyhow::anyhow;
+use byteorder::{BigEndian, ByteOrder};
+use protobuf::Message;
+
+use crate::bspatch::bspatch;
+use crate::puffin::patch_header::PatchType;
+use crate::puffin::{BitExtent, PatchHeader, StreamInfo};
+
+// Patch format produced by AOSP's puffin, used by PUFFDIFF payload operations
+// https://android.googlesource.com/platform/external/puffin/+/refs/heads/master/README.md
+//
+// The deflate streams of the source are converted into "puffs", a byte aligned encoding
+// of their Huffman symbols, and a bsdiff patch is applied to the puffed source. The deflate
+// streams of the destination are then re-encoded from the patched puffs.
+
+const PUFFIN_MAGIC: &[u8] = b"PUF1";
+const PATCH_VERSION: i32 = 1;
+
+// Puffs encode at most this many literals in a row
+const MAX_LITERALS: usize = (1 << 16) + 127;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order of the code length code lengths in dynamic block headers
+const PERMUTATIONS: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+macro_rules! bad_patch {
+    ($msg:literal) => {
+        anyhow!(concat!("invalid puffin patch: ", $msg))
+    };
+    ($($args:tt)*) => {
+        anyhow!("invalid puffin patch: {}", format_args!($($args)*))
+    };
+}
+
+macro_rules! bad_deflate {
+    ($msg:literal) => {
+        anyhow!(concat!("invalid deflate stream: ", $msg))
+    };
+}
+
+macro_rules! bad_puff {
+    ($msg:literal) => {
+        anyhow!(concat!("invalid puff stream: ", $msg))
+    };
+}
+
+// Deflate streams are read and written starting from the least significant bit of each byte
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    fn bits(&mut self, n: usize) -> anyhow::Result<u32> {
+        if n > self.remaining() {
+            return Err(bad_deflate!("stream is truncated"));
+        }
+        let mut val = 0u32;
+        let mut read = 0;
+        while read < n {
+            let shift = self.pos % 8;
+            let take = (8 - shift).min(n - read);
+            let byte = (self.data[self.pos / 8] >> shift) as u32;
+            val |= (byte & ((1 << take) - 1)) << read;
+            read += take;
+            self.pos += take;
+        }
+        Ok(val)
+    }
+
+    // The unused bits before the next byte boundary
+    fn boundary_bits(&mut self) -> anyhow::Result<u32> {
+        self.bits((8 - self.pos % 8) % 8)
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    pending: usize,
+    written: u64,
+}
+
+impl BitWriter {
+    // The first byte starts with the lowest n bits of val, which are not counted as written
+    fn new(val: u8, n: usize) -> BitWriter {
+        BitWriter {
+            out: Vec::new(),
+            acc: val as u32 & ((1 << n) - 1),
+            pending: n,
+            written: 0,
+        }
+    }
+
+    fn bits(&mut self, n: usize, val: u32) {
+        self.acc |= (val & ((1 << n) - 1)) << self.pending;
+        self.pending += n;
+        self.written += n as u64;
+        while self.pending >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.pending -= 8;
+        }
+    }
+
+    fn boundary_bits(&mut self, val: u32) {
+        self.bits((8 - self.pending) % 8, val);
+    }
+}
+
+// Canonical Huffman code built from its code lengths
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+    // Codes are stored bit reversed, ready to be written into the stream
+    codes: Vec<(u16, u8)>,
+}
+
+impl Huffman {
+    fn new(lens: &[u8]) -> anyhow::Result<Huffman> {
+        let mut counts = [0u16; 16];
+        for len in lens {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Incomplete codes are allowed, over-subscribed ones are not
+        let mut left = 1i32;
+        for count in &counts[1..] {
+            left = (left << 1) - *count as i32;
+            if left < 0 {
+                return Err(bad_deflate!("over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        let mut next_code = [0u16; 16];
+        for len in 1..16 {
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+            next_code[len] = (next_code[len - 1] + counts[len - 1]) << 1;
+        }
+        let mut symbols = vec![0; offsets[15] as usize + counts[15] as usize];
+        let mut codes = vec![(0, 0); lens.len()];
+        for (sym, len) in lens.iter().enumerate() {
+            let len = *len as usize;
+            if len != 0 {
+                symbols[offsets[len] as usize] = sym as u16;
+                offsets[len] += 1;
+                let code = next_code[len].reverse_bits() >> (16 - len);
+                codes[sym] = (code, len as u8);
+                next_code[len] += 1;
+            }
+        }
+        Ok(Huffman {
+            counts,
+            symbols,
+            codes,
+        })
+    }
+
+    fn fixed() -> (Huffman, Huffman) {
+        let mut lens = [8u8; 288];
+        lens[144..256].fill(9);
+        lens[256..280].fill(7);
+        // Both tables are complete, they cannot fail to build
+        (
+            Huffman::new(&lens).unwrap(),
+            Huffman::new(&[5u8; 30]).unwrap(),
+        )
+    }
+
+    fn decode(&self, br: &mut BitReader) -> anyhow::Result<usize> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for count in &self.counts[1..] {
+            code |= br.bits(1)? as i32;
+            let count = *count as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(bad_deflate!("invalid Huffman code"))
+    }
+
+    fn encode(&self, bw: &mut BitWriter, sym: usize) -> anyhow::Result<()> {
+        match self.codes.get(sym) {
+            Some((code, len)) if *len != 0 => {
+                bw.bits(*len as usize, *code as u32);
+                Ok(())
+            }
+            _ => Err(bad_puff!("symbol has no Huffman code")),
+        }
+    }
+}
+
+// Reads the code lengths of a dynamic block, the header is copied into meta as:
+// HLIT, HDIST, HCLEN, code length code lengths packed in nibbles, then one byte per
+// code length symbol (0-15: length, 16-19: repeat 3-6, 20-27: zero 3-10, 28-155: zero 11-138)
+fn read_dynamic_header(
+    br: &mut BitReader,
+    meta: &mut Vec<u8>,
+) -> anyhow::Result<(Huffman, Huffman)> {
+    let num_lit_len = br.bits(5)? as usize + 257;
+    let num_distance = br.bits(5)? as usize + 1;
+    let num_codes = br.bits(4)? as usize + 4;
+    if num_lit_len > 286 || num_distance > 30 {
+        return Err(bad_deflate!("too many Huffman codes"));
+    }
+    meta.extend([
+        (num_lit_len - 257) as u8,
+        (num_distance - 1) as u8,
+        (num_codes - 4) as u8,
+    ]);
+
+    let mut code_lens = [0u8; 19];
+    for idx in PERMUTATIONS.iter().take(num_codes) {
+        code_lens[*idx] = br.bits(3)? as u8;
+    }
+    for pair in PERMUTATIONS.chunks(2).take(num_codes.div_ceil(2)) {
+        let low = pair.get(1).map_or(0, |idx| code_lens[*idx]);
+        meta.push(code_lens[pair[0]] << 4 | low);
+    }
+    let code_huff = Huffman::new(&code_lens)?;
+
+    let mut lens = Vec::with_capacity(num_lit_len + num_distance);
+    while lens.len() < num_lit_len + num_distance {
+        let sym = code_huff.decode(br)?;
+        let (code, val, count) = match sym {
+            0..=15 => (sym as u32, sym as u8, 1),
+            16 => {
+                let prev = *lens.last().ok_or(bad_deflate!("repeat without a length"))?;
+                let extra = br.bits(2)?;
+                (16 + extra, prev, 3 + extra as usize)
+            }
+            17 => {
+                let extra = br.bits(3)?;
+                (20 + extra, 0, 3 + extra as usize)
+            }
+            _ => {
+                let extra = br.bits(7)?;
+                (28 + extra, 0, 11 + extra as usize)
+            }
+        };
+        meta.push(code as u8);
+        lens.extend(std::iter::repeat_n(val, count));
+    }
+    if lens.len() != num_lit_len + num_distance {
+        return Err(bad_deflate!("too many code lengths"));
+    }
+    Ok((
+        Huffman::new(&lens[..num_lit_len])?,
+        Huffman::new(&lens[num_lit_len..])?,
+    ))
+}
+
+// The reverse of read_dynamic_header
+fn write_dynamic_header(meta: &[u8], bw: &mut BitWriter) -> anyhow::Result<(Huffman, Huffman)> {
+    let [hlit, hdist, hclen, rest @ ..] = meta else {
+        return Err(bad_puff!("dynamic block header is truncated"));
+    };
+    if *hlit > 29 || *hdist > 29 || *hclen > 15 {
+        return Err(bad_puff!("too many Huffman codes"));
+    }
+    bw.bits(5, *hlit as u32);
+    bw.bits(5, *hdist as u32);
+    bw.bits(4, *hclen as u32);
+    let num_lit_len = *hlit as usize + 257;
+    let num_distance = *hdist as usize + 1;
+    let num_codes = *hclen as usize + 4;
+
+    let packed_len = num_codes.div_ceil(2);
+    if rest.len() < packed_len {
+        return Err(bad_puff!("dynamic block header is truncated"));
+    }
+    let mut code_lens = [0u8; 19];
+    for (i, idx) in PERMUTATIONS.iter().take(num_codes).enumerate() {
+        let len = if i % 2 == 0 {
+            rest[i / 2] >> 4
+        } else {
+            rest[i / 2] & 0xf
+        };
+        if len > 7 {
+            return Err(bad_puff!("invalid code length"));
+        }
+        code_lens[*idx] = len;
+        bw.bits(3, len as u32);
+    }
+    let code_huff = Huffman::new(&code_lens)?;
+
+    let mut lens = Vec::with_capacity(num_lit_len + num_distance);
+    for code in &rest[packed_len..] {
+        let code = *code as u32;
+        let (val, count) = match code {
+            0..=15 => {
+                code_huff.encode(bw, code as usize)?;
+                (code as u8, 1)
+            }
+            16..=19 => {
+                let prev = *lens.last().ok_or(bad_puff!("repeat without a length"))?;
+                code_huff.encode(bw, 16)?;
+                bw.bits(2, code - 16);
+                (prev, 3 + (code - 16) as usize)
+            }
+            20..=27 => {
+                code_huff.encode(bw, 17)?;
+                bw.bits(3, code - 20);
+                (0, 3 + (code - 20) as usize)
+            }
+            28..=155 => {
+                code_huff.encode(bw, 18)?;
+                bw.bits(7, code - 28);
+                (0, 11 + (code - 28) as usize)
+            }
+            _ => return Err(bad_puff!("invalid code length symbol")),
+        };
+        lens.extend(std::iter::repeat_n(val, count));
+    }
+    if lens.len() != num_lit_len + num_distance {
+        return Err(bad_puff!("code lengths do not match the header"));
+    }
+    Ok((
+        Huffman::new(&lens[..num_lit_len])?,
+        Huffman::new(&lens[num_lit_len..])?,
+    ))
+}
+
+// Puff encoding:
+// block metadata: u16 BE (length - 1), block header byte (BFINAL, BTYPE, stored block
+//                 padding bits), dynamic Huffman code lengths
+// literals:       0LLLLLLL (1-127 literals) or 0x7F + u16 BE (length - 128), the literals
+// length/dist:    1LLLLLLL (length 3-129) or 0xFF + (length - 130), u16 BE (distance - 1)
+// end of block:   0xFF 0x81, which is length 259 without a distance
+#[derive(Default)]
+struct PuffWriter {
+    out: Vec<u8>,
+    literals: Vec<u8>,
+}
+
+impl PuffWriter {
+    fn flush_literals(&mut self) {
+        let len = self.literals.len();
+        if len == 0 {
+            return;
+        }
+        if len <= 127 {
+            self.out.push((len - 1) as u8);
+        } else {
+            self.out.push(0x7f);
+            self.out.extend(((len - 128) as u16).to_be_bytes());
+        }
+        self.out.append(&mut self.literals);
+    }
+
+    fn literal(&mut self, byte: u8) {
+        self.literals.push(byte);
+        if self.literals.len() == MAX_LITERALS {
+            self.flush_literals();
+        }
+    }
+
+    fn len_dist(&mut self, len: usize, dist: usize) {
+        self.flush_literals();
+        if len < 130 {
+            self.out.push(0x80 | (len - 3) as u8);
+        } else {
+            self.out.extend([0xff, (len - 130) as u8]);
+        }
+        self.out.extend(((dist - 1) as u16).to_be_bytes());
+    }
+
+    fn end_of_block(&mut self) {
+        self.flush_literals();
+        self.out.extend([0xff, 0x81]);
+    }
+
+    fn metadata(&mut self, meta: &[u8]) {
+        self.flush_literals();
+        self.out.extend(((meta.len() - 1) as u16).to_be_bytes());
+        self.out.extend(meta);
+    }
+}
+
+enum PuffData<'a> {
+    Literals(&'a [u8]),
+    LenDist(usize, usize),
+    EndOfBlock,
+}
+
+struct PuffReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PuffReader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(bad_puff!("stream is truncated"))?;
+        let data = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(BigEndian::read_u16(self.take(2)?))
+    }
+
+    fn metadata(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u16()? as usize + 1;
+        self.take(len)
+    }
+
+    fn next(&mut self) -> anyhow::Result<PuffData<'a>> {
+        let b = self.u8()?;
+        if b & 0x80 == 0 {
+            let len = if b < 0x7f {
+                b as usize + 1
+            } else {
+                self.u16()? as usize + 128
+            };
+            return Ok(PuffData::Literals(self.take(len)?));
+        }
+        let len = if b < 0xff {
+            (b & 0x7f) as usize + 3
+        } else {
+            self.u8()? as usize + 130
+        };
+        match len {
+            259 => Ok(PuffData::EndOfBlock),
+            3..=258 => Ok(PuffData::LenDist(len, self.u16()? as usize + 1)),
+            _ => Err(bad_puff!("invalid length")),
+        }
+    }
+}
+
+// Returns the puff of all deflate blocks in data starting at bit start, and the end bit.
+// The puff is at most max_len bytes.
+fn puff_deflate(data: &[u8], start: usize, max_len: usize) -> anyhow::Result<(Vec<u8>, usize)> {
+    let mut br = BitReader { data, pos: start };
+    let mut pw = PuffWriter::default();
+
+    // The shortest possible block is longer than a byte
+    while br.remaining() >= 8 {
+        let final_bit = br.bits(1)?;
+        let block_type = br.bits(2)?;
+        let header = (final_bit << 7 | block_type << 5) as u8;
+        let (lit_len, distance) = match block_type {
+            0 => {
+                let padding = br.boundary_bits()?;
+                let len = br.bits(16)?;
+                let nlen = br.bits(16)?;
+                if len ^ nlen != 0xffff {
+                    return Err(bad_deflate!("invalid stored block length"));
+                }
+                pw.metadata(&[header | padding as u8]);
+                let start = br.pos / 8;
+                let end = start + len as usize;
+                if end > data.len() {
+                    return Err(bad_deflate!("stream is truncated"));
+                }
+                data[start..end].iter().for_each(|b| pw.literal(*b));
+                br.pos = end * 8;
+                pw.end_of_block();
+                continue;
+            }
+            1 => {
+                pw.metadata(&[header]);
+                Huffman::fixed()
+            }
+            2 => {
+                let mut meta = vec![header];
+                let tables = read_dynamic_header(&mut br, &mut meta)?;
+                pw.metadata(&meta);
+                tables
+            }
+            _ => return Err(bad_deflate!("invalid block type")),
+        };
+
+        loop {
+            if pw.out.len() > max_len {
+                return Err(bad_patch!("puff is too large"));
+            }
+            let sym = lit_len.decode(&mut br)?;
+            match sym {
+                0..=255 => pw.literal(sym as u8),
+                256 => break,
+                _ => {
+                    let idx = sym - 257;
+                    if idx >= LENGTH_BASES.len() {
+                        return Err(bad_deflate!("invalid length symbol"));
+                    }
+                    let len = LENGTH_BASES[idx] as usize
+                        + br.bits(LENGTH_EXTRA_BITS[idx] as usize)? as usize;
+                    let idx = distance.decode(&mut br)?;
+                    if idx >= DISTANCE_BASES.len() {
+                        return Err(bad_deflate!("invalid distance symbol"));
+                    }
+                    let dist = DISTANCE_BASES[idx] as usize
+                        + br.bits(DISTANCE_EXTRA_BITS[idx] as usize)? as usize;
+                    pw.len_dist(len, dist);
+                }
+            }
+        }
+        pw.end_of_block();
+    }
+    pw.flush_literals();
+    Ok((pw.out, br.pos))
+}
+
+// The reverse of puff_deflate
+fn huff_deflate(puff: &[u8], bw: &mut BitWriter) -> anyhow::Result<()> {
+    let mut pr = PuffReader { data: puff, pos: 0 };
+    while pr.pos < puff.len() {
+        let meta = pr.metadata()?;
+        let header = meta[0];
+        bw.bits(1, (header >> 7) as u32);
+        bw.bits(2, (header >> 5 & 3) as u32);
+        let (lit_len, distance) = match header >> 5 & 3 {
+            0 => {
+                bw.boundary_bits((header & 0x1f) as u32);
+                match pr.next()? {
+                    PuffData::Literals(data) if data.len() <= 0xffff => {
+                        bw.bits(16, data.len() as u32);
+                        bw.bits(16, !data.len() as u32);
+                        data.iter().for_each(|b| bw.bits(8, *b as u32));
+                        if !matches!(pr.next()?, PuffData::EndOfBlock) {
+                            return Err(bad_puff!("stored block did not end properly"));
+                        }
+                    }
+                    PuffData::EndOfBlock => {
+                        bw.bits(16, 0);
+                        bw.bits(16, 0xffff);
+                    }
+                    _ => return Err(bad_puff!("stored block did not end properly")),
+                }
+                continue;
+            }
+            1 => Huffman::fixed(),
+            2 => write_dynamic_header(&meta[1..], bw)?,
+            _ => return Err(bad_puff!("invalid block type")),
+        };
+
+        loop {
+            match pr.next()? {
+                PuffData::Literals(data) => {
+                    for b in data {
+                        lit_len.encode(bw, *b as usize)?;
+                    }
+                }
+                PuffData::LenDist(len, dist) => {
+                    let idx = LENGTH_BASES.partition_point(|base| *base as usize <= len) - 1;
+                    lit_len.encode(bw, idx + 257)?;
+                    bw.bits(
+                        LENGTH_EXTRA_BITS[idx] as usize,
+                        (len - LENGTH_BASES[idx] as usize) as u32,
+                    );
+                    if dist > 32768 {
+                        return Err(bad_puff!("invalid distance"));
+                    }
+                    let idx = DISTANCE_BASES.partition_point(|base| *base as usize <= dist) - 1;
+                    distance.encode(bw, idx)?;
+                    bw.bits(
+                        DISTANCE_EXTRA_BITS[idx] as usize,
+                        (dist - DISTANCE_BASES[idx] as usize) as u32,
+                    );
+                }
+                PuffData::EndOfBlock => {
+                    lit_len.encode(bw, 256)?;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Number of raw bytes in the puff stream between a deflate ending at bit end and the next
+// one starting at bit start. Bytes shared with deflates are included, with the deflate bits
+// removed, unless the deflates are back to back.
+fn raw_len(end: u64, start: u64) -> u64 {
+    if end == start {
+        0
+    } else {
+        start.div_ceil(8) - end / 8
+    }
+}
+
+fn check_extents(deflates: &[BitExtent], puffs: &[BitExtent]) -> anyhow::Result<()> {
+    if deflates.len() != puffs.len() {
+        return Err(bad_patch!("deflate and puff extents do not match"));
+    }
+    let mut end = 0u64;
+    for deflate in deflates {
+        if deflate.offset < end {
+            return Err(bad_patch!("deflate extents overlap"));
+        }
+        end = deflate
+            .offset
+            .checked_add(deflate.length)
+            .ok_or(bad_patch!("invalid deflate extent"))?;
+    }
+    Ok(())
+}
+
+fn puff_extent(ext: &BitExtent, len: usize) -> anyhow::Result<(usize, usize)> {
+    ext.offset
+        .checked_add(ext.length)
+        .filter(|end| *end <= len as u64)
+        .map(|end| (ext.offset as usize, end as usize))
+        .ok_or(bad_patch!("puff extent is out of bounds"))
+}
+
+// Converts the deflates of data into puffs
+fn puff_stream(data: &[u8], info: &StreamInfo) -> anyhow::Result<Vec<u8>> {
+    check_extents(&info.deflates, &info.puffs)?;
+    let mut puff = Vec::new();
+
+    // Bits of the bytes shared with deflates are masked off before and shifted out after them
+    let copy_raw = |puff: &mut Vec<u8>, end: u64, start: u64| {
+        let first = (end / 8) as usize;
+        let last = (start.div_ceil(8) - 1) as usize;
+        let start_byte = puff.len();
+        puff.extend(&data[first..=last]);
+        if !start.is_multiple_of(8) {
+            *puff.last_mut().unwrap() &= (1 << (start % 8)) - 1;
+        }
+        puff[start_byte] >>= end % 8;
+    };
+
+    let mut end = 0u64;
+    for (deflate, ext) in info.deflates.iter().zip(&info.puffs) {
+        let start = deflate.offset;
+        let stop = start + deflate.length;
+        if stop.div_ceil(8) > data.len() as u64 {
+            return Err(bad_patch!("deflate extent is out of bounds"));
+        }
+        if raw_len(end, start) > 0 {
+            copy_raw(&mut puff, end, start);
+        }
+        if puff.len() as u64 != ext.offset || ext.offset > info.puff_length {
+            return Err(bad_patch!("puff extents do not match the deflates"));
+        }
+
+        let first = (start / 8) as usize;
+        let last = stop.div_ceil(8) as usize;
+        let max_len = (info.puff_length - puff.len() as u64) as usize;
+        let (deflate_puff, pos) = puff_deflate(&data[first..last], (start % 8) as usize, max_len)?;
+        if pos.div_ceil(8) != last - first || deflate_puff.len() as u64 != ext.length {
+            return Err(bad_patch!("deflate extent does not match its puff"));
+        }
+        puff.extend(deflate_puff);
+        end = stop;
+    }
+    let size = data.len() as u64 * 8;
+    if raw_len(end, size) > 0 {
+        copy_raw(&mut puff, end, size);
+    }
+
+    if puff.len() as u64 != info.puff_length {
+        return Err(bad_patch!("source puff size mismatch"));
+    }
+    Ok(puff)
+}
+
+// Converts the puffs of puff back into deflates
+fn huff_stream(puff: &[u8], info: &StreamInfo) -> anyhow::Result<Vec<u8>> {
+    check_extents(&info.deflates, &info.puffs)?;
+    let mut data = Vec::new();
+
+    // Bits of the last byte written by the previous deflate
+    let mut tail = 0u8;
+    let mut end = 0u64;
+    let mut pos = 0usize;
+    for (deflate, ext) in info.deflates.iter().zip(&info.puffs) {
+        let start = deflate.offset;
+        let (puff_start, puff_end) = puff_extent(ext, puff.len())?;
+        if (pos as u64).checked_add(raw_len(end, start)) != Some(puff_start as u64) {
+            return Err(bad_patch!("puff extents do not match the deflates"));
+        }
+
+        // Bits of the first byte that come before the deflate
+        let mut head = tail;
+        let raw = &puff[pos..puff_start];
+        for (i, b) in raw.iter().enumerate() {
+            let mut b = *b;
+            if i == 0 && !end.is_multiple_of(8) {
+                b = b << (end % 8) | tail;
+            }
+            if i == raw.len() - 1 && !start.is_multiple_of(8) {
+                head = b;
+            } else {
+                data.push(b);
+            }
+        }
+
+        let mut bw = BitWriter::new(head, (start % 8) as usize);
+        huff_deflate(&puff[puff_start..puff_end], &mut bw)?;
+        if bw.written != deflate.length {
+            return Err(bad_patch!("deflate size mismatch"));
+        }
+        data.extend(bw.out);
+        tail = bw.acc as u8;
+        end = start + deflate.length;
+        pos = puff_end;
+    }
+
+    let raw = &puff[pos..];
+    if !end.is_multiple_of(8) {
+        let (first, rest) = raw
+            .split_first()
+            .ok_or(bad_patch!("puff stream is truncated"))?;
+        data.push(first << (end % 8) | tail);
+        data.extend(rest);
+    } else {
+        data.extend(raw);
+    }
+    Ok(data)
+}
+
+// The patched stream is at most max_size bytes, which bounds the buffers allocated for it
+pub fn puffpatch(src: &[u8], patch: &[u8], max_size: u64) -> anyhow::Result<Vec<u8>> {
+    if patch.len() < 8 || !patch.starts_with(PUFFIN_MAGIC) {
+        return Err(bad_patch!("invalid magic"));
+    }
+    let header_size = BigEndian::read_u32(&patch[4..8]) as usize;
+    let header_end = 8usize
+        .checked_add(header_size)
+        .filter(|end| *end <= patch.len())
+        .ok_or(bad_patch!("header is truncated"))?;
+    let header = PatchHeader::parse_from_bytes(&patch[8..header_end])?;
+    if header.version != PATCH_VERSION {
+        return Err(bad_patch!("unsupported version {}", header.version));
+    }
+    match header.type_.enum_value() {
+        Ok(PatchType::BSDIFF) => {}
+        Ok(PatchType::ZUCCHINI) => return Err(bad_patch!("zucchini patches are not supported")),
+        Err(ty) => return Err(bad_patch!("unknown patch type {}", ty)),
+    }
+    for info in [&header.src, &header.dst] {
+        if info.puff_length > max_size {
+            return Err(bad_patch!("puff size {} is too large", info.puff_length));
+        }
+    }
+
+    let src_puff = puff_stream(src, &header.src)?;
+    let dst_puff = bspatch(&src_puff, &patch[header_end..], header.dst.puff_length)?;
+    if dst_puff.len() as u64 != header.dst.puff_length {
+        return Err(bad_patch!("destination puff size mismatch"));
+    }
+    huff_stream(&dst_puff, &header.dst)
+}
@@ -1,3 +1,5 @@
+#include <set>
+
 #include <base.hpp>
 
 #include "cpio.hpp"
@@ -21,8 +23,28 @@ public:
     char *sha1();
     void restore();
     void backup(const char *orig);
+    void diff(const char *orig);
+    void merge(const char *stock, const char *base);
 };
 
+static bool entry_eq(const cpio_entry *a, const cpio_entry *b) {
+    if (a == nullptr || b == nullptr)
+        return a == b;
+    return a->mode == b->mode && a->uid == b->uid && a->gid == b->gid &&
+           a->filesize == b->filesize && memcmp(a->data, b->data, a->filesize) == 0;
+}
+
+static cpio_entry *clone_entry(const cpio_entry *e) {
+    auto c = new cpio_entry(e->mode);
+    c->uid = e->uid;
+    c->gid = e->gid;
+    c->mtime = e->mtime;
+    c->filesize = e->filesize;
+    c->data = malloc(e->filesize);
+    memcpy(c->data, e->data, e->filesize);
+    return c;
+}
+
 bool check_env(const char *name) {
     const char *val = getenv(name);
     return val != nullptr && val == "true"sv;
@@ -230,6 +252,284 @@ void magisk_cpio::backup(const char *orig) {
         entries.merge(backups);
 }
 
+void magisk_cpio::diff(const char *orig) {
+    magisk_cpio o;
+    o.load_cpio(orig);
+
+    auto lhs = o.entries.begin();
+    auto rhs = entries.begin();
+
+    while (lhs != o.entries.end() || rhs != entries.end()) {
+        int res;
+        if (lhs != o.entries.end() && rhs != entries.end()) {
+            res = lhs->first.compare(rhs->first);
+        } else if (lhs == o.entries.end()) {
+            res = 1;
+        } else {
+            res = -1;
+        }
+
+        if (res < 0) {
+            printf("removed [%s]\n", lhs->first.data());
+            ++lhs;
+        } else if (res > 0) {
+            printf("added   [%s]\n", rhs->first.data());
+            ++rhs;
+        } else {
+            auto &a = lhs->second;
+            auto &b = rhs->second;
+            if ((a->mode & S_IFMT) != (b->mode & S_IFMT) || a->filesize != b->filesize ||
+                memcmp(a->data, b->data, a->filesize) != 0) {
+                printf("changed [%s]\n", rhs->first.data());
+            }
+            if ((a->mode & 07777) != (b->mode & 07777)) {
+                printf("mode    [%s] (%04o) -> (%04o)\n", rhs->first.data(),
+                       a->mode & 07777, b->mode & 07777);
+            }
+            if (a->uid != b->uid || a->gid != b->gid) {
+                printf("owner   [%s] (%u:%u) -> (%u:%u)\n", rhs->first.data(),
+                       a->uid, a->gid, b->uid, b->gid);
+            }
+            ++lhs; ++rhs;
+        }
+    }
+}
+
+void magisk_cpio::merge(const char *stock, const char *base) {
+    // Without an explicit base, the stock ramdisk our changes were made against is
+    // recovered from the backups, the same way as when restoring the ramdisk
+    magisk_cpio b;
+    if (base) {
+        b.load_cpio(base);
+    } else {
+        for (auto &e : entries)
+            b.entries.emplace(e.first, clone_entry(e.second.get()));
+        b.restore();
+    }
+
+    // The Magisk config is not part of the ramdisk diff, keep it as is
+    bool has_backup = exists(".backup");
+    unique_ptr<cpio_entry> config;
+    if (auto it = entries.find(".backup/.magisk"); it != entries.end())
+        config = std::move(it->second);
+    rm(".backup", true);
+    b.rm(".backup", true);
+
+    magisk_cpio n;
+    n.load_cpio(stock);
+    n.rm(".backup", true);
+
+    set<string> names;
+    for (auto &e : b.entries)
+        names.insert(e.first);
+    for (auto &e : entries)
+        names.insert(e.first);
+
+    for (auto &name : names) {
+        auto bi = b.entries.find(name);
+        auto oi = entries.find(name);
+        auto ni = n.entries.find(name);
+        auto be = bi == b.entries.end() ? nullptr : bi->second.get();
+        auto oe = oi == entries.end() ? nullptr : oi->second.get();
+        auto ne = ni == n.entries.end() ? nullptr : ni->second.get();
+
+        // Not customized by us
+        if (entry_eq(oe, be))
+            continue;
+        if (!entry_eq(ne, be) && !entry_eq(ne, oe))
+            fprintf(stderr, "Conflict [%s]: keep our version\n", name.data());
+
+        if (oe) {
+            fprintf(stderr, "Merge [%s]\n", name.data());
+            n.entries.insert_or_assign(name, std::move(oi->second));
+        } else if (ne) {
+            fprintf(stderr, "Merge removal [%s]\n", name.data());
+            n.entries.erase(ni);
+        }
+    }
+    entries.swap(n.entries);
+
+    // Create backups of the new stock ramdisk, so that it can be restored again
+    if (has_backup) {
+        backup(stock);
+        if (!exists(".backup"))
+            entries.emplace(".backup", new cpio_entry(S_IFDIR));
+        if (config)
+            entries.insert_or_assign(".backup/.magisk", std::move(config));
+    }
+}
+
+static bool parse_mode(const string &s, mode_t &mode) {
+    char *end;
+    errno = 0;
+    unsigned long v = strtoul(s.data(), &end, 8);
+    if (s.empty() || *end != '\0' || errno || v > 07777)
+        return false;
+    mode = v;
+    return true;
+}
+
+static bool parse_owner(const string &s, uid_t &uid, gid_t &gid) {
+    auto sep = s.find(':');
+    if (sep == string::npos)
+        return false;
+    int u = parse_int(string_view(s).substr(0, sep));
+    int g = parse_int(string_view(s).substr(sep + 1));
+    if (u < 0 || g < 0)
+        return false;
+    uid = u;
+    gid = g;
+    return true;
+}
+
+// Arguments are separated by spaces or tabs, and can be quoted with single or double
+// quotes. Outside of single quotes, a backslash escapes the next character.
+static bool split_script_line(string_view line, vector<string> &args) {
+    string arg;
+    bool in_arg = false;
+    char quote = 0;
+    for (size_t i = 0; i < line.size(); ++i) {
+        char c = line[i];
+        if (c == '\\' && quote != '\'' && i + 1 < line.size()) {
+            in_arg = true;
+            arg += line[++i];
+        } else if (quote) {
+            if (c == quote)
+                quote = 0;
+            else
+                arg += c;
+        } else if (c == ' ' || c == '\t') {
+            if (in_arg)
+                args.emplace_back(std::move(arg));
+            arg.clear();
+            in_arg = false;
+        } else {
+            in_arg = true;
+            if (c == '\'' || c == '"')
+                quote = c;
+            else
+                arg += c;
+        }
+    }
+    if (in_arg)
+        args.emplace_back(std::move(arg));
+    return quote == 0;
+}
+
+// Check the syntax of a script command, and that the files it reads exist
+static bool check_script_cmd(const vector<string> &cmd) {
+    mode_t mode;
+    uid_t uid;
+    gid_t gid;
+    if (cmd.empty())
+        return false;
+    auto &op = cmd[0];
+    auto n = cmd.size();
+    if (op == "add")
+        return n == 4 && parse_mode(cmd[1], mode) && access(cmd[3].data(), R_OK) == 0;
+    if (op == "rm")
+        return n == 2 || (n == 3 && cmd[1] == "-r");
+    if (op == "mv" || op == "ln")
+        return n == 3;
+    if (op == "mkdir" || op == "chmod")
+        return n == 3 && parse_mode(cmd[1], mode);
+    if (op == "chown")
+        return n == 3 && parse_owner(cmd[1], uid, gid);
+    if (op == "touch")
+        return n == 2 || (n == 4 && cmd[1] == "-t" && parse_int(cmd[2]) >= 0);
+    return false;
+}
+
+static bool apply_script_cmd(magisk_cpio &cpio, const vector<string> &cmd) {
+    mode_t mode = 0;
+    uid_t uid = 0;
+    gid_t gid = 0;
+    auto &op = cmd[0];
+    if (op == "add") {
+        parse_mode(cmd[1], mode);
+        cpio.add(mode, cmd[2].data(), cmd[3].data());
+    } else if (op == "rm") {
+        bool r = cmd.size() == 3;
+        cpio.rm(cmd[1 + r].data(), r);
+    } else if (op == "mv") {
+        return cpio.mv(cmd[1].data(), cmd[2].data());
+    } else if (op == "ln") {
+        cpio.ln(cmd[1].data(), cmd[2].data());
+    } else if (op == "mkdir") {
+        parse_mode(cmd[1], mode);
+        cpio.mkdir(mode, cmd[2].data());
+    } else if (op == "chmod") {
+        parse_mode(cmd[1], mode);
+        return cpio.chmod(mode, cmd[2].data());
+    } else if (op == "chown") {
+        parse_owner(cmd[1], uid, gid);
+        return cpio.chown(uid, gid, cmd[2].data());
+    } else if (op == "touch") {
+        time_t mtime = cmd.size() == 4 ? parse_int(cmd[2]) : time(nullptr);
+        cpio.touch(cmd.back().data(), mtime);
+    }
+    return true;
+}
+
+// All commands are checked before any of them is applied, and incpio is only
+// replaced after all of them succeeded, so that it is never left half modified
+static int cpio_script(magisk_cpio &cpio, const char *incpio, const char *script) {
+    if (access(script, R_OK) != 0) {
+        fprintf(stderr, "Cannot open script [%s]\n", script);
+        exit(1);
+    }
+
+    struct script_cmd {
+        int line_no;
+        string line;
+        vector<string> args;
+    };
+    vector<script_cmd> cmds;
+    int line_no = 0;
+    file_readline(true, script, [&](string_view line) -> bool {
+        ++line_no;
+        if (line.empty() || line[0] == '#')
+            return true;
+        vector<string> cmd;
+        bool valid = split_script_line(line, cmd);
+        if (valid && cmd.empty())
+            return true;
+        // Unterminated quotes are reported as invalid commands
+        if (!valid)
+            cmd.clear();
+        cmds.push_back({line_no, string(line), std::move(cmd)});
+        return true;
+    });
+
+    bool ok = true;
+    for (auto &cmd : cmds) {
+        if (!check_script_cmd(cmd.args)) {
+            fprintf(stderr, "%s:%d: invalid command [%s]\n",
+                    script, cmd.line_no, cmd.line.data());
+            ok = false;
+        }
+    }
+    if (ok) {
+        for (auto &cmd : cmds) {
+            if (!apply_script_cmd(cpio, cmd.args)) {
+                fprintf(stderr, "%s:%d: command failed [%s]\n",
+                        script, cmd.line_no, cmd.line.data());
+                ok = false;
+                break;
+            }
+        }
+    }
+    if (!ok) {
+        fprintf(stderr, "No changes written to [%s]\n", incpio);
+        exit(1);
+    }
+
+    string tmp = string(incpio) + ".tmp";
+    cpio.dump(tmp.data());
+    xrename(tmp.data(), incpio);
+    return 0;
+}
+
 int cpio_commands(int argc, char *argv[]) {
     char *incpio = argv[0];
     ++argv;
@@ -239,6 +539,9 @@ int cpio_commands(int argc, char *argv[]) {
     if (access(incpio, R_OK) == 0)
         cpio.load_cpio(incpio);
 
+    if (argc == 2 && argv[0] == "-f"sv)
+        return cpio_script(cpio, incpio, argv[1]);
+
     int cmdc;
     char *cmdv[6];
 
@@ -273,6 +576,11 @@ int cpio_commands(int argc, char *argv[]) {
             exit(!cpio.exists(cmdv[1]));
         } else if (cmdc == 2 && cmdv[0] == "backup"sv) {
             cpio.backup(cmdv[1]);
+        } else if (cmdc == 2 && cmdv[0] == "diff"sv) {
+            cpio.diff(cmdv[1]);
+            return 0;
+        } else if ((cmdc == 2 || cmdc == 3) && cmdv[0] == "merge"sv) {
+            cpio.merge(cmdv[1], cmdc == 3 ? cmdv[2] : nullptr);
         } else if (cmdc >= 2 && cmdv[0] == "rm"sv) {
             bool r = cmdc > 2 && cmdv[1] == "-r"sv;
             cpio.rm(cmdv[1 + r], r);
@@ -286,11 +594,40 @@ int cpio_commands(int argc, char *argv[]) {
                 return 0;
             }
         } else if (cmdc == 3 && cmdv[0] == "mkdir"sv) {
-            cpio.mkdir(strtoul(cmdv[1], nullptr, 8), cmdv[2]);
+            mode_t mode;
+            if (!parse_mode(cmdv[1], mode))
+                return 1;
+            cpio.mkdir(mode, cmdv[2]);
+        } else if (cmdc == 3 && cmdv[0] == "chmod"sv) {
+            mode_t mode;
+            if (!parse_mode(cmdv[1], mode))
+                return 1;
+            cpio.chmod(mode, cmdv[2]);
+        } else if (cmdc == 3 && cmdv[0] == "chown"sv) {
+            uid_t uid;
+            gid_t gid;
+            if (!parse_owner(cmdv[1], uid, gid))
+                return 1;
+            cpio.chown(uid, gid, cmdv[2]);
+        } else if (cmdv[0] == "touch"sv) {
+            if (cmdc == 2) {
+                cpio.touch(cmdv[1], time(nullptr));
+            } else if (cmdc == 4 && cmdv[1] == "-t"sv && parse_int(cmdv[2]) >= 0) {
+                cpio.touch(cmdv[3], parse_int(cmdv[2]));
+            } else {
+                return 1;
+            }
+        } else if (cmdv[0] == "ls"sv) {
+            bool l = cmdc > 1 && cmdv[1] == "-l"sv;
+            cpio.ls(cmdc > 1 + l ? cmdv[1 + l] : nullptr, l);
+            return 0;
         } else if (cmdc == 3 && cmdv[0] == "ln"sv) {
             cpio.ln(cmdv[1], cmdv[2]);
         } else if (cmdc == 4 && cmdv[0] == "add"sv) {
-            cpio.add(strtoul(cmdv[1], nullptr, 8), cmdv[2], cmdv[3]);
+            mode_t mode;
+            if (!parse_mode(cmdv[1], mode))
+                return 1;
+            cpio.add(mode, cmdv[2], cmdv[3]);
         } else {
             return 1;
         }
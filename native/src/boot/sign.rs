@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::Read;
+
+use anyhow::{anyhow, Context};
+use der::{Decode, Encode};
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha2::Sha256;
+use x509_cert::Certificate;
+
+pub enum VerifyingKey {
+    Rsa(RsaPublicKey),
+    Ecdsa(p256::ecdsa::VerifyingKey),
+}
+
+impl VerifyingKey {
+    fn from_spki_der(der: &[u8]) -> anyhow::Result<VerifyingKey> {
+        if let Ok(key) = RsaPublicKey::from_public_key_der(der) {
+            Ok(VerifyingKey::Rsa(key))
+        } else if let Ok(key) = p256::ecdsa::VerifyingKey::from_public_key_der(der) {
+            Ok(VerifyingKey::Ecdsa(key))
+        } else {
+            Err(anyhow!("unsupported public key type"))
+        }
+    }
+
+    // Accepts both X.509 certificates and public keys, PEM or DER encoded
+    fn from_bytes(data: &[u8]) -> anyhow::Result<VerifyingKey> {
+        let (label, der) = match der::pem::decode_vec(data) {
+            Ok((label, der)) => (label, der),
+            Err(_) => ("", data.to_vec()),
+        };
+        match label {
+            "PUBLIC KEY" => VerifyingKey::from_spki_der(&der),
+            _ => {
+                let cert = Certificate::from_der(&der).context("invalid certificate")?;
+                let spki = cert.tbs_certificate.subject_public_key_info.to_der()?;
+                VerifyingKey::from_spki_der(&spki)
+            }
+        }
+    }
+
+    // Verify a signature of a SHA-256 digest
+    pub fn verify_digest(&self, digest: &[u8], sig: &[u8]) -> bool {
+        match self {
+            VerifyingKey::Rsa(key) => key
+                .verify(Pkcs1v15Sign::new::<Sha256>(), digest, sig)
+                .is_ok(),
+            VerifyingKey::Ecdsa(key) => p256::ecdsa::Signature::from_der(sig)
+                .map(|sig| key.verify_prehash(digest, &sig).is_ok())
+                .unwrap_or(false),
+        }
+    }
+}
+
+// Load public keys from a certificate, a public key, or a zip of certificates like otacerts.zip
+pub fn load_verifying_keys(path: &str) -> anyhow::Result<Vec<VerifyingKey>> {
+    let mut file = File::open(path).with_context(|| format!("cannot open '{path}'"))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    if !data.starts_with(b"PK\x03\x04") {
+        return Ok(vec![VerifyingKey::from_bytes(&data)
+            .with_context(|| format!("cannot load key from '{path}'"))?]);
+    }
+
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    let mut keys = Vec::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let name = entry.name().to_owned();
+        let mut buf = Vec::new();
+        entry.read_to_end(&mut buf)?;
+        keys.push(
+            VerifyingKey::from_bytes(&buf)
+                .with_context(|| format!("cannot load key from '{path}/{name}'"))?,
+        );
+    }
+    if keys.is_empty() {
+        return Err(anyhow!("no keys found in '{path}'"));
+    }
+    Ok(keys)
+}
+
+pub struct SigningKey(RsaPrivateKey);
+
+impl SigningKey {
+    // Accepts PKCS#1 and PKCS#8 RSA private keys, PEM or DER encoded
+    pub fn load(path: &str) -> anyhow::Result<SigningKey> {
+        let mut file = File::open(path).with_context(|| format!("cannot open '{path}'"))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let (label, der) = match der::pem::decode_vec(&data) {
+            Ok((label, der)) => (label, der),
+            Err(_) => ("", data.clone()),
+        };
+        let key = match label {
+            "RSA PRIVATE KEY" => RsaPrivateKey::from_pkcs1_der(&der).ok(),
+            "PRIVATE KEY" => RsaPrivateKey::from_pkcs8_der(&der).ok(),
+            _ => RsaPrivateKey::from_pkcs8_der(&der)
+                .or_else(|_| RsaPrivateKey::from_pkcs1_der(&der))
+                .ok(),
+        };
+        key.map(SigningKey)
+            .ok_or_else(|| anyhow!("cannot load RSA private key from '{path}'"))
+    }
+
+    pub fn public_key(&self) -> RsaPublicKey {
+        self.0.to_public_key()
+    }
+
+    // Size of the modulus in bytes
+    pub fn size(&self) -> usize {
+        self.0.size()
+    }
+
+    // Sign a SHA-256 digest
+    pub fn sign_digest(&self, digest: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(self.0.sign(Pkcs1v15Sign::new::<Sha256>(), digest)?)
+    }
+}
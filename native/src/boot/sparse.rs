@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{anyhow, Context};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+// Android sparse image format, as used by fastboot
+// https://android.googlesource.com/platform/system/core/+/refs/heads/main/libsparse/sparse_format.h
+
+const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+const SPARSE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ChunkType {
+    Raw,
+    Fill(u32),
+    DontCare,
+}
+
+struct Chunk {
+    kind: ChunkType,
+    start_block: u64,
+    num_blocks: u32,
+}
+
+// Blocks that consist of a single repeated 32-bit value are stored as FILL chunks
+fn classify(block: &[u8]) -> ChunkType {
+    let pattern = &block[..4];
+    if block.chunks_exact(4).all(|c| c == pattern) {
+        ChunkType::Fill(LittleEndian::read_u32(pattern))
+    } else {
+        ChunkType::Raw
+    }
+}
+
+fn read_block(src: &File, size: u64, block: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+    let offset = block * buf.len() as u64;
+    // The last block is padded with zeros
+    let len = std::cmp::min(buf.len() as u64, size - offset) as usize;
+    buf[len..].fill(0);
+    src.read_exact_at(&mut buf[..len], offset)
+        .context("failed to read image")?;
+    Ok(())
+}
+
+// Convert the first size bytes of src into a sparse image. Blocks in the dont_care
+// ranges, given as (start_block, num_blocks), are not stored in the sparse image.
+pub fn write_sparse_image<W: Write>(
+    src: &File,
+    size: u64,
+    block_size: u32,
+    dont_care: &[(u64, u64)],
+    out: &mut W,
+) -> anyhow::Result<()> {
+    if block_size == 0 || block_size & 3 != 0 {
+        return Err(anyhow!("invalid sparse block size {}", block_size));
+    }
+    let bs = block_size as u64;
+    let total_blocks =
+        u32::try_from(size.div_ceil(bs)).map_err(|_| anyhow!("image is too large"))?;
+    // The size of a RAW chunk has to fit in its header
+    let max_raw_blocks = ((u32::MAX - CHUNK_HEADER_SIZE as u32) / block_size) as u64;
+
+    // Blocks are visited in order, so the sorted ranges are walked along with them
+    let mut dont_care = dont_care.to_vec();
+    dont_care.sort_unstable();
+    let mut ranges = dont_care.iter().peekable();
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut buf = vec![0u8; block_size as usize];
+    for block in 0..total_blocks as u64 {
+        // Skip the ranges that end before this block
+        while ranges
+            .next_if(|(start, num)| start.saturating_add(*num) <= block)
+            .is_some()
+        {}
+        let kind = if ranges.peek().is_some_and(|(start, _)| *start <= block) {
+            ChunkType::DontCare
+        } else {
+            read_block(src, size, block, &mut buf)?;
+            classify(&buf)
+        };
+        match chunks.last_mut() {
+            Some(chunk)
+                if chunk.kind == kind
+                    && (kind != ChunkType::Raw || (chunk.num_blocks as u64) < max_raw_blocks) =>
+            {
+                chunk.num_blocks += 1;
+            }
+            _ => chunks.push(Chunk {
+                kind,
+                start_block: block,
+                num_blocks: 1,
+            }),
+        }
+    }
+
+    out.write_u32::<LittleEndian>(SPARSE_HEADER_MAGIC)?;
+    out.write_u16::<LittleEndian>(1)?;
+    out.write_u16::<LittleEndian>(0)?;
+    out.write_u16::<LittleEndian>(SPARSE_HEADER_SIZE)?;
+    out.write_u16::<LittleEndian>(CHUNK_HEADER_SIZE)?;
+    out.write_u32::<LittleEndian>(block_size)?;
+    out.write_u32::<LittleEndian>(total_blocks)?;
+    out.write_u32::<LittleEndian>(chunks.len() as u32)?;
+    // The image checksum is optional
+    out.write_u32::<LittleEndian>(0)?;
+
+    for chunk in chunks.iter() {
+        let (chunk_type, data_size) = match chunk.kind {
+            ChunkType::Raw => (CHUNK_TYPE_RAW, chunk.num_blocks * block_size),
+            ChunkType::Fill(_) => (CHUNK_TYPE_FILL, 4),
+            ChunkType::DontCare => (CHUNK_TYPE_DONT_CARE, 0),
+        };
+        out.write_u16::<LittleEndian>(chunk_type)?;
+        out.write_u16::<LittleEndian>(0)?;
+        out.write_u32::<LittleEndian>(chunk.num_blocks)?;
+        out.write_u32::<LittleEndian>(CHUNK_HEADER_SIZE as u32 + data_size)?;
+        match chunk.kind {
+            ChunkType::Raw => {
+                for block in chunk.start_block..(chunk.start_block + chunk.num_blocks as u64) {
+                    read_block(src, size, block, &mut buf)?;
+                    out.write_all(&buf)?;
+                }
+            }
+            ChunkType::Fill(val) => out.write_u32::<LittleEndian>(val)?,
+            ChunkType::DontCare => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use std::fs::OpenOptions;
+
+use anyhow::{anyhow, Context};
+use rsa::traits::PublicKeyParts;
+
+use base::libc::c_char;
+use base::{ptr_to_str_result, ResultExt};
+
+use crate::avb::{
+    c_str, decode_public_key, encode_public_key, parse_descriptors, read_image_vbmeta,
+    verify_vbmeta, write_image_vbmeta, AvbFooter, Descriptor, HashAlgorithm, Vbmeta,
+    ALGORITHM_NAMES, ALGORITHM_NONE, FLAG_HASHTREE_DISABLED, FLAG_VERIFICATION_DISABLED,
+};
+use crate::sign::{load_verifying_keys, SigningKey, VerifyingKey};
+use crate::util::to_hex;
+
+const FLAG_NAMES: [(u32, &str); 2] = [
+    (FLAG_HASHTREE_DISABLED, "hashtree-disabled"),
+    (FLAG_VERIFICATION_DISABLED, "verification-disabled"),
+];
+
+fn print_field(name: &str, value: impl std::fmt::Display) {
+    println!("{name:<25} [{value}]");
+}
+
+// Public keys are identified by their SHA-1 like avbtool does
+fn key_sha1(key: &[u8]) -> String {
+    to_hex(&HashAlgorithm::Sha1.hash(&[], key))
+}
+
+fn flags_str(flags: u32) -> String {
+    let names: Vec<_> = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if names.is_empty() {
+        flags.to_string()
+    } else {
+        format!("{flags} ({})", names.join(","))
+    }
+}
+
+// FLAGS is either a number, or a comma separated list of flag names
+fn parse_flags(s: &str) -> anyhow::Result<u32> {
+    if let Ok(flags) = s.parse() {
+        return Ok(flags);
+    }
+    let mut flags = 0;
+    for name in s.split(',') {
+        flags |= FLAG_NAMES
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(flag, _)| *flag)
+            .ok_or_else(|| anyhow!("unknown vbmeta flag '{name}'"))?;
+    }
+    Ok(flags)
+}
+
+fn print_vbmeta(footer: Option<&AvbFooter>, data: &[u8]) -> anyhow::Result<()> {
+    let vbmeta = Vbmeta::parse(data)?;
+    if let Some(footer) = footer {
+        print_field("ORIGINAL_IMAGE_SIZE", footer.original_image_size);
+        print_field("VBMETA_OFFSET", footer.vbmeta_offset);
+    }
+    print_field("VBMETA_SIZE", data.len());
+    let (major, minor) = vbmeta.required_libavb_version;
+    print_field("LIBAVB_VERSION", format!("{major}.{minor}"));
+    let algorithm = vbmeta.algorithm_type;
+    match ALGORITHM_NAMES.get(algorithm as usize) {
+        Some(name) => print_field("ALGORITHM", name),
+        None => print_field("ALGORITHM", algorithm),
+    }
+    if !vbmeta.public_key.is_empty() {
+        print_field("PUBLIC_KEY_SHA1", key_sha1(&vbmeta.public_key));
+    }
+    print_field("ROLLBACK_INDEX", vbmeta.rollback_index);
+    print_field("ROLLBACK_INDEX_LOCATION", vbmeta.rollback_index_location);
+    print_field("FLAGS", flags_str(vbmeta.flags));
+    print_field("RELEASE_STRING", c_str(&vbmeta.release_string));
+
+    for desc in parse_descriptors(data)? {
+        match desc {
+            Descriptor::Property(desc) => {
+                print_field("PROPERTY", &desc.key);
+                print_field("  VALUE", String::from_utf8_lossy(&desc.value));
+            }
+            Descriptor::Hashtree(desc) => {
+                print_field("HASHTREE", &desc.partition_name);
+                print_field("  IMAGE_SIZE", desc.image_size);
+                print_field("  TREE_OFFSET", desc.tree_offset);
+                print_field("  TREE_SIZE", desc.tree_size);
+                print_field("  DATA_BLOCK_SIZE", desc.data_block_size);
+                print_field("  HASH_BLOCK_SIZE", desc.hash_block_size);
+                print_field("  HASH_ALGORITHM", &desc.hash_algorithm);
+                print_field("  SALT", to_hex(&desc.salt));
+                print_field("  ROOT_DIGEST", to_hex(&desc.root_digest));
+            }
+            Descriptor::Hash(desc) => {
+                print_field("HASH", &desc.partition_name);
+                print_field("  IMAGE_SIZE", desc.image_size);
+                print_field("  HASH_ALGORITHM", &desc.hash_algorithm);
+                print_field("  SALT", to_hex(&desc.salt));
+                print_field("  DIGEST", to_hex(&desc.digest));
+            }
+            Descriptor::KernelCmdline(desc) => {
+                print_field("KERNEL_CMDLINE", &desc.cmdline);
+                print_field("  FLAGS", desc.flags);
+            }
+            Descriptor::ChainPartition(desc) => {
+                print_field("CHAIN_PARTITION", &desc.partition_name);
+                print_field("  ROLLBACK_INDEX_LOCATION", desc.rollback_index_location);
+                print_field("  PUBLIC_KEY_SHA1", key_sha1(&desc.public_key));
+                print_field("  FLAGS", desc.flags);
+            }
+            Descriptor::Unknown(tag) => print_field("UNKNOWN", tag),
+        }
+    }
+    Ok(())
+}
+
+// Public keys embedded in vbmeta images that KEY can verify, KEY can also be a private key
+fn load_avb_keys(key: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+    if let Ok(key) = SigningKey::load(key) {
+        return Ok(vec![encode_public_key(&key.public_key())]);
+    }
+    Ok(load_verifying_keys(key)?
+        .iter()
+        .filter_map(|k| match k {
+            VerifyingKey::Rsa(k) => Some(encode_public_key(k)),
+            VerifyingKey::Ecdsa(_) => None,
+        })
+        .collect())
+}
+
+fn verify(data: &[u8], key: Option<&str>) -> anyhow::Result<()> {
+    let vbmeta = verify_vbmeta(data)?;
+    if let Some(key) = key {
+        if !load_avb_keys(key)?.contains(&vbmeta.public_key) {
+            return Err(anyhow!("vbmeta is not signed by '{key}'"));
+        }
+    }
+    let bits = decode_public_key(&vbmeta.public_key)?.size() * 8;
+    eprintln!("vbmeta signature is valid ({bits} bits RSA key)");
+    Ok(())
+}
+
+fn do_vbmeta(img: &str, action: &str, args: &[&str]) -> anyhow::Result<()> {
+    let mut key = None;
+    let mut pos_args = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match *arg {
+            "-k" => key = Some(*iter.next().ok_or(anyhow!("-k requires an argument"))?),
+            _ if arg.starts_with('-') => return Err(anyhow!("unknown option '{arg}'")),
+            _ => pos_args.push(*arg),
+        }
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(action == "patch")
+        .open(img)
+        .with_context(|| format!("cannot open '{img}'"))?;
+    let (footer, data) = read_image_vbmeta(&file)?;
+
+    match (action, pos_args.as_slice()) {
+        ("print", []) => print_vbmeta(footer.as_ref(), &data),
+        ("verify", []) => verify(&data, key),
+        ("patch", flags) if flags.len() <= 1 => {
+            let mut vbmeta = Vbmeta::parse(&data)?;
+            if let Some(flags) = flags.first() {
+                vbmeta.flags = parse_flags(flags)?;
+            }
+            match key {
+                Some(key) => vbmeta.sign(&SigningKey::load(key)?)?,
+                None if vbmeta.algorithm_type != ALGORITHM_NONE => {
+                    eprintln!("! vbmeta is signed, provide a key to re-sign it");
+                }
+                None => {}
+            }
+            write_image_vbmeta(
+                &file,
+                footer.as_ref(),
+                data.len() as u64,
+                &vbmeta.to_bytes(),
+            )
+        }
+        _ => Err(anyhow!("invalid vbmeta command")),
+    }
+}
+
+pub fn vbmeta_commands(argc: i32, argv: *const *const c_char) -> bool {
+    fn inner(argc: i32, argv: *const *const c_char) -> anyhow::Result<()> {
+        let mut args = Vec::new();
+        for i in 0..argc as usize {
+            args.push(ptr_to_str_result(unsafe { *argv.add(i) })?);
+        }
+        let [img, action, args @ ..] = args.as_slice() else {
+            return Err(anyhow!("invalid vbmeta command"));
+        };
+        do_vbmeta(img, action, args)
+    }
+    inner(argc, argv).log().is_ok()
+}